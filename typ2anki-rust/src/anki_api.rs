@@ -2,67 +2,123 @@ use once_cell::sync::OnceCell;
 use reqwest::blocking::Client;
 use serde_json::{Value, json};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::time::Duration;
 
 // Assume CardInfo lives here; adjust path if needed.
-use crate::card_wrapper::CardInfo;
-use crate::{config, utils};
+use crate::card_wrapper::{CardInfo, is_metadata_tag};
+use crate::error::{Result, Typ2AnkiError};
+use crate::{config::Config, utils};
 
 const ANKI_CONNECT_URL: &str = "http://localhost:8765";
-pub const CARDS_CACHE_FILENAME: &str = "_typ-cards-cache.json";
+const CARDS_CACHE_FILENAME: &str = "_typ-cards-cache.json";
+
+// Each --project has its own cache, so that the projects sharing an Anki profile don't
+// overwrite each other's
+pub fn cards_cache_filename(cfg: &Config) -> String {
+    match &cfg.project {
+        Some(project) => format!("_typ-cards-cache-{}.json", project),
+        None => CARDS_CACHE_FILENAME.to_string(),
+    }
+}
+
+// The requests sent to AnkiConnect at the same time, whatever the number of threads: the
+// add-on drops connections when it gets too many. Set from --anki-max-inflight.
+static MAX_INFLIGHT: AtomicUsize = AtomicUsize::new(4);
+static INFLIGHT: Mutex<usize> = Mutex::new(0);
+static INFLIGHT_DONE: Condvar = Condvar::new();
+
+pub fn set_max_inflight(max: usize) {
+    MAX_INFLIGHT.store(max.max(1), Ordering::Relaxed);
+}
+
+// Held while a request is sent and its response read
+struct InflightRequest;
 
-fn _handle_response(resp: reqwest::blocking::Response) -> Result<Value, String> {
+impl InflightRequest {
+    fn acquire() -> Self {
+        let mut inflight = INFLIGHT.lock().unwrap_or_else(|e| e.into_inner());
+        while *inflight >= MAX_INFLIGHT.load(Ordering::Relaxed) {
+            inflight = INFLIGHT_DONE
+                .wait(inflight)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *inflight += 1;
+        InflightRequest
+    }
+}
+
+impl Drop for InflightRequest {
+    fn drop(&mut self) {
+        *INFLIGHT.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        INFLIGHT_DONE.notify_one();
+    }
+}
+
+// AnkiConnect runs on this computer, so its requests never go through the HTTP proxy of
+// the environment, which couldn't reach it
+fn anki_client(timeout: Duration) -> reqwest::Result<Client> {
+    Client::builder().timeout(timeout).no_proxy().build()
+}
+
+fn _handle_response(resp: reqwest::blocking::Response) -> Result<Value> {
     let v: Value = resp
         .json()
-        .map_err(|e| format!("invalid json response: {}", e))?;
+        .map_err(|e| Typ2AnkiError::Anki(format!("invalid json response: {}", e)))?;
     if let Some(err) = v.get("error")
         && !err.is_null()
     {
-        return Err(format!("Anki API Error: {}", err));
+        return Err(Typ2AnkiError::Anki(format!("Anki API Error: {}", err)));
     }
     Ok(v.get("result").cloned().unwrap_or(Value::Null))
 }
 
-fn send_request(payload: Value) -> Result<Value, String> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()
-        .map_err(|e| format!("reqwest build error: {}", e))?;
+fn send_request(payload: Value) -> Result<Value> {
+    log::debug!(
+        "AnkiConnect request: {}",
+        payload.get("action").cloned().unwrap_or(Value::Null)
+    );
+    let client = anki_client(Duration::from_secs(10))
+        .map_err(|e| Typ2AnkiError::Network(format!("reqwest build error: {}", e)))?;
+    let _inflight = InflightRequest::acquire();
     _handle_response(
         client
             .post(ANKI_CONNECT_URL)
             .json(&payload)
             .send()
             .map_err(|e| {
-                format!(
+                Typ2AnkiError::Network(format!(
                     "request error: {:?} (status: {}, action {:?})",
                     e,
                     e.status().unwrap_or_default(),
                     payload.get("action").cloned().unwrap_or(Value::Null)
-                )
+                ))
             })?,
     )
 }
 
 pub fn check_anki_running() -> bool {
-    let client = Client::builder().timeout(Duration::from_secs(3)).build();
+    let client = anki_client(Duration::from_secs(3));
     if client.is_err() {
         return false;
     }
     let client = client.unwrap();
+    let _inflight = InflightRequest::acquire();
     let resp = client.get(ANKI_CONNECT_URL).send();
     if resp.is_err() {
         return false;
     }
-    let v: Result<Value, _> = resp.unwrap().json();
+    let v: std::result::Result<Value, _> = resp.unwrap().json();
     if let Ok(json) = v {
         return json.get("apiVersion").is_some();
     }
     false
 }
 
-pub fn upload_file(filename: String, base64_data: &String) -> Result<String, String> {
+pub fn upload_file(filename: String, base64_data: &String) -> Result<String> {
     let payload = json!({
         "action": "storeMediaFile",
         "version": 6,
@@ -75,8 +131,17 @@ pub fn upload_file(filename: String, base64_data: &String) -> Result<String, Str
     Ok(filename)
 }
 
-#[allow(dead_code)]
-pub fn get_media_dir_path() -> Result<String, String> {
+pub fn delete_media_file(filename: &str) -> Result<()> {
+    let payload = json!({
+        "action": "deleteMediaFile",
+        "version": 6,
+        "params": { "filename": filename }
+    });
+    send_request(payload)?;
+    Ok(())
+}
+
+pub fn get_media_dir_path() -> Result<String> {
     let payload = json!({
         "action": "getMediaDirPath",
         "version": 6
@@ -84,37 +149,87 @@ pub fn get_media_dir_path() -> Result<String, String> {
     let res = send_request(payload)?;
     res.as_str()
         .map(|s| s.to_string())
-        .ok_or_else(|| "unexpected response".to_string())
+        .ok_or_else(|| Typ2AnkiError::Anki("unexpected response".to_string()))
+}
+
+static CACHED_MEDIA_DIR: OnceCell<Result<PathBuf>> = OnceCell::new();
+
+// Anki's media folder, for the features that read it directly like --prune-media. Older
+// AnkiConnect versions don't have getMediaDirPath, and the folder may not be readable; the
+// images are uploaded with storeMediaFile all the same, which doesn't need it.
+pub fn media_dir() -> Result<PathBuf> {
+    CACHED_MEDIA_DIR.get_or_init(_media_dir).clone()
+}
+
+fn _media_dir() -> Result<PathBuf> {
+    resolve_media_dir(get_media_dir_path())
 }
 
-pub fn get_cards_cache_string() -> Option<String> {
+// Checks the answer of getMediaDirPath
+pub fn resolve_media_dir(path: Result<String>) -> Result<PathBuf> {
+    let dir = path.map_err(|e| match e {
+        Typ2AnkiError::Anki(msg) if msg.contains("unsupported action") => Typ2AnkiError::Anki(
+            "this version of AnkiConnect can't tell where Anki's media folder is, update the add-on to use it".to_string(),
+        ),
+        e => e.context("Anki's media folder couldn't be found"),
+    })?;
+    let dir = PathBuf::from(dir);
+    fs::read_dir(&dir).map_err(|e| {
+        Typ2AnkiError::Io(format!(
+            "Anki's media folder {} can't be read: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+    Ok(dir)
+}
+
+// Err if Anki couldn't be reached, Ok(None) if it has no cache (yet)
+pub fn get_cards_cache_string(cfg: &Config) -> Result<Option<String>> {
     let payload = json!({
         "action": "retrieveMediaFile",
         "version": 6,
-        "params": { "filename": CARDS_CACHE_FILENAME }
+        "params": { "filename": cards_cache_filename(cfg) }
     });
-    match send_request(payload) {
-        Ok(val) => {
-            if let Some(s) = val.as_str() {
-                match utils::b64_decode(s) {
-                    Ok(bytes) => String::from_utf8(bytes).ok(),
-                    Err(_) => None,
-                }
-            } else {
-                None
-            }
+    let val = send_request(payload)?;
+    if let Some(s) = val.as_str() {
+        match utils::b64_decode(s) {
+            Ok(bytes) => Ok(String::from_utf8(bytes).ok()),
+            Err(_) => Ok(None),
         }
-        Err(_) => None,
+    } else {
+        Ok(None)
     }
 }
 
-pub fn create_deck(deck_name: &str) -> Result<(), String> {
+// Decks that already exist aren't created again. The new ones are added to the known
+// decks, with their parents, so that the decks looked up afterwards can resolve to them.
+pub fn create_deck(deck_name: &str) -> Result<()> {
+    let mut deck_names = cached_deck_names();
+    if deck_names.iter().any(|d| d == deck_name) {
+        return Ok(());
+    }
     let payload = json!({
         "action": "createDeck",
         "version": 6,
         "params": { "deck": deck_name }
     });
     send_request(payload)?;
+
+    let levels: Vec<&str> = deck_name.split("::").collect();
+    for i in 1..=levels.len() {
+        let name = levels[..i].join("::");
+        if !deck_names.contains(&name) {
+            deck_names.push(name);
+        }
+    }
+    drop(deck_names);
+    ANKI_DECK_MAP
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(deck_name.to_string())
+        .or_insert_with(|| deck_name.to_string());
     Ok(())
 }
 
@@ -133,11 +248,24 @@ pub fn get_deck_names() -> Vec<String> {
     }
 }
 
-static CACHED_DECK_NAMES: OnceCell<Vec<String>> = OnceCell::new();
+// The decks in Anki, fetched once, then kept up to date by create_deck
+static CACHED_DECK_NAMES: OnceCell<Mutex<Vec<String>>> = OnceCell::new();
+
+fn cached_deck_names() -> MutexGuard<'static, Vec<String>> {
+    CACHED_DECK_NAMES
+        .get_or_init(|| Mutex::new(get_deck_names()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
 
 static ANKI_DECK_MAP: OnceCell<Mutex<HashMap<String, String>>> = OnceCell::new();
 
-pub fn get_anki_deck_name(typ_deck_name: &str) -> String {
+// The Anki deck of a target-deck: the one given in [deck_map], or else an existing deck
+// ending with it, or else a new deck with this exact name
+pub fn get_anki_deck_name(cfg: &Config, typ_deck_name: &str) -> String {
+    if let Some(mapped) = cfg.deck_map.get(typ_deck_name) {
+        return mapped.clone();
+    }
     let map = ANKI_DECK_MAP.get_or_init(|| Mutex::new(HashMap::new()));
 
     // Check cache
@@ -147,13 +275,26 @@ pub fn get_anki_deck_name(typ_deck_name: &str) -> String {
     }
     drop(guard);
 
-    let cached = CACHED_DECK_NAMES.get_or_init(get_deck_names);
+    let cached = cached_deck_names();
     let s = format!("::{}", typ_deck_name);
-    let result = cached
-        .iter()
-        .find(|&name| name.ends_with(&s))
-        .cloned()
+    let candidates: Vec<&String> = cached.iter().filter(|name| name.ends_with(&s)).collect();
+    if candidates.len() > 1 {
+        log::warn!(
+            "Several Anki decks end with \"{}\" ({}), using \"{}\". Add the one you mean to [deck_map] in typ2anki.toml",
+            s,
+            candidates
+                .iter()
+                .map(|c| format!("\"{}\"", c))
+                .collect::<Vec<_>>()
+                .join(", "),
+            candidates[0]
+        );
+    }
+    let result = candidates
+        .first()
+        .map(|name| name.to_string())
         .unwrap_or_else(|| typ_deck_name.to_string());
+    drop(cached);
 
     // Update cache
     let mut guard = map.lock().unwrap_or_else(|e| e.into_inner());
@@ -162,17 +303,15 @@ pub fn get_anki_deck_name(typ_deck_name: &str) -> String {
     result
 }
 
-pub fn find_note_id_by_tag(tag: &str) -> Result<Vec<i64>, String> {
+pub fn find_note_id_by_tag(tag: &str) -> Result<Vec<i64>> {
     let payload = json!({
         "action": "findNotes",
         "version": 6,
         "params": { "query": format!("tag:{}", tag) }
     });
 
-    let client = Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()
-        .map_err(|e| format!("reqwest build error: {}", e))?;
+    let client = anki_client(Duration::from_secs(10))
+        .map_err(|e| Typ2AnkiError::Network(format!("reqwest build error: {}", e)))?;
     let res = send_request_and_retry(&client, payload)?;
 
     if let Some(arr) = res.as_array() {
@@ -188,95 +327,324 @@ pub fn find_note_id_by_tag(tag: &str) -> Result<Vec<i64>, String> {
     }
 }
 
-type ModelInfo = (String, (String, String));
+// Escapes a value to be used between double quotes in Anki's search, where `*` and `_`
+// are wildcards
+fn escape_search(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('*', "\\*")
+        .replace('_', "\\_")
+}
+
+// The notes directly in an Anki deck, without those of its subdecks
+pub fn find_deck_note_ids(anki_deck_name: &str) -> Result<Vec<i64>> {
+    let escaped = escape_search(anki_deck_name);
+    let payload = json!({
+        "action": "findNotes",
+        "version": 6,
+        "params": { "query": format!("deck:\"{0}\" -deck:\"{0}::*\"", escaped) }
+    });
+    let res = send_request(payload)?;
+    Ok(res
+        .as_array()
+        .map(|arr| arr.iter().filter_map(|v| v.as_i64()).collect())
+        .unwrap_or_default())
+}
+
+// The notes of a note type whose field has exactly this value, like Anki's duplicate check
+fn find_note_ids_by_field(model_name: &str, field: &str, value: &str) -> Result<Vec<i64>> {
+    let payload = json!({
+        "action": "findNotes",
+        "version": 6,
+        "params": {
+            "query": format!(
+                "\"note:{}\" \"{}:{}\"",
+                escape_search(model_name),
+                escape_search(field),
+                escape_search(value)
+            )
+        }
+    });
+    let res = send_request(payload)?;
+    Ok(res
+        .as_array()
+        .map(|arr| arr.iter().filter_map(|v| v.as_i64()).collect())
+        .unwrap_or_default())
+}
+
+pub struct NoteInfo {
+    pub note_id: i64,
+    pub tags: Vec<String>,
+    pub field_values: Vec<String>,
+}
+
+pub fn get_notes_info(note_ids: &[i64]) -> Result<Vec<NoteInfo>> {
+    let payload = json!({
+        "action": "notesInfo",
+        "version": 6,
+        "params": { "notes": note_ids }
+    });
+    let res = send_request(payload)?;
+    let notes = res
+        .as_array()
+        .ok_or_else(|| Typ2AnkiError::Anki("notesInfo returned unexpected type".to_string()))?;
+    Ok(notes
+        .iter()
+        .filter_map(|note| {
+            Some(NoteInfo {
+                note_id: note.get("noteId")?.as_i64()?,
+                tags: note
+                    .get("tags")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|t| t.as_str().map(|s| s.to_string()))
+                    .collect(),
+                field_values: note
+                    .get("fields")?
+                    .as_object()?
+                    .values()
+                    .filter_map(|f| f.get("value")?.as_str().map(|s| s.to_string()))
+                    .collect(),
+            })
+        })
+        .collect())
+}
+
+// A note type and its fields, the front and back going in the first two
+pub type ModelInfo = (String, Vec<String>);
 
-static CACHED_BASICAL_MODEL_NAME: OnceCell<ModelInfo> = OnceCell::new();
+static CACHED_BASICAL_MODEL_NAME: OnceCell<Result<ModelInfo>> = OnceCell::new();
 
 const BASIC_MODEL_LOCALES: [&str; 3] = ["Basic", "Basique", "Grundlegend"];
 
-fn _get_basic_model_name() -> Result<ModelInfo, String> {
+pub fn get_model_names() -> Result<Vec<String>> {
     let payload = json!({ "action": "modelNames", "version": 6 });
     let models = send_request(payload)?;
-    let model_list = models
+    Ok(models
         .as_array()
-        .ok_or_else(|| "modelNames returned unexpected type".to_string())?;
-    let mut basic_model_name: Option<String> = None;
-    'outer: for locale in &BASIC_MODEL_LOCALES {
-        for v in model_list {
-            if let Some(s) = v.as_str()
-                && s == *locale
-            {
-                basic_model_name = Some(s.to_string());
-                break 'outer;
-            }
+        .ok_or_else(|| Typ2AnkiError::Anki("modelNames returned unexpected type".to_string()))?
+        .iter()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect())
+}
+
+// What to do when a note type can't be used
+fn model_guidance(model_names: &[String]) -> String {
+    format!(
+        "Note types in Anki: {}. Set `model = \"<note type>\"` in typ2anki.toml (or use --model) to use one of them, the front and back going in its first two fields, or create a note type with Tools > Manage Note Types > Add > Add: Basic in Anki",
+        if model_names.is_empty() {
+            "none".to_string()
+        } else {
+            model_names.join(", ")
         }
+    )
+}
+
+fn _get_basic_model_name() -> Result<ModelInfo> {
+    let model_names = get_model_names()?;
+    let model_name = BASIC_MODEL_LOCALES
+        .iter()
+        .find(|locale| model_names.iter().any(|m| m == *locale))
+        .ok_or_else(|| {
+            Typ2AnkiError::Anki(format!(
+                "No Basic note type found in Anki (looked for {}). {}",
+                BASIC_MODEL_LOCALES.join(", "),
+                model_guidance(&model_names)
+            ))
+        })?;
+    let fields = get_model_field_names(model_name)?;
+    if fields.len() != 2 {
+        return Err(Typ2AnkiError::Anki(format!(
+            "The note type '{}' should have 2 fields, but has {} ({}). {}",
+            model_name,
+            fields.len(),
+            fields.join(", "),
+            model_guidance(&model_names)
+        )));
     }
-    let model_name = basic_model_name.ok_or_else(|| "Basic model not found in Anki".to_string())?;
-    let payload2 = json!({
+
+    Ok((model_name.to_string(), fields))
+}
+
+fn get_model_field_names(model_name: &str) -> Result<Vec<String>> {
+    let payload = json!({
         "version": 6,
         "action": "modelFieldNames",
         "params": { "modelName": model_name }
     });
-    let fields_val = send_request(payload2)?;
-    let fields = fields_val
+    let fields_val = send_request(payload)?;
+    Ok(fields_val
         .as_array()
-        .ok_or_else(|| "modelFieldNames returned unexpected type".to_string())?
+        .ok_or_else(|| Typ2AnkiError::Anki("modelFieldNames returned unexpected type".to_string()))?
         .iter()
         .filter_map(|v| v.as_str().map(|s| s.to_string()))
-        .collect::<Vec<_>>();
-    if fields.len() != 2 {
-        return Err(format!(
-            "Basic model should have 2 fields, but found {}",
-            fields.len()
-        ));
+        .collect::<Vec<_>>())
+}
+
+static CACHED_MODELS: OnceCell<Mutex<HashMap<String, ModelInfo>>> = OnceCell::new();
+
+// Note type chosen for a deck in typ2anki.toml; the front and back go in its first two
+// fields, the extra pages in the fields named by --extra-field
+fn get_model(model_name: &str) -> Result<ModelInfo> {
+    let models = CACHED_MODELS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(model) = models.lock().unwrap().get(model_name) {
+        return Ok(model.clone());
+    }
+    let model_names = get_model_names()?;
+    if !model_names.iter().any(|m| m == model_name) {
+        return Err(Typ2AnkiError::Anki(format!(
+            "The note type '{}' doesn't exist in Anki. {}",
+            model_name,
+            model_guidance(&model_names)
+        )));
     }
+    let fields = get_model_field_names(model_name).map_err(|e| {
+        e.context(&format!(
+            "Couldn't get the fields of note type '{}'",
+            model_name
+        ))
+    })?;
+    if fields.len() < 2 {
+        return Err(Typ2AnkiError::Anki(format!(
+            "The note type '{}' should have at least 2 fields, but has {}. {}",
+            model_name,
+            fields.len(),
+            model_guidance(&model_names)
+        )));
+    }
+    let model = (model_name.to_string(), fields);
+    models
+        .lock()
+        .unwrap()
+        .insert(model_name.to_string(), model.clone());
+    Ok(model)
+}
 
-    Ok((model_name, (fields[0].clone(), fields[1].clone())))
+pub fn get_basic_model_name() -> Result<ModelInfo> {
+    CACHED_BASICAL_MODEL_NAME
+        .get_or_init(_get_basic_model_name)
+        .clone()
 }
 
-fn get_basic_model_name() -> &'static ModelInfo {
-    CACHED_BASICAL_MODEL_NAME.get_or_init(|| {
-        _get_basic_model_name().unwrap_or((
-            "Basic".to_string(),
-            ("Front".to_string(), "Back".to_string()),
-        ))
-    })
+// The note type of new notes of a deck: the one set in the configuration, or Basic
+fn get_deck_model(cfg: &Config, card: &CardInfo) -> Result<ModelInfo> {
+    match card.deck_config(cfg).model {
+        Some(model_name) => get_model(&model_name),
+        None => get_basic_model_name(),
+    }
 }
 
-pub fn send_request_and_retry(client: &Client, payload: Value) -> Result<Value, String> {
+// Checks, before anything is uploaded, that the note types of the cards exist in Anki and
+// have the fields the cards need
+pub fn check_note_types<'a>(
+    cfg: &Config,
+    cards: impl IntoIterator<Item = &'a CardInfo>,
+) -> Result<()> {
+    let mut checked: Vec<Option<String>> = Vec::new();
+    for card in cards {
+        let model = card.deck_config(cfg).model;
+        if checked.contains(&model) {
+            continue;
+        }
+        let model_info = get_deck_model(cfg, card)?;
+        note_fields(cfg, card, &model_info)?;
+        checked.push(model);
+    }
+    Ok(())
+}
+
+pub fn send_request_and_retry(client: &Client, payload: Value) -> Result<Value> {
     let mut attempts = 0;
     loop {
+        let _inflight = InflightRequest::acquire();
         match client.post(ANKI_CONNECT_URL).json(&payload).send() {
             Ok(res) => return _handle_response(res),
             Err(e) => {
                 if attempts <= 2 && e.status().is_none() {
                     attempts += 1;
+                    log::debug!("AnkiConnect request failed ({}), retrying", e);
                 } else {
-                    return Err(format!(
+                    return Err(Typ2AnkiError::Network(format!(
                         "request error: {} (status: {}, action: {:?})",
                         e,
                         e.status().unwrap_or_default(),
                         payload.get("action").cloned().unwrap_or(Value::Null)
-                    ));
+                    )));
                 }
             }
         }
     }
 }
 
+// The fields of the note of a card, one per page, filled with their templates
+fn note_fields(cfg: &Config, card: &CardInfo, model: &ModelInfo) -> Result<Value> {
+    let (model_name, model_fields) = model;
+    let mut fields = serde_json::Map::new();
+    for page in 1..=cfg.page_count() {
+        let field = if page <= 2 {
+            &model_fields[page - 1]
+        } else {
+            let name = &cfg.extra_fields[page - 3];
+            if !model_fields.contains(name) {
+                return Err(Typ2AnkiError::Anki(format!(
+                    "The note type '{}' has no field '{}' for page {} (its fields: {}). Add it in Anki with Tools > Manage Note Types > Fields, or change --extra-field",
+                    model_name,
+                    name,
+                    page,
+                    model_fields.join(", ")
+                )));
+            }
+            name
+        };
+        fields.insert(
+            field.clone(),
+            json!(cfg.template_page(card, page, &card.image_path(cfg, page))),
+        );
+    }
+    Ok(Value::Object(fields))
+}
+
+// The tags to add to a note that has `existing`, and the tags typ2anki added before that
+// it must remove: the --embed-metadata tags that changed. The tags added in Anki are kept.
+fn tag_changes(existing: &[String], tags: &[String]) -> (Vec<String>, Vec<String>) {
+    let to_add = tags
+        .iter()
+        .filter(|t| !existing.contains(t))
+        .cloned()
+        .collect();
+    let to_remove = existing
+        .iter()
+        .filter(|t| is_metadata_tag(t) && !tags.contains(t))
+        .cloned()
+        .collect();
+    (to_add, to_remove)
+}
+
+// The notes being uploaded, by ID tag. Cards with the same ID (when duplicates aren't
+// checked) are uploaded one after the other, so that they can't both find no note and
+// each add one.
+static NOTE_LOCKS: OnceCell<Mutex<HashMap<String, Arc<Mutex<()>>>>> = OnceCell::new();
+
+fn note_lock(id_tag: &str) -> Arc<Mutex<()>> {
+    let mut map = NOTE_LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    map.entry(id_tag.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone()
+}
+
 pub struct CardUploaderThread {
     client: Client,
 }
 impl CardUploaderThread {
     pub fn new() -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .expect("Failed to build reqwest client");
+        let client = anki_client(Duration::from_secs(10)).expect("Failed to build reqwest client");
         Self { client }
     }
 
-    fn upload_file(&self, filename: String, base64_data: &String) -> Result<String, String> {
+    fn upload_file(&self, filename: String, base64_data: &String) -> Result<String> {
         let payload = json!({
             "action": "storeMediaFile",
             "version": 6,
@@ -289,58 +657,148 @@ impl CardUploaderThread {
         Ok(filename)
     }
 
+    // Updates the fields of an existing note, and adds the card's tags to the ones it has
+    // instead of replacing them
+    fn update_note(&self, note_id: i64, fields: Value, tags: &[String]) -> Result<()> {
+        let payload = json!({
+            "action": "updateNoteFields",
+            "version": 6,
+            "params": { "note": { "id": note_id, "fields": fields } }
+        });
+        send_request_and_retry(&self.client, payload)?;
+
+        let existing = get_notes_info(&[note_id])?
+            .pop()
+            .map(|note| note.tags)
+            .unwrap_or_default();
+        let (to_add, to_remove) = tag_changes(&existing, tags);
+        for (action, tags) in [("addTags", to_add), ("removeTags", to_remove)] {
+            if tags.is_empty() {
+                continue;
+            }
+            let payload = json!({
+                "action": action,
+                "version": 6,
+                "params": { "notes": [note_id], "tags": tags.join(" ") }
+            });
+            send_request_and_retry(&self.client, payload)?;
+        }
+        Ok(())
+    }
+
     pub fn upload_card(
         &self,
+        cfg: &Config,
         card: &CardInfo,
-        front_data_base64: &String,
-        back_data_base64: &String,
-    ) -> Result<(), String> {
-        let cfg = config::get();
+        pages_base64: &[String],
+    ) -> Result<()> {
         if cfg.dry_run {
             return Ok(());
         }
-        self.upload_file(card.image_path(1), front_data_base64)?;
-        self.upload_file(card.image_path(2), back_data_base64)?;
+        for (i, data) in pages_base64.iter().enumerate() {
+            self.upload_file(card.image_path(cfg, i + 1), data)?;
+        }
 
-        let note_ids = find_note_id_by_tag(&card.card_id)?;
-        let tags = vec![card.card_id.clone()];
+        let id_tag = card.id_tag(cfg);
+        let note_lock = note_lock(&id_tag);
+        let _uploading = note_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let note_ids = find_note_id_by_tag(&id_tag)?;
+        let mut tags = card.anki_tags(cfg);
+        if cfg.embed_metadata {
+            tags.extend(card.metadata_tags(cfg));
+        }
 
-        let payload = if !note_ids.is_empty() {
-            let note_id = note_ids[0];
+        let model = get_deck_model(cfg, card)?;
+        let fields = note_fields(cfg, card, &model)?;
+        if !note_ids.is_empty() {
+            return self.update_note(note_ids[0], fields, &tags);
+        }
 
-            json!({
-                "action": "updateNoteFields",
-                "version": 6,
-                "params": {
-                    "note": {
-                        "id": note_id,
-                        "fields": {
-                            "Front": cfg.template_front(card,card.image_path(1).as_str()),
-                            "Back": cfg.template_back(card,card.image_path(2).as_str()),
-                        },
-                        "tags": tags
-                    }
-                }
-            })
-        } else {
-            let (model_name, (model_field_front, model_field_back)) = get_basic_model_name();
-            json!({
-                "action": "addNote",
-                "version": 6,
-                "params": {
-                    "note": {
-                        "deckName": card.anki_deck_name,
-                        "modelName": model_name,
-                        "fields": {
-                            model_field_front: cfg.template_front(card,card.image_path(1).as_str()),
-                            model_field_back: cfg.template_back(card,card.image_path(2).as_str()),
-                        },
-                        "tags": tags
-                    }
+        let payload = json!({
+            "action": "addNote",
+            "version": 6,
+            "params": {
+                "note": {
+                    "deckName": card.anki_deck_name,
+                    "modelName": model.0,
+                    "fields": fields,
+                    "tags": tags,
+                    "options": { "allowDuplicate": cfg.allow_duplicate_notes }
                 }
-            })
-        };
-        send_request_and_retry(&self.client, payload)?;
-        Ok(())
+            }
+        });
+        match send_request_and_retry(&self.client, payload) {
+            Ok(_) => Ok(()),
+            // Anki refuses a note whose first field is the same as another note's: the note
+            // of the card lost its ID tag, or is a copy of it. It is updated instead.
+            Err(Typ2AnkiError::Anki(e))
+                if e.contains("cannot create note because it is a duplicate") =>
+            {
+                let first_field = &model.1[0];
+                let value = fields
+                    .get(first_field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default();
+                let Some(&note_id) = find_note_ids_by_field(&model.0, first_field, value)?.first()
+                else {
+                    return Err(Typ2AnkiError::Anki(format!(
+                        "{} (Anki found a note with the same '{}' field, which can't be found back: set allow_duplicate_notes = true in typ2anki.toml to add the note anyway)",
+                        e, first_field
+                    )));
+                };
+                log::info!(
+                    "Card {} has the same '{}' field as note {}, updating it",
+                    card.card_id,
+                    first_field,
+                    note_id
+                );
+                self.update_note(note_id, fields, &tags)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn adds_missing_tags() {
+        let (to_add, to_remove) = tag_changes(&tags(&["a"]), &tags(&["a", "b"]));
+        assert_eq!(to_add, tags(&["b"]));
+        assert!(to_remove.is_empty());
+        let (to_add, to_remove) = tag_changes(&[], &tags(&["a"]));
+        assert_eq!(to_add, tags(&["a"]));
+        assert!(to_remove.is_empty());
+    }
+
+    #[test]
+    fn keeps_tags_added_in_anki() {
+        let (to_add, to_remove) = tag_changes(&tags(&["a", "mine"]), &tags(&["a"]));
+        assert!(to_add.is_empty());
+        assert!(to_remove.is_empty());
+    }
+
+    #[test]
+    fn replaces_changed_metadata_tags() {
+        let existing = tags(&["a", "typ2anki::src::old.typ", "typ2anki::deck::D"]);
+        let (to_add, to_remove) = tag_changes(
+            &existing,
+            &tags(&["a", "typ2anki::src::new.typ", "typ2anki::deck::D"]),
+        );
+        assert_eq!(to_add, tags(&["typ2anki::src::new.typ"]));
+        assert_eq!(to_remove, tags(&["typ2anki::src::old.typ"]));
+        // Without --embed-metadata, the metadata tags of earlier runs are removed
+        let (to_add, to_remove) = tag_changes(&existing, &tags(&["a"]));
+        assert!(to_add.is_empty());
+        assert_eq!(
+            to_remove,
+            tags(&["typ2anki::src::old.typ", "typ2anki::deck::D"])
+        );
     }
 }
@@ -0,0 +1,462 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use rusqlite::{Connection, params};
+use serde_json::{Value, json};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    card_wrapper::CardInfo,
+    config::Config,
+    error::{self, Typ2AnkiError},
+    export::CardExporter,
+    utils,
+};
+
+// The note type of the cards whose deck has no `model`
+const MODEL_NAME: &str = "typ2anki Basic";
+const DEFAULT_DECK_ID: i64 = 1;
+const DEFAULT_DECK_CONF_ID: i64 = 1;
+
+// Schema of the legacy (version 11) `collection.anki2` database, which every Anki version can import
+const SCHEMA: &str = r#"
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+    conf text not null, models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null,
+    csum integer not null, flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null,
+    due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+    type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+"#;
+
+struct ApkgNote {
+    // The card's position in source order, as cards are added in the order they compile
+    internal_id: usize,
+    deck_name: String,
+    // The `model` of the card's deck, or MODEL_NAME
+    model_name: String,
+    guid: String,
+    tags: Vec<String>,
+    // The front, the back, then the extra fields
+    fields: Vec<String>,
+}
+
+/// Collects compiled cards and writes them as an Anki package (.apkg), so that decks
+/// can be shared without a running Anki.
+pub struct ApkgWriter {
+    // The names of the fields after Front and Back, from --extra-field
+    extra_fields: Vec<String>,
+    notes: Vec<ApkgNote>,
+    // (card position, filename, bytes) of every media file referenced by the notes
+    media: Vec<(usize, String, Vec<u8>)>,
+}
+
+// Ids in Anki are i64; derive them from a hash so that they are stable across exports
+fn stable_id(s: &str) -> i64 {
+    let hash = utils::hash_string(s);
+    // 12 hex digits keep the id well within i64, and away from the reserved default ids
+    i64::from_str_radix(&hash[..12], 16).unwrap_or(0) + 2
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// Anki's duplicate checksum: the first 8 hex digits of the sha1 of the sort field
+fn field_checksum(field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(field).digest().to_string();
+    i64::from_str_radix(&digest[..8], 16).unwrap_or(0)
+}
+
+fn strip_html(s: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for ch in s.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => out.push(ch),
+            _ => {}
+        }
+    }
+    out.trim().to_string()
+}
+
+impl ApkgWriter {
+    pub fn new(extra_fields: &[String]) -> Self {
+        Self {
+            extra_fields: extra_fields.to_vec(),
+            notes: Vec::new(),
+            media: Vec::new(),
+        }
+    }
+
+    fn deck_json(id: i64, name: &str, now: i64) -> Value {
+        json!({
+            "id": id,
+            "name": name,
+            "mod": now / 1000,
+            "usn": -1,
+            "lrnToday": [0, 0],
+            "revToday": [0, 0],
+            "newToday": [0, 0],
+            "timeToday": [0, 0],
+            "collapsed": false,
+            "browserCollapsed": false,
+            "desc": "",
+            "dyn": 0,
+            "conf": DEFAULT_DECK_CONF_ID,
+            "extendNew": 0,
+            "extendRev": 0,
+        })
+    }
+
+    // A note type with other fields must not replace the one of the same name when imported
+    fn model_id(&self, name: &str) -> i64 {
+        if self.extra_fields.is_empty() {
+            stable_id(name)
+        } else {
+            stable_id(&format!("{}\n{}", name, self.extra_fields.join("\n")))
+        }
+    }
+
+    fn model_json(&self, id: i64, name: &str, now: i64) -> Value {
+        let field = |name: &str, ord: usize| {
+            json!({
+                "name": name,
+                "ord": ord,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "media": [],
+            })
+        };
+        let names: Vec<&str> = ["Front", "Back"]
+            .into_iter()
+            .chain(self.extra_fields.iter().map(String::as_str))
+            .collect();
+        let fields: Vec<Value> = names
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| field(name, ord))
+            .collect();
+        // The extra fields are shown after the back
+        let afmt = names[1..].iter().fold(
+            "{{FrontSide}}\n\n<hr id=answer>".to_string(),
+            |afmt, name| format!("{}\n\n{{{{{}}}}}", afmt, name),
+        );
+        json!({
+            "id": id,
+            "name": name,
+            "type": 0,
+            "mod": now / 1000,
+            "usn": -1,
+            "sortf": 0,
+            "did": DEFAULT_DECK_ID,
+            "tmpls": [{
+                "name": "Card 1",
+                "ord": 0,
+                "qfmt": "{{Front}}",
+                "afmt": afmt,
+                "did": null,
+                "bqfmt": "",
+                "bafmt": "",
+            }],
+            "flds": fields,
+            "css": ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "latexsvg": false,
+            "req": [[0, "any", [0]]],
+            "tags": [],
+            "vers": [],
+        })
+    }
+
+    fn deck_conf_json() -> Value {
+        json!({
+            "id": DEFAULT_DECK_CONF_ID,
+            "name": "Default",
+            "mod": 0,
+            "usn": 0,
+            "maxTaken": 60,
+            "autoplay": true,
+            "timer": 0,
+            "replayq": true,
+            "dyn": false,
+            "new": {
+                "bury": true,
+                "delays": [1.0, 10.0],
+                "initialFactor": 2500,
+                "ints": [1, 4, 7],
+                "order": 1,
+                "perDay": 20,
+                "separate": true,
+            },
+            "lapse": {
+                "delays": [10.0],
+                "leechAction": 0,
+                "leechFails": 8,
+                "minInt": 1,
+                "mult": 0.0,
+            },
+            "rev": {
+                "bury": true,
+                "ease4": 1.3,
+                "fuzz": 0.05,
+                "ivlFct": 1.0,
+                "maxIvl": 36500,
+                "minSpace": 1,
+                "perDay": 100,
+            },
+        })
+    }
+
+    fn write_collection(&self, path: &Path) -> anyhow::Result<()> {
+        let now = now_millis();
+        let model_names: BTreeSet<&str> =
+            self.notes.iter().map(|n| n.model_name.as_str()).collect();
+        let models: BTreeMap<String, Value> = model_names
+            .iter()
+            .map(|name| {
+                let id = self.model_id(name);
+                (id.to_string(), self.model_json(id, name, now))
+            })
+            .collect();
+        let current_model_id = self.model_id(model_names.first().copied().unwrap_or(MODEL_NAME));
+
+        // Every deck, including the parents of nested decks, must exist in the collection
+        let mut deck_names: BTreeSet<String> = BTreeSet::new();
+        for note in &self.notes {
+            let parts: Vec<&str> = note.deck_name.split("::").collect();
+            for i in 1..=parts.len() {
+                deck_names.insert(parts[..i].join("::"));
+            }
+        }
+        let mut decks: BTreeMap<String, Value> = BTreeMap::new();
+        decks.insert(
+            DEFAULT_DECK_ID.to_string(),
+            Self::deck_json(DEFAULT_DECK_ID, "Default", now),
+        );
+        for name in &deck_names {
+            let id = stable_id(name);
+            decks.insert(id.to_string(), Self::deck_json(id, name, now));
+        }
+
+        let conf = json!({
+            "nextPos": self.notes.len() + 1,
+            "estTimes": true,
+            "activeDecks": [DEFAULT_DECK_ID],
+            "sortType": "noteFld",
+            "timeLim": 0,
+            "sortBackwards": false,
+            "addToCur": true,
+            "curDeck": DEFAULT_DECK_ID,
+            "newBury": true,
+            "newSpread": 0,
+            "dueCounts": true,
+            "curModel": current_model_id.to_string(),
+            "collapseTime": 1200,
+        });
+        let dconf = json!({ DEFAULT_DECK_CONF_ID.to_string(): Self::deck_conf_json() });
+
+        let conn = Connection::open(path).context("Failed to create the collection database")?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute(
+            "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+            params![
+                now / 1000,
+                now,
+                conf.to_string(),
+                serde_json::to_string(&models)?,
+                serde_json::to_string(&decks)?,
+                dconf.to_string()
+            ],
+        )?;
+
+        // In source order, so that exporting the same sources twice gives the same notes
+        let mut notes: Vec<&ApkgNote> = self.notes.iter().collect();
+        notes.sort_by_key(|n| n.internal_id);
+        for (i, note) in notes.into_iter().enumerate() {
+            let id = now + i as i64;
+            let sort_field = strip_html(&note.fields[0]);
+            conn.execute(
+                "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+                params![
+                    id,
+                    note.guid,
+                    self.model_id(&note.model_name),
+                    now / 1000,
+                    format!(" {} ", note.tags.join(" ")),
+                    note.fields.join("\x1f"),
+                    sort_field,
+                    field_checksum(&sort_field)
+                ],
+            )?;
+            conn.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                params![id, id, stable_id(&note.deck_name), now / 1000, i as i64 + 1],
+            )?;
+        }
+        conn.close().map_err(|(_, e)| e)?;
+        Ok(())
+    }
+}
+
+impl CardExporter for ApkgWriter {
+    fn add_card(
+        &mut self,
+        cfg: &Config,
+        card: &CardInfo,
+        pages_base64: &[String],
+    ) -> error::Result<()> {
+        for (i, data) in pages_base64.iter().enumerate() {
+            let page = i + 1;
+            let bytes = utils::b64_decode(data)
+                .map_err(|e| Typ2AnkiError::Compile(format!("invalid image data: {}", e)))?;
+            self.media
+                .push((card.internal_id, card.image_path(cfg, page), bytes));
+        }
+        self.notes.push(ApkgNote {
+            internal_id: card.internal_id,
+            deck_name: card
+                .anki_deck_name
+                .clone()
+                .unwrap_or_else(|| card.deck_name.clone()),
+            model_name: card
+                .deck_config(cfg)
+                .model
+                .unwrap_or_else(|| MODEL_NAME.to_string()),
+            guid: utils::hash_string(&format!("typ2anki-{}-{}", card.deck_name, card.card_id)),
+            tags: card.anki_tags(cfg),
+            fields: (1..=pages_base64.len())
+                .map(|page| cfg.template_page(card, page, &card.image_path(cfg, page)))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    /// Writes the package: a zip holding `collection.anki2`, the media files named
+    /// by their index, and a `media` JSON mapping those indexes to the real filenames
+    fn write(&self, path: &Path) -> anyhow::Result<usize> {
+        let tmp = tempfile::tempdir_in(utils::get_typ2anki_tmp())?;
+        let collection_path = tmp.path().join("collection.anki2");
+        self.write_collection(&collection_path)?;
+
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut zip = ZipWriter::new(file);
+        let options = SimpleFileOptions::default();
+
+        zip.start_file("collection.anki2", options)?;
+        zip.write_all(&fs::read(&collection_path)?)?;
+
+        let mut media: Vec<&(usize, String, Vec<u8>)> = self.media.iter().collect();
+        media.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let mut media_map: BTreeMap<String, String> = BTreeMap::new();
+        for (i, (_, filename, bytes)) in media.into_iter().enumerate() {
+            zip.start_file(i.to_string(), options)?;
+            zip.write_all(bytes)?;
+            media_map.insert(i.to_string(), filename.clone());
+        }
+        zip.start_file("media", options)?;
+        zip.write_all(serde_json::to_string(&media_map)?.as_bytes())?;
+        zip.finish()?;
+        Ok(self.notes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{config::config_from_args, parse_file::DocumentDefaults};
+
+    fn card(deck: &str) -> CardInfo {
+        let card_str = format!(
+            r#"#card(id: "001", target-deck: "{}", q: [q], a: [a])"#,
+            deck
+        );
+        CardInfo::from_string(
+            &card_str,
+            PathBuf::from("cards.typ"),
+            (0, card_str.len()),
+            &DocumentDefaults::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn notes_have_the_model_of_their_deck() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("typ2anki.toml"),
+            "[decks.\"Spanish\"]\nmodel = \"Vocabulary\"\n",
+        )
+        .unwrap();
+        let cfg = config_from_args(&[&dir.path().to_string_lossy()]);
+        let mut writer = ApkgWriter::new(&[]);
+        let pages = [utils::b64_encode("front"), utils::b64_encode("back")];
+        writer.add_card(&cfg, &card("Spanish"), &pages).unwrap();
+        writer.add_card(&cfg, &card("Biology"), &pages).unwrap();
+        let path = dir.path().join("collection.anki2");
+        writer.write_collection(&path).unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let models: String = conn
+            .query_row("SELECT models FROM col", [], |row| row.get(0))
+            .unwrap();
+        let models: Value = serde_json::from_str(&models).unwrap();
+        let mut names: Vec<&str> = models
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["Vocabulary", MODEL_NAME]);
+
+        let mut statement = conn.prepare("SELECT mid FROM notes ORDER BY id").unwrap();
+        let model_ids: Vec<i64> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(model_ids, [stable_id("Vocabulary"), stable_id(MODEL_NAME)]);
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 
 use crate::{
     card_wrapper::{BarebonesCardInfo, CardInfo},
-    config,
+    config::Config,
     output::{OutputManager, OutputMessage},
     parse_file::{self},
 };
@@ -21,18 +21,18 @@ static TEN_DIGIT_NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^\d{10
 /// can be ran as a formatter basically, on the file given in config.auto_number_file
 /// Either does "previous minute + 1", or "current time" - in all cases, it's deduplicated by incrementing minute further if needed
 /// I recommend using dprint plugin exec - you can put this *before* typstyle in the order of execution
-pub fn run_auto_number(output: impl OutputManager + 'static) -> anyhow::Result<()> {
+pub fn run_auto_number(cfg: &Config, output: impl OutputManager + 'static) -> anyhow::Result<()> {
     let output = Arc::new(output);
-    let cfg = config::get();
     let file_path: PathBuf = cfg
         .auto_number_file
         .clone()
         .context("auto_number is not set in config")?
         .into();
     let mut contents = get_file_contents(file_path.to_str().context("Invalid file path")?)?;
-    let mut cards = parse_file::parse_cards_string(&contents, &output, false)
+    let defaults = parse_file::document_defaults(cfg, &contents);
+    let mut cards = parse_file::parse_cards_string(cfg, &contents, &output, false)
         .into_iter()
-        .map(|f| CardInfo::from_string(0, &f, file_path.clone()))
+        .map(|f| CardInfo::parse(&f.content, file_path.clone(), f.byte_range, &defaults))
         .filter_map(|f| match f {
             Ok(card) => Some(card),
             Err(e) => {
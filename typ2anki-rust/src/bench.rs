@@ -0,0 +1,113 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use anyhow::Context;
+
+use crate::{
+    card_wrapper::{CardInfo, TFiles, sort_in_source_order},
+    cards_cache::CardsCacheManager,
+    compile,
+    config::Config,
+    output::{OutputManager, OutputMessage},
+    parse_file,
+};
+
+const BENCH_FILES: usize = 5;
+const CARDS_PER_FILE: usize = 40;
+
+// The same cards every time, so that runs of different versions can be compared. They mix
+// text, math and a table, like typical cards.
+fn write_synthetic_deck(dir: &Path) -> std::io::Result<()> {
+    for file in 0..BENCH_FILES {
+        let mut content = String::new();
+        for card in 0..CARDS_PER_FILE {
+            let n = file * CARDS_PER_FILE + card;
+            content.push_str(&format!(
+                r#"#card(
+  id: "bench-{n}",
+  target-deck: "Bench {file}",
+  q: [What is the derivative of $x^{n} + sin(x)$? *Card {n}*],
+  a: [
+    $ d / (d x) (x^{n} + sin(x)) = {n} x^{m} + cos(x) $
+    #table(columns: 3, [a], [b], [c], [{n}], [{m}], [{file}])
+  ],
+)
+
+"#,
+                m = n.saturating_sub(1)
+            ));
+        }
+        fs::write(dir.join(format!("bench-{}.typ", file)), content)?;
+    }
+    Ok(())
+}
+
+// --bench: compiles a synthetic deck in a temporary folder, as a dry run so that nothing
+// reaches Anki, with the compilation settings of the project (render scale, image format,
+// concurrency...). Every run recompiles every card.
+pub fn run(cfg: &Config, runs: usize, output: impl OutputManager + 'static) -> anyhow::Result<()> {
+    let output = Arc::new(output);
+    let dir = tempfile::tempdir().context("Failed to create the folder of the synthetic deck")?;
+    write_synthetic_deck(dir.path()).context("Failed to write the synthetic deck")?;
+
+    let mut bench_cfg = cfg.clone();
+    bench_cfg.path = dir.path().to_path_buf();
+    bench_cfg.paths = vec![dir.path().to_path_buf()];
+    bench_cfg.dry_run = true;
+    bench_cfg.force = true;
+    bench_cfg.use_cache = false;
+    bench_cfg.include_files.clear();
+    bench_cfg.exclude_files.clear();
+    bench_cfg.include_decks.clear();
+    bench_cfg.exclude_decks.clear();
+    let cfg = &bench_cfg;
+    parse_file::check_ankiconf_exists(cfg);
+    let typ_files = parse_file::find_card_files(cfg);
+
+    let mut results = Vec::with_capacity(runs);
+    let mut card_count = 0;
+    for _ in 0..runs {
+        let now = Instant::now();
+        let mut cards_cache_manager =
+            CardsCacheManager::init(cfg, parse_file::get_ankiconf_hash(cfg), output.as_ref());
+        let mut cards: Vec<CardInfo> = Vec::new();
+        let mut deck_names: BTreeSet<String> = BTreeSet::new();
+        let files: TFiles = Arc::new(RwLock::new(HashMap::new()));
+        files.write().unwrap().extend(parse_file::parse_typ_files(
+            cfg,
+            &typ_files,
+            &mut cards_cache_manager,
+            output.clone(),
+            &mut deck_names,
+            &mut cards,
+        ));
+        let parse_time = now.elapsed();
+
+        sort_in_source_order(&mut cards);
+        for card in &mut cards {
+            card.set_status(cfg, &cards_cache_manager);
+            card.anki_deck_name = Some(card.deck_name.clone());
+        }
+        compile::compile_cards_concurrent(
+            cfg,
+            &cards,
+            output.clone(),
+            Arc::new(Mutex::new(cards_cache_manager)),
+            files,
+            Vec::new(),
+        );
+        card_count = cards.len();
+        results.push((now.elapsed(), compile::take_phase_times(parse_time)));
+    }
+
+    output.send(OutputMessage::BenchReport {
+        cards: card_count,
+        runs: results,
+    });
+    Ok(())
+}
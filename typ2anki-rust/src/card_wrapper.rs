@@ -1,11 +1,16 @@
-use std::{ops::Range, path::PathBuf};
+use std::{collections::HashMap, ops::Range, path::PathBuf, time::Duration};
 
 use anyhow::Context as _;
 use colored::*;
 
 use crate::{
-    cards_cache, config,
-    parse_file::{ANSWER_RE, DECK_RE, ID_RE, QUESTION_RE, is_card_empty},
+    cards_cache,
+    config::{self, Config},
+    error::{self, Typ2AnkiError},
+    parse_file::{
+        ANSWER_RE, DECK_RE, DocumentDefaults, ID_RE, QUESTION_RE, TAGS_RE, argument_value,
+        is_card_empty, nearest_ankiconf, parse_tags,
+    },
     utils,
 };
 
@@ -57,26 +62,59 @@ pub struct TypFileStats {
     pub unchanged_cards: CardCountPair,
     pub empty_cards: usize,
     pub skipped_cards: usize,
+    // Cards that couldn't be parsed, and so aren't counted in total_cards
+    pub parse_errors: usize,
+    // Size of the images of the file's cards that were uploaded to Anki
+    pub media_bytes: u64,
+    // Time spent compiling the file's cards, added up over the threads
+    pub compile_time: Duration,
 }
 pub type TFiles =
     std::sync::Arc<std::sync::RwLock<std::collections::HashMap<PathBuf, TypFileStats>>>;
 
+// The card counts of every file added together, for the end of a run
+#[derive(Debug, Clone, Default)]
+pub struct CardsSummary {
+    pub total: usize,
+    pub new: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub empty: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub media_bytes: u64,
+}
+
 pub trait TFilesExt {
     fn total_errors(&self) -> usize;
+    fn summary(&self) -> CardsSummary;
 }
 
 impl TFilesExt for TFiles {
     fn total_errors(&self) -> usize {
         self.read()
-            .map(|map| {
-                map.values()
-                    .map(|stats| stats.total_errors())
-                    .sum()
-            })
+            .map(|map| map.values().map(|stats| stats.total_errors()).sum())
             .unwrap_or(0)
     }
-}
 
+    fn summary(&self) -> CardsSummary {
+        let mut summary = CardsSummary::default();
+        let Ok(files) = self.read() else {
+            return summary;
+        };
+        for stats in files.values() {
+            summary.total += stats.total_cards;
+            summary.new += stats.new_cards.0;
+            summary.updated += stats.updated_cards.0;
+            summary.unchanged += stats.unchanged_cards.0;
+            summary.empty += stats.empty_cards;
+            summary.skipped += stats.skipped_cards;
+            summary.errors += stats.total_errors();
+            summary.media_bytes += stats.media_bytes;
+        }
+        summary
+    }
+}
 
 impl TypFileStats {
     pub fn new(_filepath: PathBuf) -> Self {
@@ -87,6 +125,9 @@ impl TypFileStats {
             unchanged_cards: (0, 0),
             empty_cards: 0,
             skipped_cards: 0,
+            parse_errors: 0,
+            media_bytes: 0,
+            compile_time: Duration::ZERO,
         }
     }
 
@@ -94,6 +135,20 @@ impl TypFileStats {
         self.new_cards.1 + self.updated_cards.1 + self.unchanged_cards.1
     }
 
+    pub fn count_card_status(&mut self, status: &CardModificationStatus) {
+        match status {
+            CardModificationStatus::Unchanged => self.unchanged_cards.0 += 1,
+            CardModificationStatus::Updated => self.updated_cards.0 += 1,
+            CardModificationStatus::New => self.new_cards.0 += 1,
+            CardModificationStatus::Unknown => {}
+        }
+    }
+
+    // The cards that are compiled in this run, the unchanged ones being skipped
+    pub fn cards_to_compile(&self) -> usize {
+        self.total_cards.saturating_sub(self.unchanged_cards.0)
+    }
+
     pub fn stats_colored(&self) -> String {
         let separator = "|".bright_black();
         format!(
@@ -140,6 +195,57 @@ impl BarebonesCardInfo {
     }
 }
 
+// Anki separates the levels of nested decks with `::`. Returns why a target deck would be
+// surprising once in Anki, if it would.
+pub fn deck_name_problem(deck_name: &str) -> Option<String> {
+    if let Some(c) = deck_name.chars().find(|c| *c == '"' || c.is_control()) {
+        return Some(format!(
+            "target deck \"{}\" contains {:?}, which Anki doesn't accept in deck names",
+            deck_name, c
+        ));
+    }
+    let levels: Vec<&str> = deck_name.split("::").collect();
+    if levels.iter().any(|l| l.trim().is_empty()) {
+        return Some(format!(
+            "target deck \"{}\" has an empty level: `::` can't start or end a deck name, or appear twice in a row",
+            deck_name
+        ));
+    }
+    if levels.iter().any(|l| *l != l.trim()) {
+        return Some(format!(
+            "target deck \"{}\" has spaces around its levels, which Anki removes",
+            deck_name
+        ));
+    }
+    if levels.len() > 1 {
+        return Some(format!(
+            "target deck \"{}\" contains `::`, so it is the deck \"{}\" nested in \"{}\". A deck already nested in Anki is found by its own name: target-deck: \"{}\" is enough",
+            deck_name,
+            levels[levels.len() - 1],
+            levels[..levels.len() - 1].join("::"),
+            levels[levels.len() - 1]
+        ));
+    }
+    None
+}
+
+// The tags added by --embed-metadata start with this, they aren't the card's own tags
+pub const METADATA_TAG_PREFIX: &str = "typ2anki::";
+
+pub fn is_metadata_tag(tag: &str) -> bool {
+    tag.starts_with(METADATA_TAG_PREFIX)
+}
+
+// Anki separates tags with spaces, so they are escaped like in URLs
+fn encode_tag_value(s: &str) -> String {
+    s.replace('%', "%25").replace(' ', "%20")
+}
+
+// Every image of a deck's cards starts with this, unless --legacy-media-names is used
+pub fn deck_media_prefix(deck_name: &str) -> String {
+    format!("typ-{}", &utils::hash_string(deck_name)[..8])
+}
+
 #[derive(Debug, Clone)]
 pub struct CardInfo {
     // The file name from which the card is compiled
@@ -150,53 +256,224 @@ pub struct CardInfo {
     pub deck_name: String,
     // The deck name in anki, with leading folder
     pub anki_deck_name: Option<String>,
-    // The card's content
+    // The user defined tags, from the card's `tags` argument
+    pub tags: Vec<String>,
+    // The card's content: the prelude of its file, then its call
     pub content: String,
+    // Where the card's call starts in `content`
+    pub call_start: usize,
     // A hash of the card's content
     pub content_hash: String,
     // The card's noticed modification status
     pub modification_status: CardModificationStatus,
+    // range of bytes of the card in the source file
+    pub byte_range: (usize, usize),
+    // 1-based line and column of the start of the card in the source file
+    pub location: Option<(usize, usize)>,
+    // The position of the card among all the cards of the run, in source order (by file
+    // path, then position in the file), set by `sort_in_source_order`. The same sources
+    // always give the same positions, so exports ordered by it are reproducible.
+    pub internal_id: usize,
+}
+
+// Cards are processed in source order, whatever order the files were read in, so that
+// two runs over the same sources give the same output
+pub fn sort_in_source_order(cards: &mut [CardInfo]) {
+    cards.sort_by(|a, b| (&a.source_file, a.byte_range).cmp(&(&b.source_file, b.byte_range)));
+    for (i, card) in cards.iter_mut().enumerate() {
+        card.internal_id = i;
+    }
+}
+
+// The Typst source of a card made of several parts: after the prelude of the first part, each
+// part's `card` call only records its arguments, then the card is shown once with the
+// arguments of the first part, and the questions, answers and extra pages of all the parts
+// joined in order. Returns the source and where the calls start in it.
+fn merged_card_content(cfg: &Config, parts: &[CardInfo]) -> (String, usize) {
+    let mut content = parts[0].prelude().to_string();
+    let call_start = content.len();
+    content.push_str(
+        r#"#let typ2anki-parts = state("typ2anki-parts", ())
+#let typ2anki-card = card
+#let card(..args) = typ2anki-parts.update(parts => parts + (args.named(),))
+#let custom-card = card
+"#,
+    );
+    for name in &cfg.card_functions {
+        content.push_str(&format!("#let {} = card\n", name));
+    }
+    for part in parts {
+        content.push('\n');
+        content.push_str(part.call());
+        content.push('\n');
+    }
+    content.push_str(
+        r#"
+#context {
+  let parts = typ2anki-parts.final()
+  let join(values) = values.filter(v => v != none).join(parbreak())
+  let pages = parts.map(p => p.at("pages", default: ()))
+  let merged = parts.first()
+  merged.insert("q", join(parts.map(p => p.at("q", default: none))))
+  merged.insert("a", join(parts.map(p => p.at("a", default: none))))
+  merged.insert(
+    "pages",
+    range(calc.max(..pages.map(p => p.len()))).map(i => join(pages.map(p => p.at(i, default: none)))),
+  )
+  typ2anki-card(..merged)
+}
+"#,
+    );
+    (content, call_start)
+}
+
+// --merge-by-id: the cards of a file with the same ID and target deck are parts of one card,
+// merged in source order. The merged card has the tags of all its parts and the other
+// arguments of the first one. Parts in other files or decks aren't merged, and are left to
+// the duplicate ID check.
+pub fn merge_by_id(cfg: &Config, cards: Vec<CardInfo>) -> Vec<CardInfo> {
+    let mut groups: Vec<Vec<CardInfo>> = Vec::new();
+    // The index in `groups` of the group of each file, ID and deck
+    let mut group_indices: HashMap<(PathBuf, String, String), usize> = HashMap::new();
+    for card in cards {
+        let key = (
+            card.source_file.clone(),
+            card.card_id.clone(),
+            card.deck_name.clone(),
+        );
+        match group_indices.get(&key) {
+            Some(&i) => groups[i].push(card),
+            None => {
+                group_indices.insert(key, groups.len());
+                groups.push(vec![card]);
+            }
+        }
+    }
+    groups
+        .into_iter()
+        .map(|mut parts| {
+            if parts.len() == 1 {
+                return parts.remove(0);
+            }
+            parts.sort_by_key(|p| p.byte_range);
+            let (content, call_start) = merged_card_content(cfg, &parts);
+            let mut tags: Vec<String> = Vec::new();
+            for tag in parts.iter().flat_map(|p| &p.tags) {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+            let last_end = parts[parts.len() - 1].byte_range.1;
+            let first = parts.remove(0);
+            log::debug!("Merged {} parts of card {}", parts.len() + 1, first.card_id);
+            CardInfo {
+                tags,
+                content_hash: utils::hash_text(&content),
+                content,
+                call_start,
+                byte_range: (first.byte_range.0, last_end),
+                ..first
+            }
+        })
+        .collect()
 }
 
 impl CardInfo {
+    // Parses a card, rejecting blank IDs and decks: the ID is the card's tag in Anki, so
+    // blank IDs would all be the same card
     pub fn from_string(
-        _internal_id: i64,
         card_str: &str,
         filepath: PathBuf,
-    ) -> Result<Self, String> {
-        let card_id = ID_RE
-            .captures(card_str)
-            .and_then(|caps| caps.get(1).map(|m| m.as_str().to_string()));
+        byte_range: (usize, usize),
+        defaults: &DocumentDefaults,
+    ) -> error::Result<Self> {
+        let card = Self::parse(card_str, filepath, byte_range, defaults)?;
+        if card.card_id.trim().is_empty() {
+            return Err(Typ2AnkiError::Parse(format!(
+                "Card ID is empty (id: \"{}\")",
+                card.card_id
+            )));
+        }
+        // The ID is the tag the note is found by, and Anki splits tags on whitespace
+        if card.card_id.contains(char::is_whitespace) {
+            return Err(Typ2AnkiError::Parse(format!(
+                "Card ID contains whitespace (id: \"{}\")",
+                card.card_id
+            )));
+        }
+        if card.deck_name.trim().is_empty() {
+            return Err(Typ2AnkiError::Parse(format!(
+                "Target deck is empty (target-deck: \"{}\") for card {}",
+                card.deck_name, card.card_id
+            )));
+        }
+        Ok(card)
+    }
+
+    // Parses a card without validating its ID, for auto_number which fills in the empty ones.
+    // The card's own `target-deck` and `tags` take precedence over those of its document.
+    pub fn parse(
+        card_str: &str,
+        filepath: PathBuf,
+        byte_range: (usize, usize),
+        defaults: &DocumentDefaults,
+    ) -> error::Result<Self> {
+        let card_id = argument_value(&ID_RE, card_str);
         if card_id.is_none() {
-            return Err("Card ID not found".to_string());
+            return Err(Typ2AnkiError::Parse("Card ID not found".to_string()));
         }
         let card_id = card_id.unwrap();
 
-        let target_deck = DECK_RE
-            .captures(card_str)
-            .and_then(|caps| caps.get(1).map(|m| m.as_str().to_string()));
-        if target_deck.is_none() {
-            return Err("Target deck not found".to_string());
-        }
-        let target_deck = target_deck.unwrap();
+        let mut content_hash = utils::hash_text(card_str);
+        let target_deck = match argument_value(&DECK_RE, card_str) {
+            Some(deck) => deck,
+            None => {
+                let Some(deck) = defaults.deck.clone() else {
+                    return Err(Typ2AnkiError::Parse(
+                        "Target deck not found (set target-deck, or the title of the document)"
+                            .to_string(),
+                    ));
+                };
+                deck
+            }
+        };
+        let tags = if TAGS_RE.is_match(card_str) {
+            parse_tags(card_str)
+        } else {
+            // The tags are only set when the note is uploaded: the card must be uploaded
+            // again when they change
+            if !defaults.tags.is_empty() {
+                content_hash =
+                    utils::hash_string(&format!("{}{}", content_hash, defaults.tags.join(" ")));
+            }
+            defaults.tags.clone()
+        };
 
         Ok(Self {
             source_file: filepath,
             card_id,
             deck_name: target_deck,
             anki_deck_name: None,
+            tags,
             content: card_str.to_string(),
-            content_hash: utils::hash_string(card_str),
+            call_start: 0,
+            content_hash,
             modification_status: CardModificationStatus::Unknown,
+            byte_range,
+            location: None,
+            internal_id: 0,
         })
     }
 
-    pub fn set_status(&mut self, cards_cache_manager: &cards_cache::CardsCacheManager) {
-        let cfg = config::get();
+    pub fn set_status(
+        &mut self,
+        cfg: &Config,
+        cards_cache_manager: &cards_cache::CardsCacheManager,
+    ) {
         let key = cards_cache::card_key(&self.deck_name, &self.card_id);
-        if let Some(old_hash) = cards_cache_manager.old_cache.get(&key) {
-            if old_hash.ends_with(&self.content_hash) {
-                if !old_hash.starts_with(&cards_cache_manager.static_hash)
+        if let Some(old_entry) = cards_cache_manager.old_cache.get(&key) {
+            if old_entry.content_hash == self.content_hash {
+                if old_entry.config_hash != cards_cache_manager.static_hash
                     && cfg.recompile_on_config_change.read().unwrap().unwrap()
                 {
                     self.modification_status = CardModificationStatus::Updated;
@@ -209,28 +486,109 @@ impl CardInfo {
         } else {
             self.modification_status = CardModificationStatus::New;
         }
+        // --force: the cache still tells new cards apart, but nothing is skipped
+        if cfg.force && self.modification_status == CardModificationStatus::Unchanged {
+            self.modification_status = CardModificationStatus::Updated;
+        }
     }
 
-    pub fn path_relative_to_root(&self) -> String {
-        let cfg = config::get();
+    pub fn path_relative_to_root(&self, cfg: &Config) -> String {
         cfg.path_relative_to_root(&self.source_file)
     }
 
-    pub fn relative_ankiconf_path(&self) -> String {
-        let cfg = config::get();
-        let output_path = self.source_file.parent().unwrap_or(&cfg.path).to_path_buf();
+    // The folder the card's file was found in
+    pub fn root<'a>(&self, cfg: &'a Config) -> &'a PathBuf {
+        cfg.root_of(&self.source_file)
+    }
+
+    pub fn relative_ankiconf_path(&self, cfg: &Config) -> String {
+        let root = self.root(cfg);
+        let output_path = self.source_file.parent().unwrap_or(root).to_path_buf();
 
-        // relative path from output_path to cfg.path / ankiconf.typ
+        // relative path from output_path to the nearest ankiconf.typ
 
-        let ankiconf_path = cfg.path.join("ankiconf.typ");
+        let ankiconf_path = nearest_ankiconf(root, &self.source_file);
         pathdiff::diff_paths(&ankiconf_path, &output_path)
             .unwrap_or(ankiconf_path)
             .to_string_lossy()
             .into_owned()
     }
 
-    pub fn image_path(&self, page: usize) -> String {
-        format!("typ-{}-{}.png", self.card_id, page)
+    pub fn deck_config(&self, cfg: &Config) -> config::DeckConfig {
+        cfg.deck_config(&self.deck_name)
+    }
+
+    // The tag the note of the card is found by: its card_id, in the namespace of --project
+    pub fn id_tag(&self, cfg: &Config) -> String {
+        match &cfg.project {
+            Some(project) => format!("{}::{}", project, self.card_id),
+            None => self.card_id.clone(),
+        }
+    }
+
+    // All the tags the note should have in anki: the ID tag, then the card's own tags,
+    // then the default tags of its deck
+    pub fn anki_tags(&self, cfg: &Config) -> Vec<String> {
+        let mut tags = vec![self.id_tag(cfg)];
+        for tag in self.tags.iter().chain(&self.deck_config(cfg).tags) {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        tags
+    }
+
+    // --embed-metadata: where the card comes from, so that its note can be mapped back to its
+    // source even if its ID is reused. The path is relative to the card's root, with `/`.
+    pub fn metadata_tags(&self, cfg: &Config) -> Vec<String> {
+        let path = self.path_relative_to_root(cfg).replace('\\', "/");
+        vec![
+            format!("{}src::{}", METADATA_TAG_PREFIX, encode_tag_value(&path)),
+            format!(
+                "{}deck::{}",
+                METADATA_TAG_PREFIX,
+                encode_tag_value(&self.deck_name)
+            ),
+        ]
+    }
+
+    // The name of the image of a page in Anki's media folder, which all the decks share.
+    // The hash of the deck keeps cards with the same ID in different decks apart.
+    // With --hash-media-names, a hash of what the image looks like (the card and the
+    // configuration) is added, so that every version of the card has its own file.
+    pub fn image_path(&self, cfg: &Config, page: usize) -> String {
+        let mut name = if cfg.legacy_media_names {
+            format!("typ-{}-{}", self.card_id, page)
+        } else {
+            format!(
+                "{}-{}-{}",
+                deck_media_prefix(&self.deck_name),
+                self.card_id,
+                page
+            )
+        };
+        if cfg.hash_media_names {
+            let version = utils::hash_string(&format!(
+                "{}{}",
+                cfg.config_hash.as_deref().unwrap_or_default(),
+                self.content_hash
+            ));
+            name = format!("{}-{}", name, &version[..8]);
+        }
+        format!(
+            "{}.{}",
+            name,
+            self.deck_config(cfg).image_format.extension()
+        )
+    }
+
+    // The text of the file given to every card before its call
+    pub fn prelude(&self) -> &str {
+        &self.content[..self.call_start]
+    }
+
+    pub fn call(&self) -> &str {
+        &self.content[self.call_start..]
     }
 
     pub fn is_empty(&self) -> bool {
@@ -256,3 +614,101 @@ impl CardInfo {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        compile::new_world, config::config_from_args, output_json::OutputJson,
+        parse_file::parse_cards_from_file_content,
+    };
+
+    fn from_string(card_str: &str) -> error::Result<CardInfo> {
+        CardInfo::from_string(
+            card_str,
+            PathBuf::from("cards.typ"),
+            (0, card_str.len()),
+            &DocumentDefaults::default(),
+        )
+    }
+
+    #[test]
+    fn rejects_empty_ids() {
+        for id in [r#""""#, r#"" ""#, "\"\\t\\n \"", "[]", "[ \n ]"] {
+            let card = format!(r#"#card(id: {}, target-deck: "D", q: [q], a: [a])"#, id);
+            let error = from_string(&card).unwrap_err();
+            assert!(matches!(error, Typ2AnkiError::Parse(_)), "{}", id);
+            assert!(error.to_string().starts_with("Card ID is empty"), "{}", id);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_target_decks() {
+        for deck in [r#""""#, r#""  ""#, "[ ]"] {
+            let card = format!(r#"#card(id: "001", target-deck: {}, q: [q], a: [a])"#, deck);
+            let error = from_string(&card).unwrap_err();
+            assert!(
+                error.to_string().starts_with("Target deck is empty"),
+                "{}",
+                deck
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_ids_with_whitespace() {
+        for id in [r#"" 001 ""#, r#""001 ""#, r#""chapter 1""#, "\"a\\tb\""] {
+            let card = format!(r#"#card(id: {}, target-deck: "D", q: [q], a: [a])"#, id);
+            let error = from_string(&card).unwrap_err();
+            assert!(matches!(error, Typ2AnkiError::Parse(_)), "{}", id);
+            assert!(
+                error.to_string().starts_with("Card ID contains whitespace"),
+                "{}",
+                id
+            );
+        }
+        let card = from_string(r#"#card(id: "chapter-1", target-deck: "D", q: [q], a: [a])"#);
+        assert_eq!(card.unwrap().card_id, "chapter-1");
+    }
+
+    #[test]
+    fn merged_card_has_the_prelude_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Arc::new(config_from_args(&[
+            "--merge-by-id",
+            &dir.path().to_string_lossy(),
+        ]));
+        let output = Arc::new(OutputJson::new(cfg.clone()));
+        let content = r#"// START
+Prelude text
+#let card(q: none, a: none, ..args) = [#q / #a]
+
+#card(id: "001", target-deck: "D", q: [First], a: [one])
+#card(id: "002", target-deck: "D", q: [Other], a: [two])
+#card(id: "001", target-deck: "D", q: [Second], a: [three])
+"#;
+        let path = dir.path().join("cards.typ");
+        let (_, cards) =
+            parse_cards_from_file_content(&cfg, &path, content.to_string(), output).unwrap();
+        assert_eq!(cards.len(), 2);
+        let merged = &cards[0];
+        assert_eq!(merged.card_id, "001");
+        assert_eq!(merged.content.matches("Prelude text").count(), 1);
+        assert_eq!(merged.content.matches("#let card(q: none").count(), 1);
+        assert!(
+            merged
+                .prelude()
+                .starts_with("Prelude text\n#let card(q: none")
+        );
+        assert!(!merged.call().contains("Prelude text"));
+        assert!(!merged.content.contains("Other"));
+
+        // The prelude's own `card` is the one that shows the merged card
+        let mut world = new_world(&cfg, Arc::new(OutputJson::new(cfg.clone())));
+        world.source = typst::syntax::Source::detached(merged.content.clone());
+        let document = typst::compile::<typst::layout::PagedDocument>(&world).output;
+        assert!(document.is_ok(), "{:?}", document.err());
+    }
+}
@@ -1,41 +1,152 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::output::{OutputManager, OutputMessage};
 use crate::utils::{self, hash_string};
-use crate::{anki_api, config};
+use crate::{anki_api, config::Config};
+
+// Bumped whenever the format of the cache changes. A cache written by a newer version
+// of typ2anki is ignored rather than misread.
+const CACHE_VERSION: u32 = 2;
+
+// What was uploaded for a card
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedCard {
+    // Hash of ankiconf.typ and of the configuration the card was compiled with
+    pub config_hash: String,
+    pub content_hash: String,
+}
 
-const CACHE_HASH_PART_LENGTH: usize = 34;
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    cards: HashMap<String, CachedCard>,
+}
 
 #[derive(Debug, Clone)]
 pub struct CardsCacheManager {
     pub static_hash: String,
-    pub old_cache: HashMap<String, String>,
-    pub new_cache: HashMap<String, String>,
+    pub old_cache: HashMap<String, CachedCard>,
+    pub new_cache: HashMap<String, CachedCard>,
 }
 
 pub fn card_key(deck_name: &str, card_id: &str) -> String {
     format!("{}_{}", deck_name, card_id)
 }
 
-fn cache_concat_hashes_padding(hash1: &str, hash2: &str) -> String {
-    let mut out = String::new();
-    out.push_str(hash1);
-    out.push_str(&"0".repeat(CACHE_HASH_PART_LENGTH.saturating_sub(hash1.len())));
-    out.push_str(&"0".repeat(CACHE_HASH_PART_LENGTH.saturating_sub(hash2.len())));
-    out.push_str(hash2);
-    out
+// Version 1 stored both md5 hashes of a card in a single string, padded to 34 characters
+// each, or separated by ':'. Malformed entries are dropped, so the card is compiled again.
+fn migrate_v1_entry(entry: &str) -> Option<CachedCard> {
+    const HASH_LENGTH: usize = 32;
+    const PART_LENGTH: usize = 34;
+    let (config_hash, content_hash) = match entry.split_once(':') {
+        Some(hashes) => hashes,
+        None if entry.len() == 2 * PART_LENGTH && entry.is_ascii() => (
+            &entry[..HASH_LENGTH],
+            &entry[2 * PART_LENGTH - HASH_LENGTH..],
+        ),
+        None => return None,
+    };
+    if config_hash.is_empty() || content_hash.is_empty() {
+        return None;
+    }
+    Some(CachedCard {
+        config_hash: config_hash.to_string(),
+        content_hash: content_hash.to_string(),
+    })
 }
 
-impl CardsCacheManager {
-    pub fn init(ankiconf_hash: String, _output: &impl OutputManager) -> Self {
-        let cfg = config::get();
-        let static_hash =
-            hash_string(format!("{}{}", ankiconf_hash, cfg.config_hash.as_ref().unwrap()).as_str());
-        let cache = if cfg.skip_cache {
+// Reads a cache in any known format. An unreadable cache is as good as no cache.
+fn parse_cache(s: &str, output: &impl OutputManager) -> HashMap<String, CachedCard> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(s) else {
+        return HashMap::new();
+    };
+    // Card keys always contain a '_', so a version 1 cache never has a "version" key
+    match value.get("version").map(|v| v.as_u64()) {
+        None => {
+            log::info!("Migrating the cards cache to version {}", CACHE_VERSION);
+            serde_json::from_value::<HashMap<String, serde_json::Value>>(value)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, migrate_v1_entry(v.as_str()?)?)))
+                .collect()
+        }
+        Some(Some(version)) if version == CACHE_VERSION as u64 => {
+            serde_json::from_value::<CacheFile>(value)
+                .map(|c| c.cards)
+                .unwrap_or_default()
+        }
+        Some(version) => {
+            output.send(OutputMessage::UnsupportedCacheVersion(
+                version.map_or("unknown".to_string(), |v| v.to_string()),
+            ));
             HashMap::new()
+        }
+    }
+}
+
+// A copy of the cache is kept on disk, one per project (set of folders and --project), for
+// the runs that can't use the one in Anki: dry runs, or when Anki isn't running
+pub fn local_cache_path(cfg: &Config) -> PathBuf {
+    let mut roots: Vec<String> = cfg
+        .paths
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    // Without --project, the path stays the same as before it existed
+    if let Some(project) = &cfg.project {
+        roots.push(format!("project:{}", project));
+    }
+    utils::get_typ2anki_tmp()
+        .join("cards_cache")
+        .join(format!("{}.json", hash_string(&roots.join("\n"))))
+}
+
+fn compute_static_hash(cfg: &Config, ankiconf_hash: &str) -> String {
+    hash_string(format!("{}{}", ankiconf_hash, cfg.config_hash.as_ref().unwrap()).as_str())
+}
+
+impl CardsCacheManager {
+    pub fn init(cfg: &Config, ankiconf_hash: String, output: &impl OutputManager) -> Self {
+        Self::init_with(cfg, ankiconf_hash, output, || {
+            // The local copy is only used when Anki can't be reached: if Anki has no cache,
+            // the cards were never uploaded to it, whatever the local copy says
+            match anki_api::get_cards_cache_string(cfg) {
+                Ok(s) => s,
+                Err(e) => {
+                    let path = local_cache_path(cfg);
+                    log::info!(
+                        "Couldn't read the cards cache from Anki ({}), using {}",
+                        e,
+                        path.display()
+                    );
+                    fs::read_to_string(path).ok()
+                }
+            }
+        })
+    }
+
+    // `read_cache` is only called when the cache is used (not with --no-cache)
+    fn init_with(
+        cfg: &Config,
+        ankiconf_hash: String,
+        output: &impl OutputManager,
+        read_cache: impl FnOnce() -> Option<String>,
+    ) -> Self {
+        let static_hash = compute_static_hash(cfg, &ankiconf_hash);
+        let cache = if cfg.use_cache {
+            let cache = read_cache()
+                .map(|s| parse_cache(&s, output))
+                .unwrap_or_default();
+            log::debug!("{} cards in the cache", cache.len());
+            cache
         } else {
-            let s = anki_api::get_cards_cache_string().unwrap_or("{}".to_string());
-            serde_json::from_str(&s).unwrap_or(HashMap::new())
+            HashMap::new()
         };
 
         Self {
@@ -45,10 +156,25 @@ impl CardsCacheManager {
         }
     }
 
+    // Used in watch mode when ankiconf.typ is modified
+    pub fn update_ankiconf_hash(&mut self, cfg: &Config, ankiconf_hash: String) {
+        self.static_hash = compute_static_hash(cfg, &ankiconf_hash);
+    }
+
+    // Moves the hashes of this run into the old cache, so that the next run in watch mode
+    // compares cards against what was just uploaded
+    pub fn commit_new_cache(&mut self) {
+        let new_cache = std::mem::take(&mut self.new_cache);
+        self.old_cache.extend(new_cache);
+    }
+
     pub fn add_card_hash(&mut self, deck_name: &str, card_id: &str, content_hash: &str) {
         self.new_cache.insert(
             card_key(deck_name, card_id),
-            cache_concat_hashes_padding(&self.static_hash, content_hash),
+            CachedCard {
+                config_hash: self.static_hash.clone(),
+                content_hash: content_hash.to_string(),
+            },
         );
     }
 
@@ -59,9 +185,21 @@ impl CardsCacheManager {
         self.old_cache.remove(&key);
     }
 
-    pub fn detect_configuration_change(&mut self, output: &impl OutputManager) {
-        let cfg = config::get();
-        if cfg.skip_cache {
+    // Keeps the old hash for a card that wasn't processed (used when aborting with --fail-fast)
+    pub fn discard_new_card_hash(&mut self, deck_name: &str, card_id: &str) {
+        self.new_cache.remove(&card_key(deck_name, card_id));
+    }
+
+    // Drops the cards of these decks that weren't found in this run, so that their images
+    // are uploaded again if they come back after their images were deleted
+    pub fn forget_missing_cards(&mut self, decks: &HashSet<&str>, live: &HashSet<String>) {
+        self.old_cache.retain(|k, _| {
+            live.contains(k) || !decks.iter().any(|d| k.starts_with(&card_key(d, "")))
+        });
+    }
+
+    pub fn detect_configuration_change(&mut self, cfg: &Config, output: &impl OutputManager) {
+        if !cfg.use_cache {
             return;
         }
 
@@ -70,7 +208,7 @@ impl CardsCacheManager {
         for (k, v) in &self.old_cache {
             total_cards += 1;
             if let Some(new_v) = self.new_cache.get(k)
-                && v[..CACHE_HASH_PART_LENGTH] != new_v[..CACHE_HASH_PART_LENGTH]
+                && v.config_hash != new_v.config_hash
             {
                 config_changes += 1;
             }
@@ -83,35 +221,126 @@ impl CardsCacheManager {
             });
         }
 
-        if cfg.recompile_on_config_change.read().unwrap().is_none()
+        let configured = *cfg.recompile_on_config_change.read().unwrap();
+        if configured.is_none()
             && total_cards > 0
             && (config_changes as f64) / (total_cards as f64) >= 0.2
         {
-            if output.ask_yes_no("A configuration or ankiconf.typ change has been detected. Do you wish to recompile all cards with this new config? (y/N)", false) {
-                    *cfg.recompile_on_config_change.write().unwrap() = Some(true);
-                } else {
-                    *cfg.recompile_on_config_change.write().unwrap() = Some(false);
-                }
+            // With `_`, nobody answering (--non-interactive, closed stdin) doesn't recompile
+            let recompile = output.ask_yes_no(
+                "A configuration or ankiconf.typ change has been detected. Do you wish to recompile all cards with this new config?",
+                configured.unwrap_or(false),
+            );
+            *cfg.recompile_on_config_change.write().unwrap() = Some(recompile);
         }
     }
 
-    pub fn save_cache(&self, output: &impl OutputManager) {
-        let cfg = config::get();
-        if cfg.dry_run || cfg.skip_cache {
+    pub fn save_cache(&self, cfg: &Config, output: &impl OutputManager) {
+        if !cfg.use_cache {
             return;
         }
-        let push: HashMap<String, String> = self
-            .old_cache
-            .clone()
-            .into_iter()
-            .chain(self.new_cache.clone())
-            .collect();
+        let push = CacheFile {
+            version: CACHE_VERSION,
+            cards: self
+                .old_cache
+                .clone()
+                .into_iter()
+                .chain(self.new_cache.clone())
+                .collect(),
+        };
         let s = serde_json::to_string(&push).unwrap_or("{}".to_string());
+
+        let local_path = local_cache_path(cfg);
+        if let Err(e) = local_path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&local_path, &s))
+        {
+            output.send(OutputMessage::ErrorSavingCache(format!(
+                "failed to write {}: {}",
+                local_path.display(),
+                e
+            )));
+        }
+        if cfg.dry_run {
+            return;
+        }
+
         let payload = utils::b64_encode(s);
-        if let Err(e) = anki_api::upload_file(anki_api::CARDS_CACHE_FILENAME.into(), &payload) {
-            output.send(OutputMessage::ErrorSavingCache(e));
+        if let Err(e) = anki_api::upload_file(anki_api::cards_cache_filename(cfg), &payload) {
+            output.send(OutputMessage::ErrorSavingCache(e.to_string()));
         } else {
             output.send(OutputMessage::DbgSavedCache);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{config::config_from_args, output_json::OutputJson};
+
+    const CACHE: &str =
+        r#"{"version":2,"cards":{"Deck_001":{"config_hash":"abc","content_hash":"def"}}}"#;
+
+    fn init(args: &[&str]) -> CardsCacheManager {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = args.to_vec();
+        let path = dir.path().to_string_lossy().into_owned();
+        args.push(&path);
+        let cfg = Arc::new(config_from_args(&args));
+        let output = OutputJson::new(cfg.clone());
+        CardsCacheManager::init_with(&cfg, String::new(), &output, || Some(CACHE.to_string()))
+    }
+
+    #[test]
+    fn loads_the_cache_by_default() {
+        let manager = init(&[]);
+        assert_eq!(
+            manager.old_cache.get("Deck_001"),
+            Some(&CachedCard {
+                config_hash: "abc".to_string(),
+                content_hash: "def".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn corrupted_cache_is_empty() {
+        let cfg = Arc::new(config_from_args(&["--no-cache"]));
+        let output = OutputJson::new(cfg);
+        assert!(parse_cache("", &output).is_empty());
+        assert!(parse_cache("not json", &output).is_empty());
+        assert!(parse_cache(&CACHE[..CACHE.len() / 2], &output).is_empty());
+        assert!(parse_cache(r#"{"version":2,"cards":"oops"}"#, &output).is_empty());
+    }
+
+    #[test]
+    fn migrates_v1_entries() {
+        let config_hash = "a".repeat(32);
+        let content_hash = "b".repeat(32);
+        let expected = CachedCard {
+            config_hash: config_hash.clone(),
+            content_hash: content_hash.clone(),
+        };
+        let padded = format!("{:<34}{:>34}", config_hash, content_hash);
+        assert_eq!(migrate_v1_entry(&padded), Some(expected.clone()));
+        let separated = format!("{}:{}", config_hash, content_hash);
+        assert_eq!(migrate_v1_entry(&separated), Some(expected.clone()));
+        assert_eq!(migrate_v1_entry("too short"), None);
+        assert_eq!(migrate_v1_entry(":"), None);
+
+        let cfg = Arc::new(config_from_args(&["--no-cache"]));
+        let output = OutputJson::new(cfg);
+        let cache = parse_cache(&format!(r#"{{"Deck_001":"{}"}}"#, separated), &output);
+        assert_eq!(cache.get("Deck_001"), Some(&expected));
+    }
+
+    #[test]
+    fn no_cache_ignores_the_cache() {
+        let manager = init(&["--no-cache"]);
+        assert!(manager.old_cache.is_empty());
+    }
+}
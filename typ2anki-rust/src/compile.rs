@@ -1,9 +1,15 @@
 use once_cell::sync::OnceCell;
 use std::{
     ops::Range,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
 };
 use typst::{
+    diag::{SourceDiagnostic, SourceResult, Warned},
     layout::PagedDocument,
     syntax::{FileId, Source, VirtualPath},
 };
@@ -12,111 +18,484 @@ use crate::{
     anki_api,
     card_wrapper::{CardInfo, CardModificationStatus, TFiles},
     cards_cache::CardsCacheManager,
-    config, generator,
+    config::{Config, ImageFormat},
+    error::{self, Typ2AnkiError},
+    export::SharedExporter,
+    generator, image_cache,
     output::{OutputCompiledCardInfo, OutputManager, OutputMessage},
-    typst_as_library::{self, DiagnosticFormat, DownloadLocks},
+    typst_as_library::{self, DiagnosticFormat, DownloadLocks, TypstWrapperWorld, WorldOptions},
     utils,
 };
 
+// Set when a card fails, so that with --fail-fast every thread stops compiling
+static CARD_FAILED: AtomicBool = AtomicBool::new(false);
+
+// Must be called before compiling a new set of cards
+pub fn reset_fail_fast() {
+    CARD_FAILED.store(false, Ordering::Relaxed);
+}
+
+// Set on Ctrl-C: the cards being compiled or uploaded are finished, the others are skipped
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+// Returns whether it was already cancelled
+pub fn cancel() -> bool {
+    CANCELLED.swap(true, Ordering::Relaxed)
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+// The time spent compiling and uploading cards, added up over the threads doing it, since
+// the last call to take_phase_times
+static COMPILE_NANOS: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// Where the time of a run went. The cards are uploaded while the next ones are compiled, so
+// the compile and upload times overlap, and with several threads can exceed the run's time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhaseTimes {
+    pub parse: Duration,
+    pub compile: Duration,
+    pub upload: Duration,
+}
+
+fn add_time(counter: &AtomicU64, since: Instant) {
+    counter.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+// The compile and upload times since the last call, with the given parse time
+pub fn take_phase_times(parse: Duration) -> PhaseTimes {
+    PhaseTimes {
+        parse,
+        compile: Duration::from_nanos(COMPILE_NANOS.swap(0, Ordering::Relaxed)),
+        upload: Duration::from_nanos(UPLOAD_NANOS.swap(0, Ordering::Relaxed)),
+    }
+}
+
+// Uploads wait on AnkiConnect rather than the CPU, so they get their own threads. The queue
+// is bounded so that compiling can't run far ahead and keep every image in memory.
+const UPLOAD_THREADS: usize = 4;
+const UPLOAD_QUEUE_SIZE: usize = 16;
+
+// A compiled card with the images of its pages (front, back, then the extra fields) in
+// base64, waiting to be pushed
+type CompiledCard<'a> = (&'a CardInfo, Vec<String>);
+
 // A cache_manager should be passed so that in the case of an error during
 // compilation or upload, the card's hash can be removed from the cache.
+// When exporters are given, cards are added to them instead of being uploaded to Anki.
 pub fn compile_cards_concurrent(
+    cfg: &Config,
     cards: &Vec<CardInfo>,
     output: Arc<impl OutputManager + 'static>,
     cache_manager: Arc<Mutex<CardsCacheManager>>,
     file_stats: TFiles,
+    exporters: Vec<SharedExporter>,
 ) {
-    let cfg = config::get();
+    reset_fail_fast();
     if cfg.generation_concurrency <= 1 {
-        compile_cards(cards, output, cache_manager, file_stats);
+        compile_cards(cfg, cards, output, cache_manager, file_stats, exporters);
         return;
     }
 
     let total = cards.len();
-    if total > 0 {
-        let n_batches = std::cmp::min(cfg.generation_concurrency, total);
-        let chunk_size = total.div_ceil(n_batches);
-
-        let mut handles = Vec::with_capacity(n_batches);
-        for i in 0..n_batches {
-            let start = i * chunk_size;
-            let end = ((i + 1) * chunk_size).min(total);
-            let batch = cards[start..end].to_vec();
-            let output_clone = output.clone();
-            let cache_manager_clone = cache_manager.clone();
-            let file_stats_clone = file_stats.clone();
-            let handle = std::thread::spawn(move || {
-                compile_cards(&batch, output_clone, cache_manager_clone, file_stats_clone);
+    if total == 0 {
+        return;
+    }
+    let n_threads = std::cmp::min(cfg.generation_concurrency, total);
+
+    // Threads take the next card from a shared index as soon as they are done with one,
+    // instead of each being given a fixed share of the cards up front
+    let next_card = AtomicUsize::new(0);
+    // Every thread compiles in a clone of the same world, sharing its fonts and the files
+    // it has loaded
+    let base_world = new_world(cfg, output.clone());
+    push_cards_concurrent(
+        cfg,
+        output.clone(),
+        cache_manager.clone(),
+        file_stats.clone(),
+        exporters,
+        |push| {
+            std::thread::scope(|scope| {
+                let mut handles = Vec::with_capacity(n_threads);
+                for _ in 0..n_threads {
+                    let output = output.clone();
+                    let cache_manager = cache_manager.clone();
+                    let file_stats = file_stats.clone();
+                    let push = push.clone();
+                    let next_card = &next_card;
+                    let mut world = base_world.clone();
+                    let handle = scope.spawn(move || {
+                        let cards = std::iter::from_fn(|| {
+                            cards.get(next_card.fetch_add(1, Ordering::Relaxed))
+                        });
+                        compile_cards_into(
+                            cfg,
+                            &mut world,
+                            cards,
+                            output,
+                            cache_manager,
+                            file_stats,
+                            &push,
+                        );
+                    });
+                    handles.push(handle);
+                }
+
+                for h in handles {
+                    let _ = h.join();
+                }
             });
-            handles.push(handle);
+        },
+    );
+}
+
+// Typst warns about a font family it can't find, and uses one of its embedded fonts instead
+fn is_font_fallback(warning: &SourceDiagnostic) -> bool {
+    warning.message.starts_with("unknown font family")
+}
+
+// Whether a rendered page only has the background color of the cards, e.g. because an
+// image failed to be included
+fn is_blank(cfg: &Config, pixmap: &tiny_skia::Pixmap) -> bool {
+    let fill = cfg.card_fill_rgba();
+    // Demultiplying rounds, so the colors may be off by one
+    let close = |a: u8, b: u8| a.abs_diff(b) <= 1;
+    pixmap.pixels().iter().all(|px| {
+        let px = px.demultiply();
+        if fill[3] == 0 {
+            return px.alpha() == 0;
         }
+        close(px.red(), fill[0])
+            && close(px.green(), fill[1])
+            && close(px.blue(), fill[2])
+            && close(px.alpha(), fill[3])
+    })
+}
 
-        for h in handles {
-            let _ = h.join();
+// Returns the RGB bytes of a rendered page, composited over a white background
+fn flatten_to_rgb(pixmap: &tiny_skia::Pixmap) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(pixmap.width() as usize * pixmap.height() as usize * 3);
+    // Pixels are premultiplied, so adding the missing alpha gives the color over white
+    for px in pixmap.pixels() {
+        let white = 255 - px.alpha();
+        rgb.extend_from_slice(&[px.red() + white, px.green() + white, px.blue() + white]);
+    }
+    rgb
+}
+
+fn encode_image(
+    cfg: &Config,
+    pixmap: tiny_skia::Pixmap,
+    format: ImageFormat,
+) -> Result<Vec<u8>, String> {
+    match format {
+        // PNG keeps the alpha channel, so transparent cards stay transparent
+        ImageFormat::Png => pixmap.encode_png().map_err(|e| e.to_string()),
+        ImageFormat::Jpeg => {
+            let (width, height) = (
+                u16::try_from(pixmap.width()).map_err(|_| "image too wide for JPEG")?,
+                u16::try_from(pixmap.height()).map_err(|_| "image too tall for JPEG")?,
+            );
+            let mut out = Vec::new();
+            jpeg_encoder::Encoder::new(&mut out, cfg.image_quality)
+                .encode(
+                    &flatten_to_rgb(&pixmap),
+                    width,
+                    height,
+                    jpeg_encoder::ColorType::Rgb,
+                )
+                .map_err(|e| e.to_string())?;
+            Ok(out)
+        }
+        ImageFormat::Webp if cfg.card_fill == "transparent" => {
+            let rgba: Vec<u8> = pixmap
+                .pixels()
+                .iter()
+                .flat_map(|px| {
+                    let c = px.demultiply();
+                    [c.red(), c.green(), c.blue(), c.alpha()]
+                })
+                .collect();
+            webp::Encoder::from_rgba(&rgba, pixmap.width(), pixmap.height())
+                .encode_simple(false, cfg.image_quality as f32)
+                .map(|m| m.to_vec())
+                .map_err(|e| format!("{:?}", e))
+        }
+        ImageFormat::Webp => {
+            let rgb = flatten_to_rgb(&pixmap);
+            webp::Encoder::from_rgb(&rgb, pixmap.width(), pixmap.height())
+                .encode_simple(false, cfg.image_quality as f32)
+                .map(|m| m.to_vec())
+                .map_err(|e| format!("{:?}", e))
         }
     }
 }
 
+// Compiles on a separate thread, giving up after the timeout. Typst compilations can't be
+// interrupted, so a timed out one keeps running in the background until it ends (or the
+// program exits), but the remaining cards are not held up by it.
+fn compile_with_timeout(
+    world: &TypstWrapperWorld,
+    timeout: Duration,
+) -> error::Result<Warned<SourceResult<PagedDocument>>> {
+    let world = world.clone();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(typst::compile::<PagedDocument>(&world));
+    });
+    rx.recv_timeout(timeout).map_err(|_| {
+        Typ2AnkiError::Compile(format!(
+            "Error: Compilation timed out after {} seconds.",
+            timeout.as_secs()
+        ))
+    })
+}
+
 static TYPST_PACKAGE_DOWNLOAD_LOCK: OnceCell<DownloadLocks> = OnceCell::new();
 
 pub fn compile_cards(
+    cfg: &Config,
     cards: &Vec<CardInfo>,
     output: Arc<impl OutputManager + 'static>,
     cache_manager: Arc<Mutex<CardsCacheManager>>,
     file_stats: TFiles,
+    exporters: Vec<SharedExporter>,
 ) {
     if cards.is_empty() {
         return;
     }
-    let cfg = config::get();
-
-    let uploader = anki_api::CardUploaderThread::new();
-
-    let mut base_length: usize = 0;
-    let mut current_file_path = String::new();
+    let mut world = new_world(cfg, output.clone());
+    compile_cards_in_world(
+        cfg,
+        &mut world,
+        cards,
+        output,
+        cache_manager,
+        file_stats,
+        exporters,
+    );
+}
 
-    let mut world = typst_as_library::TypstWrapperWorld::new_with_download_locks(
+pub fn new_world(cfg: &Config, output: Arc<impl OutputManager + 'static>) -> TypstWrapperWorld {
+    let mut world = TypstWrapperWorld::new(
         cfg.path.to_string_lossy().into_owned(),
         "".to_string(),
         &cfg.typst_input,
+        WorldOptions {
+            package_cache: cfg.package_cache.clone(),
+            offline: cfg.offline,
+            font_paths: &cfg.font_paths,
+            include_system_fonts: !cfg.ignore_system_fonts,
+            now: cfg.now,
+            lockfile: Some(cfg.lockfile.clone()),
+            proxy: cfg.proxy.as_deref(),
+        },
+    )
+    .with_download_locks(
         TYPST_PACKAGE_DOWNLOAD_LOCK
             .get_or_init(DownloadLocks::default)
             .clone(),
     );
-    world.output_manager = Some(output.clone());
-
-    let mut content_range: Range<usize> = 0..0;
+    world.output_manager = Some(output);
+    world
+}
 
-    let card_error = |card: &CardInfo, m: OutputMessage| {
-        let mut cache_manager = cache_manager.lock().unwrap();
-        cache_manager.remove_card_hash(card.deck_name.as_str(), &card.card_id);
+// Removes the card's hash from the cache, so that it is retried on the next run,
+// and counts the error in its file's stats
+fn card_error(
+    card: &CardInfo,
+    m: OutputMessage,
+    output: &impl OutputManager,
+    cache_manager: &Mutex<CardsCacheManager>,
+    file_stats: &TFiles,
+) {
+    CARD_FAILED.store(true, Ordering::Relaxed);
+    let mut cache_manager = cache_manager.lock().unwrap();
+    cache_manager.remove_card_hash(card.deck_name.as_str(), &card.card_id);
 
-        {
-            let mut file_stats = file_stats.write().unwrap();
-            if let Some(stats) = file_stats.get_mut(&card.source_file) {
-                match card.modification_status {
-                    CardModificationStatus::New => stats.new_cards.1 += 1,
-                    CardModificationStatus::Updated => stats.updated_cards.1 += 1,
-                    CardModificationStatus::Unchanged => stats.unchanged_cards.1 += 1,
-                    CardModificationStatus::Unknown => {}
-                }
+    {
+        let mut file_stats = file_stats.write().unwrap();
+        if let Some(stats) = file_stats.get_mut(&card.source_file) {
+            match card.modification_status {
+                CardModificationStatus::New => stats.new_cards.1 += 1,
+                CardModificationStatus::Updated => stats.updated_cards.1 += 1,
+                CardModificationStatus::Unchanged => stats.unchanged_cards.1 += 1,
+                CardModificationStatus::Unknown => {}
             }
         }
+    }
+
+    output.send(m);
+}
 
-        output.send(m);
+// Runs `compile` with a sender for the compiled cards, while other threads upload them to
+// Anki (or add them to the exporters). Returns once every card has been pushed.
+fn push_cards_concurrent<'a, R>(
+    cfg: &Config,
+    output: Arc<impl OutputManager + 'static>,
+    cache_manager: Arc<Mutex<CardsCacheManager>>,
+    file_stats: TFiles,
+    exporters: Vec<SharedExporter>,
+    compile: impl FnOnce(mpsc::SyncSender<CompiledCard<'a>>) -> R,
+) -> R {
+    let (tx, rx) = mpsc::sync_channel::<CompiledCard<'a>>(UPLOAD_QUEUE_SIZE);
+    let rx = Mutex::new(rx);
+    // Exporters write to a single file, more threads would only wait on its lock
+    let n_threads = if exporters.is_empty() {
+        UPLOAD_THREADS
+    } else {
+        1
     };
 
-    // Returns a Result with Option of front and back base64 strings
-    let mut compile_card = |card: &CardInfo| -> Result<Option<(String, String)>, String> {
+    std::thread::scope(|scope| {
+        for _ in 0..n_threads {
+            let output = output.clone();
+            let cache_manager = cache_manager.clone();
+            let file_stats = file_stats.clone();
+            let exporters = exporters.clone();
+            let rx = &rx;
+            scope.spawn(move || {
+                let uploader = anki_api::CardUploaderThread::new();
+                loop {
+                    let Ok((card, pages_b64)) = rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    if cfg.fail_fast && CARD_FAILED.load(Ordering::Relaxed) {
+                        // The card wasn't uploaded, so its cached hash must stay the old one
+                        cache_manager
+                            .lock()
+                            .unwrap()
+                            .discard_new_card_hash(&card.deck_name, &card.card_id);
+                        continue;
+                    }
+                    let start = Instant::now();
+                    let pushed = if exporters.is_empty() {
+                        uploader
+                            .upload_card(cfg, card, &pages_b64)
+                            .map_err(|e| e.context("Error uploading card to Anki"))
+                    } else {
+                        exporters.iter().try_for_each(|exporter| {
+                            exporter
+                                .lock()
+                                .unwrap()
+                                .add_card(cfg, card, &pages_b64)
+                                .map_err(|e| e.context("Error exporting card"))
+                        })
+                    };
+                    add_time(&UPLOAD_NANOS, start);
+                    if let Err(e) = pushed {
+                        card_error(
+                            card,
+                            OutputMessage::PushError(OutputCompiledCardInfo::from_error(card, e)),
+                            output.as_ref(),
+                            &cache_manager,
+                            &file_stats,
+                        );
+                    } else {
+                        if exporters.is_empty()
+                            && !cfg.dry_run
+                            && let Some(stats) =
+                                file_stats.write().unwrap().get_mut(&card.source_file)
+                        {
+                            stats.media_bytes += pages_b64
+                                .iter()
+                                .map(|p| utils::b64_decoded_len(p) as u64)
+                                .sum::<u64>();
+                        }
+                        output.send(OutputMessage::PushedCard(card.into()));
+                    }
+                }
+            });
+        }
+        // The upload threads stop once the queue is empty and every sender has been dropped
+        compile(tx)
+    })
+}
+
+// Compiles the cards reusing an existing world, so that fonts and packages are only loaded once
+pub fn compile_cards_in_world<'a>(
+    cfg: &Config,
+    world: &mut TypstWrapperWorld,
+    cards: impl IntoIterator<Item = &'a CardInfo>,
+    output: Arc<impl OutputManager + 'static>,
+    cache_manager: Arc<Mutex<CardsCacheManager>>,
+    file_stats: TFiles,
+    exporters: Vec<SharedExporter>,
+) {
+    push_cards_concurrent(
+        cfg,
+        output.clone(),
+        cache_manager.clone(),
+        file_stats.clone(),
+        exporters,
+        |push| compile_cards_into(cfg, world, cards, output, cache_manager, file_stats, &push),
+    );
+}
+
+// How the page at `index` (0-based) of a card is called in messages
+fn side_name(cfg: &Config, index: usize) -> String {
+    match index {
+        0 => "front".to_string(),
+        1 => "back".to_string(),
+        _ => format!("'{}' page", cfg.extra_fields[index - 2]),
+    }
+}
+
+// Compiles the cards and sends them to `push` to be uploaded
+fn compile_cards_into<'a>(
+    cfg: &Config,
+    world: &mut TypstWrapperWorld,
+    cards: impl IntoIterator<Item = &'a CardInfo>,
+    output: Arc<impl OutputManager + 'static>,
+    cache_manager: Arc<Mutex<CardsCacheManager>>,
+    file_stats: TFiles,
+    push: &mpsc::SyncSender<CompiledCard<'a>>,
+) {
+    let mut base_length: usize = 0;
+    let mut current_file_path = String::new();
+    let mut current_max_card_width = String::new();
+
+    let static_hash = cache_manager.lock().unwrap().static_hash.clone();
+
+    let mut content_range: Range<usize> = 0..0;
+
+    // Returns a Result with Option of the base64 images of the pages
+    let mut compile_card = |card: &CardInfo| -> error::Result<Option<Vec<String>>> {
         if card.modification_status == CardModificationStatus::Unchanged {
             output.send(OutputMessage::SkipCompileCard(card.into()));
             return Ok(None);
         }
-        if current_file_path != card.path_relative_to_root() {
-            current_file_path = card.path_relative_to_root();
+
+        let deck_config = card.deck_config(cfg);
+
+        let image_cache_key = image_cache::card_key(cfg, &static_hash, card);
+        let page_count = cfg.page_count();
+        // --force recompiles the cards, the images are still cached for the next runs
+        if !cfg.force
+            && let Some(pages) =
+                image_cache::get(&image_cache_key, deck_config.image_format, page_count)
+        {
+            output.send(OutputMessage::CompiledCard(card.into()));
+            return Ok(Some(pages.into_iter().map(utils::b64_encode).collect()));
+        }
+
+        // The base content depends on the file (for the ankiconf import) and the deck's settings
+        if current_file_path != card.path_relative_to_root(cfg)
+            || world.root() != card.root(cfg)
+            || current_max_card_width != deck_config.max_card_width
+        {
+            world.set_root(card.root(cfg));
+            current_file_path = card.path_relative_to_root(cfg);
+            current_max_card_width = deck_config.max_card_width.clone();
             let base = generator::generate_card_file_content(
-                card.relative_ankiconf_path(),
+                cfg,
+                card.relative_ankiconf_path(cfg),
                 "".to_string(),
+                &deck_config,
             );
             base_length = base.len();
             world.source = Source::new(
@@ -130,56 +509,153 @@ pub fn compile_cards(
         let last = world.source.text().len();
         content_range = base_length..last;
 
-        let out = typst::compile(&world);
+        let out = match cfg.card_timeout {
+            Some(timeout) => compile_with_timeout(world, timeout)?,
+            None => typst::compile(&*world),
+        };
         let document: PagedDocument = out.output.map_err(|e| {
-            typst_as_library::render_diagnostics(
-                &world,
-                e.as_slice(),
-                out.warnings.as_slice(),
-                DiagnosticFormat::Human,
+            Typ2AnkiError::Compile(
+                typst_as_library::render_diagnostics(
+                    world,
+                    e.as_slice(),
+                    out.warnings.as_slice(),
+                    DiagnosticFormat::Human,
+                    cfg.color,
+                )
+                .unwrap_or_else(|_| "Failed to render diagnostics.".to_string()),
             )
-            .unwrap_or_else(|_| "Failed to render diagnostics.".to_string())
         })?;
 
-        if document.pages.len() < 2 {
-            return Err("Error: Compiled document has less than 2 pages.".to_string());
+        // A missing font silently changes how the card looks, so it is always reported
+        let shown_warnings: Vec<SourceDiagnostic> = out
+            .warnings
+            .iter()
+            .filter(|w| cfg.show_warnings || is_font_fallback(w))
+            .cloned()
+            .collect();
+        if !shown_warnings.is_empty() {
+            let warnings = typst_as_library::render_diagnostics(
+                world,
+                &[],
+                &shown_warnings,
+                DiagnosticFormat::Human,
+                cfg.color,
+            )
+            .unwrap_or_else(|_| "Failed to render diagnostics.".to_string());
+            output.send(OutputMessage::CompileWarning(
+                OutputCompiledCardInfo::build(card, Some(warnings)),
+            ));
+        }
+
+        let pages = document.pages.len();
+        if pages < page_count || (pages > page_count && cfg.strict_pages) {
+            return Err(Typ2AnkiError::Compile(format!(
+                "Error: Compiled document has {} page{}, expected {}.",
+                pages,
+                if pages == 1 { "" } else { "s" },
+                page_count
+            )));
+        }
+        let pixmaps: Vec<_> = document.pages[..page_count]
+            .iter()
+            .map(|page| typst_render::render(page, cfg.render_scale))
+            .collect();
+        let blank_sides: Vec<String> = pixmaps
+            .iter()
+            .enumerate()
+            .filter(|(_, pixmap)| is_blank(cfg, pixmap))
+            .map(|(i, _)| side_name(cfg, i))
+            .collect();
+        if cfg.strict_pages && !blank_sides.is_empty() {
+            return Err(Typ2AnkiError::Compile(format!(
+                "Error: The {} of the card is blank.",
+                blank_sides.join(" and ")
+            )));
         }
 
-        let render = typst_render::render(&document.pages[0], 2.0)
-            .encode_png()
-            .map_err(|_| "Error encoding front side PNG.")?;
-        let front_b64 = utils::b64_encode(render);
+        let mut warnings: Vec<String> = Vec::new();
+        if pages > page_count {
+            warnings.push(format!(
+                "The card has {} pages, only the first {} are used (is there a stray pagebreak?)",
+                pages, page_count
+            ));
+        }
 
-        let render = typst_render::render(&document.pages[1], 2.0)
-            .encode_png()
-            .map_err(|_| "Error encoding back side PNG.")?;
-        let back_b64 = utils::b64_encode(render);
+        for side in blank_sides {
+            warnings.push(format!("The {} of the card is blank", side));
+        }
+        if !warnings.is_empty() {
+            output.send(OutputMessage::CompileWarning(
+                OutputCompiledCardInfo::build(card, Some(warnings.join("\n"))),
+            ));
+        }
+
+        let format = deck_config.image_format;
+        let images = pixmaps
+            .into_iter()
+            .enumerate()
+            .map(|(i, pixmap)| {
+                encode_image(cfg, pixmap, format).map_err(|e| {
+                    Typ2AnkiError::Compile(format!(
+                        "Error encoding {} image: {}",
+                        side_name(cfg, i),
+                        e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        image_cache::put(&image_cache_key, format, &images);
 
         output.send(OutputMessage::CompiledCard(card.into()));
 
-        Ok(Some((front_b64, back_b64)))
+        Ok(Some(images.into_iter().map(utils::b64_encode).collect()))
     };
 
     for card in cards {
-        match compile_card(card) {
-            Ok(Some((front_b64, back_b64))) => {
-                if let Err(e) = uploader.upload_card(card, &front_b64, &back_b64) {
-                    card_error(
-                        card,
-                        OutputMessage::PushError(OutputCompiledCardInfo::build(
-                            card,
-                            Some(format!("Error uploading card to Anki: {}", e)),
-                        )),
-                    );
-                } else {
-                    output.send(OutputMessage::PushedCard(card.into()));
-                }
+        if is_cancelled() || (cfg.fail_fast && CARD_FAILED.load(Ordering::Relaxed)) {
+            // The card wasn't uploaded, so its cached hash must stay the old one
+            cache_manager
+                .lock()
+                .unwrap()
+                .discard_new_card_hash(&card.deck_name, &card.card_id);
+            continue;
+        }
+        let start = Instant::now();
+        let result = compile_card(card);
+        add_time(&COMPILE_NANOS, start);
+        if let Some(stats) = file_stats.write().unwrap().get_mut(&card.source_file) {
+            stats.compile_time += start.elapsed();
+        }
+        if let Some(dir) = &cfg.dump_source
+            && (result.is_err() || (cfg.dump_all_sources && matches!(result, Ok(Some(_)))))
+        {
+            match generator::dump_card_file(cfg, card, dir) {
+                Ok(path) => log::info!(
+                    "Wrote the source of card {} to {}",
+                    card.card_id,
+                    path.display()
+                ),
+                Err(e) => log::warn!(
+                    "Failed to write the source of card {} to {}: {}",
+                    card.card_id,
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+        match result {
+            Ok(Some(pages_b64)) => {
+                // Only fails if every upload thread has stopped
+                let _ = push.send((card, pages_b64));
             }
             Ok(None) => {}
-            Err(msg) => {
+            Err(e) => {
                 card_error(
                     card,
-                    OutputMessage::CompileError(OutputCompiledCardInfo::build(card, Some(msg))),
+                    OutputMessage::CompileError(OutputCompiledCardInfo::from_error(card, e)),
+                    output.as_ref(),
+                    &cache_manager,
+                    &file_stats,
                 );
             }
         }
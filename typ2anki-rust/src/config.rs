@@ -1,27 +1,49 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
-use tempfile::tempdir_in;
+use std::time::Duration;
+use tempfile::{TempDir, tempdir_in};
 
+use clap::error::ErrorKind;
 use clap::parser::ValueSource;
-use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use glob::Pattern;
-use once_cell::sync::OnceCell;
 use serde_json::{Value, json};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 use toml::Value as TomlValue;
 
-use html_escape::encode_double_quoted_attribute;
+use html_escape::{encode_double_quoted_attribute, encode_text};
 
 use crate::card_wrapper::CardInfo;
+use crate::doctor;
+use crate::init;
+use crate::lockfile::Lockfile;
+use crate::logging;
+use crate::output::{ColorChoice, OutputFormat};
 use crate::utils;
 use std::sync::{Arc, RwLock};
 
 pub const DEFAULT_CONFIG_FILENAME: &str = "typ2anki.toml";
+pub const DEFAULT_RENDER_SCALE: f32 = 2.0;
+pub const DEFAULT_CARD_FILL: &str = "#ffffff";
+pub const DEFAULT_CARD_MARGIN: &str = "3pt";
+// Content of the Front/Back fields of a note. {image} is replaced by the image's file name,
+// {card_id} and {deck} by the card's id and deck
+pub const DEFAULT_CARD_TEMPLATE: &str = r#"<img src="{image}">"#;
+// Functions defined by typ2anki for the cards, see generator.rs
+pub const DEFAULT_CARD_FUNCTIONS: [&str; 2] = ["card", "custom-card"];
 
 #[derive(Parser, Debug)]
-#[command(about = "Typ2Anki config parser", version)]
+#[command(
+    about = "Typ2Anki config parser",
+    version,
+    args_conflicts_with_subcommands = true
+)]
 struct Cli {
-    /// Specify the path to the config file. Set to empty string to disable config file.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Specify the path to the config file (.toml, .json or .yaml). Set to empty string to disable config file.
     #[arg(long = "config-file", default_value = DEFAULT_CONFIG_FILENAME)]
     config_file: String,
 
@@ -37,26 +59,246 @@ struct Cli {
     #[arg(long = "exclude-files", action = clap::ArgAction::Append)]
     exclude_files: Vec<String>,
 
-    /// Specify how many cards at a time can be generated. Needs duplicate checking enabled.
+    /// Only process these decks. Use multiple --include-decks options. Glob patterns supported. Excludes are applied afterwards.
+    #[arg(long = "include-decks", action = clap::ArgAction::Append)]
+    include_decks: Vec<String>,
+
+    /// Only process these files. Use multiple --include-files options. Glob patterns supported. Excludes are applied afterwards.
+    #[arg(long = "include-files", action = clap::ArgAction::Append)]
+    include_files: Vec<String>,
+
+    /// Specify how many cards at a time can be generated ('max' for one per logical CPU)
     #[arg(long = "generation-concurrency", default_value = "")]
     generation_concurrency: String,
 
+    /// Also read calls to this function as cards, besides `card` and `custom-card` (e.g. 'flashcard'). Use multiple --card-function options
+    #[arg(long = "card-function", action = clap::ArgAction::Append, value_parser = parse_card_function)]
+    card_functions: Vec<String>,
+
+    /// How cards are found: 'heuristic' reads each `#card(...)` call up to its closing parenthesis, 'markers' takes everything between a `// card-begin` line and a `// card-end` line
+    #[arg(long = "card-delimiters", value_enum, default_value = "heuristic")]
+    card_delimiters: CardDelimiters,
+
+    /// Pass an input to the cards' Typst code, read with `sys.inputs.at("key")`. Use multiple --input key=value options
+    #[arg(long = "input", action = clap::ArgAction::Append, value_parser = parse_typst_input)]
+    inputs: Vec<(String, String)>,
+
     /// Max card width, 'auto' or a value
     #[arg(long = "max-card-width", default_value = "auto")]
     max_card_width: String,
 
-    /// Force reupload of all images
+    /// Max card height, 'auto' or a value. Taller cards are scaled down to fit.
+    #[arg(long = "max-card-height", default_value = "auto")]
+    max_card_height: String,
+
+    /// Scale at which cards are rendered to images (2.0 = 144 DPI). Higher values give sharper but larger images.
+    #[arg(long = "render-scale", default_value = "2.0", value_parser = parse_render_scale)]
+    render_scale: f32,
+
+    /// Background color of the cards, like '#1e1e1e', or 'transparent'
+    #[arg(long = "card-fill", default_value = DEFAULT_CARD_FILL, value_parser = parse_card_fill)]
+    card_fill: String,
+
+    /// Margin around the content of the cards, like '3pt' or '1mm'
+    #[arg(long = "card-margin", default_value = DEFAULT_CARD_MARGIN, value_parser = parse_card_margin)]
+    card_margin: String,
+
+    /// Image format of the rendered cards: 'png', 'jpeg' or 'webp'. JPEG and WebP are much smaller for large decks.
+    #[arg(long = "image-format", value_enum, default_value = "png")]
+    image_format: ImageFormat,
+
+    /// Quality (1-100) of JPEG and WebP images
+    #[arg(long = "image-quality", default_value = "90", value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: u8,
+
+    /// Name the images of the cards typ-<id>-<page>.png, like older versions, instead of including a hash of the deck. Cards with the same ID in different decks then overwrite each other's images
+    #[arg(long = "legacy-media-names")]
+    legacy_media_names: bool,
+
+    /// Add a hash of the card's content to the names of its images, so that Anki clients that cache images by name show the new version of a card. The old images are left in the media folder
+    #[arg(long = "hash-media-names")]
+    hash_media_names: bool,
+
+    /// Force reupload of all images, without reading or updating the cards cache (see --force to also keep the cache up to date)
     #[arg(long = "no-cache")]
     no_cache: bool,
 
+    /// Recompile and upload every card, even the unchanged ones. Unlike --no-cache, the cache is still updated, so the next runs only process the cards that change
+    #[arg(long = "force", conflicts_with = "only_changed")]
+    force: bool,
+
+    /// Delete the images kept on disk from previous runs before compiling
+    #[arg(long = "clear-cache")]
+    clear_cache: bool,
+
+    /// After uploading, delete the images of the project's decks that no card uses anymore from Anki's media folder, after confirmation. With --dry-run, only list them
+    #[arg(long = "prune-media", conflicts_with_all = ["export_apkg", "export_csv", "preview_dir", "list"])]
+    prune_media: bool,
+
+    /// Record the current files of the Typst packages in typ2anki.lock, instead of failing the cards whose packages changed
+    #[arg(long = "update-lock")]
+    update_lock: bool,
+
     /// Whether to recompile cards if the config has changed. Accepts 'y' or 'n', or '_' to ask.
     #[arg(long = "recompile-on-config-change", default_value = "_")]
     recompile_on_config_change: String,
 
+    /// Stop compiling the remaining cards as soon as one card fails
+    #[arg(long = "fail-fast")]
+    fail_fast: bool,
+
+    /// Show the Typst warnings of cards that compile successfully
+    #[arg(long = "show-warnings")]
+    show_warnings: bool,
+
+    /// Anki note type of new notes, instead of the Basic one; the front and back go in its first two fields. The `model` of a deck table in typ2anki.toml takes precedence
+    #[arg(long = "model")]
+    model: Option<String>,
+
+    /// Name of the project, for several typ2anki projects in one Anki profile: its cards cache is _typ-cards-cache-<name>.json and its notes are tagged <name>::<card_id>, so that they don't clash with the other projects'
+    #[arg(long = "project", value_parser = parse_project)]
+    project: Option<String>,
+
+    /// Also tag the notes with the file and deck of their card (typ2anki::src::<path> and typ2anki::deck::<deck>), so that other tools can find the source of a note
+    #[arg(long = "embed-metadata")]
+    embed_metadata: bool,
+
+    /// Merge the cards of a file with the same ID and target deck into one card, in source order: its question is their questions one after the other, and its answer their answers
+    #[arg(long = "merge-by-id")]
+    merge_by_id: bool,
+
+    /// Add the notes that Anki considers duplicates (same first field as another note of the note type), instead of updating the existing note
+    #[arg(long = "allow-duplicate-notes")]
+    allow_duplicate_notes: bool,
+
+    /// Put the third page of the cards in this field of the note, the next --extra-field in the fourth page's field, and so on. Cards must then have 2 pages plus one per extra field. Needs a note type with these fields (see `model` in the deck tables of typ2anki.toml)
+    #[arg(long = "extra-field", action = clap::ArgAction::Append, value_parser = parse_extra_field)]
+    extra_fields: Vec<String>,
+
+    /// Fail cards that don't render to exactly 2 non-blank pages (plus one per --extra-field), instead of only warning about them
+    #[arg(long = "strict-pages")]
+    strict_pages: bool,
+
+    /// Fail the cards whose target deck contains '::' or characters Anki doesn't accept, instead of only warning about them
+    #[arg(long = "strict-deck-names")]
+    strict_deck_names: bool,
+
+    /// Give up on a card whose compilation takes longer than this many seconds
+    #[arg(long = "card-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    card_timeout: Option<u64>,
+
+    /// Send at most this many requests to AnkiConnect at a time, however many cards are compiled at a time
+    #[arg(long = "anki-max-inflight", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    anki_max_inflight: u64,
+
+    /// Download the Typst packages through this HTTP proxy (e.g. http://proxy.example.com:8080) [default: the HTTPS_PROXY, HTTP_PROXY and NO_PROXY environment variables]
+    #[arg(long = "proxy", value_parser = parse_proxy)]
+    proxy: Option<String>,
+
+    /// Never download Typst packages: fail the cards that use a package that isn't already cached
+    #[arg(long = "offline")]
+    offline: bool,
+
+    /// Folder where downloaded Typst packages are kept [default: typ2anki/packages in the user's cache folder]
+    #[arg(long = "package-cache")]
+    package_cache: Option<String>,
+
+    /// Also use the fonts in this directory. Use multiple --font-path options
+    #[arg(long = "font-path", action = clap::ArgAction::Append)]
+    font_paths: Vec<String>,
+
+    /// Don't use the fonts installed on the system, only the ones from --font-path and Typst's
+    #[arg(long = "ignore-system-fonts")]
+    ignore_system_fonts: bool,
+
+    /// Compile as if it was this time (RFC 3339, e.g. 2024-09-01T08:00:00+02:00), for `datetime.today()` [default: the current time]
+    #[arg(long = "now", value_parser = parse_now)]
+    now: Option<OffsetDateTime>,
+
     /// Run without making changes
     #[arg(long = "dry-run")]
     dry_run: bool,
 
+    /// Never prompt: questions take their default answer (set --recompile-on-config-change to choose it) and the terminal isn't kept open
+    #[arg(long = "non-interactive")]
+    non_interactive: bool,
+
+    /// Upload without first asking to confirm how many cards are added and updated
+    #[arg(short = 'y', long = "yes")]
+    yes: bool,
+
+    /// Instead of uploading to Anki, write all the cards to an .apkg file at this path
+    #[arg(long = "export-apkg")]
+    export_apkg: Option<String>,
+
+    /// Instead of uploading to Anki, write all the cards to a CSV file at this path (TSV if it ends in .tsv), with the images in a sibling <name>_media directory
+    #[arg(long = "export-csv")]
+    export_csv: Option<String>,
+
+    /// With --dry-run, write the front and back images of every card to this directory, named after the card's ID, instead of uploading them
+    #[arg(long = "preview-dir", requires = "dry_run", conflicts_with = "watch")]
+    preview_dir: Option<String>,
+
+    /// Write the Typst source typ2anki compiles for each failing card to <dir>/temporal-<card id>.typ, to open it in Typst. Files named temporal-* are never read as cards
+    #[arg(long = "dump-source")]
+    dump_source: Option<String>,
+
+    /// With --dump-source, write the source of every compiled card, not only the failing ones
+    #[arg(long = "dump-all-sources", requires = "dump_source")]
+    dump_all_sources: bool,
+
+    /// After the first run, keep watching the project and recompile the files that change
+    #[arg(long = "watch", conflicts_with_all = ["export_apkg", "export_csv"])]
+    watch: bool,
+
+    /// Don't read the files that haven't changed since their cards were all uploaded by a previous run with --only-changed. Changing the configuration or ankiconf.typ reads every file again
+    #[arg(long = "only-changed", conflicts_with_all = ["no_cache", "list", "stats_only", "prune_media", "export_apkg", "export_csv", "preview_dir"])]
+    only_changed: bool,
+
+    /// Only print the IDs of the cards added, modified or removed since they were last uploaded, by deck, without compiling or uploading anything
+    #[arg(long = "diff", conflicts_with_all = ["watch", "list", "stats_only", "only_changed", "prune_media", "no_cache", "export_apkg", "export_csv", "preview_dir"])]
+    diff: bool,
+
+    /// Compare the cards of the sources to the notes in Anki (new cards, cards already in Anki, notes whose card was removed from the sources), without compiling or uploading anything
+    #[arg(long = "stats-only", conflicts_with_all = ["watch", "list", "export_apkg", "export_csv", "preview_dir", "prune_media"])]
+    stats_only: bool,
+
+    /// Only compile and upload the cards whose ID matches this glob pattern (e.g. 'chapter-3-*'), the others are left as they are. Use multiple --filter-id options
+    #[arg(long = "filter-id", action = clap::ArgAction::Append, value_parser = parse_filter_glob, conflicts_with_all = ["watch", "only_changed", "prune_media", "diff", "stats_only"])]
+    filter_ids: Vec<String>,
+
+    /// Only compile and upload the cards whose deck matches this glob pattern, the others are left as they are. Unlike --include-decks, the other decks are still checked for duplicate IDs. Use multiple --filter-deck options
+    #[arg(long = "filter-deck", action = clap::ArgAction::Append, value_parser = parse_filter_glob, conflicts_with_all = ["watch", "only_changed", "prune_media", "diff", "stats_only"])]
+    filter_decks: Vec<String>,
+
+    /// Only process the first N cards, in source order (by file path, then position in the file). Duplicate IDs are still checked among all the cards
+    #[arg(long = "limit", value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["watch", "only_changed", "prune_media", "diff", "stats_only"])]
+    limit: Option<u64>,
+
+    /// Compile a synthetic deck this many times, without uploading it or touching the project, and report how long parsing and compiling took
+    #[arg(long = "bench", value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["watch", "list", "stats_only", "diff", "only_changed", "prune_media", "export_apkg", "export_csv", "preview_dir"])]
+    bench: Option<u64>,
+
+    /// Only list the cards that were found, with their deck and whether they are new, updated or unchanged, without compiling them
+    #[arg(long = "list", conflicts_with = "watch")]
+    list: bool,
+
+    /// Show more details about what typ2anki does: -v for info messages, -vv for debug messages, -vvv for everything
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Also write the log messages to this file, including the debug ones (useful when reporting a bug)
+    #[arg(long = "log-file")]
+    log_file: Option<String>,
+
+    /// When to color the output: 'auto' colors it in a terminal unless NO_COLOR is set, 'always' or 'never'
+    #[arg(long = "color", value_enum, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Output format: 'console' for progress bars, 'json' for one JSON object per line
+    #[arg(long = "output-format", value_enum, default_value = "console")]
+    output_format: OutputFormat,
+
     /// Hidden: print config
     #[arg(long = "print-config", hide = true)]
     print_config: bool,
@@ -65,7 +307,7 @@ struct Cli {
     #[arg(long = "auto-number", hide = true)]
     auto_number: Option<String>,
 
-    /// Path to Typst documents folder or zip (positional, allow spaces)
+    /// Paths to Typst documents folders or zips (positional, allow spaces). Each folder has its own ankiconf.typ, and its subfolders can have theirs.
     #[arg(value_parser, num_args = 0..)]
     path: Option<Vec<String>>,
 
@@ -73,17 +315,186 @@ struct Cli {
     keep_terminal_open: bool,
 }
 
-fn load_toml_config(path: &Path) -> Option<TomlValue> {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a new project: a starter ankiconf.typ, a sample cards.typ and a commented typ2anki.toml. Existing files are kept
+    Init {
+        /// The folder of the project, created if needed
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Check that typ2anki can work here: Anki and AnkiConnect, the note types, the config file, the fonts and the package cache
+    #[command(alias = "check")]
+    Doctor {
+        /// The folder of the project, where the config file is looked for
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+
+        /// The config file to check, like --config-file
+        #[arg(long = "config-file", default_value = DEFAULT_CONFIG_FILENAME)]
+        config_file: String,
+    },
+}
+
+// JSON and YAML config files are read into the same structure as TOML ones, so that
+// every key behaves the same whatever the format
+fn load_config_file(path: &Path) -> Option<TomlValue> {
+    match read_config_file(path) {
+        Ok(value) => value,
+        Err(ConfigFileError::Read(e)) => {
+            panic!("Error reading config file {}: {}", path.display(), e)
+        }
+        Err(ConfigFileError::Parse(format, e)) => {
+            panic!("Error parsing {} {}: {}", format, path.display(), e)
+        }
+        Err(ConfigFileError::EnvVar(e)) => Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("in config file {}: {}", path.display(), e),
+            )
+            .exit(),
+    }
+}
+
+pub enum ConfigFileError {
+    Read(std::io::Error),
+    // The format and the error
+    Parse(&'static str, String),
+    EnvVar(String),
+}
+
+impl std::fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigFileError::Read(e) => write!(f, "can't be read: {}", e),
+            ConfigFileError::Parse(format, e) => write!(f, "isn't valid {}: {}", format, e),
+            ConfigFileError::EnvVar(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// None if the file doesn't exist
+pub fn read_config_file(path: &Path) -> Result<Option<TomlValue>, ConfigFileError> {
     if !path.exists() {
-        return None;
+        return Ok(None);
     }
-    match fs::read_to_string(path) {
-        Ok(s) => match s.parse::<TomlValue>() {
-            Ok(v) => Some(v),
-            Err(e) => panic!("Error parsing TOML {}: {}", path.display(), e),
-        },
-        Err(e) => panic!("Error reading config file {}: {}", path.display(), e),
+    let s = fs::read_to_string(path).map_err(ConfigFileError::Read)?;
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let parsed = match extension.as_str() {
+        "json" => serde_json::from_str::<TomlValue>(&s).map_err(|e| ("JSON", e.to_string())),
+        "yaml" | "yml" => {
+            serde_yaml::from_str::<TomlValue>(&s).map_err(|e| ("YAML", e.to_string()))
+        }
+        _ => s.parse::<TomlValue>().map_err(|e| ("TOML", e.to_string())),
+    };
+    let value = parsed.map_err(|(format, e)| ConfigFileError::Parse(format, e))?;
+    expand_env_vars(value)
+        .map(Some)
+        .map_err(ConfigFileError::EnvVar)
+}
+
+// Replaces `${VAR}` in every string of the config file by the environment variable VAR, so
+// that one config can be shared by machines with different paths. `$${` is a literal `${`.
+fn expand_env_vars(value: TomlValue) -> Result<TomlValue, String> {
+    Ok(match value {
+        TomlValue::String(s) => TomlValue::String(expand_env_vars_in(&s)?),
+        TomlValue::Array(a) => TomlValue::Array(
+            a.into_iter()
+                .map(expand_env_vars)
+                .collect::<Result<_, _>>()?,
+        ),
+        TomlValue::Table(t) => TomlValue::Table(
+            t.into_iter()
+                .map(|(k, v)| Ok((k, expand_env_vars(v)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        v => v,
+    })
+}
+
+fn expand_env_vars_in(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unclosed ${{ in \"{}\"", s))?;
+            let name = &after[..end];
+            let value = std::env::var(name).map_err(|_| {
+                format!(
+                    "environment variable '{}' is not set (used in \"{}\")",
+                    name, s
+                )
+            })?;
+            out.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
     }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Like git and cargo, the config file is searched for in the path and then in its parents,
+// so that running from a subfolder of the notes still uses the project's config.
+// An absolute --config-file is used as is.
+// With the default name, typ2anki.json and typ2anki.yaml are accepted too.
+pub fn find_config_file(start: &Path, config_file: &str) -> Option<PathBuf> {
+    let path = Path::new(config_file);
+    if path.is_absolute() {
+        return path.exists().then(|| path.to_path_buf());
+    }
+    let candidates: Vec<PathBuf> = if config_file == DEFAULT_CONFIG_FILENAME {
+        ["toml", "json", "yaml", "yml"]
+            .iter()
+            .map(|ext| path.with_extension(ext))
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    start
+        .ancestors()
+        .find_map(|dir| candidates.iter().map(|c| dir.join(c)).find(|p| p.is_file()))
+}
+
+// The names and sizes of the files in the font directories. Unlike the directories' paths,
+// it is the same on every machine with the same fonts, and changes when a font is replaced.
+fn fonts_fingerprint(font_paths: &[PathBuf]) -> Vec<(String, u64)> {
+    let mut fonts: Vec<(String, u64)> = font_paths
+        .iter()
+        .flat_map(|dir| {
+            walkdir::WalkDir::new(dir)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(move |e| {
+                    let name = e.path().strip_prefix(dir).unwrap_or(e.path());
+                    let size = e.metadata().map(|m| m.len()).unwrap_or(0);
+                    (name.to_string_lossy().replace('\\', "/"), size)
+                })
+        })
+        .collect();
+    fonts.sort();
+    fonts
+}
+
+// Paths of the config file are relative to it, not to where typ2anki is run from
+pub fn config_relative_path(config_file: Option<&Path>, p: &str) -> PathBuf {
+    config_file
+        .and_then(Path::parent)
+        .map(|d| d.join(p))
+        .unwrap_or_else(|| PathBuf::from(p))
 }
 
 fn get_real_path_simple(p: &str) -> String {
@@ -93,6 +504,69 @@ fn get_real_path_simple(p: &str) -> String {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+// How the cards are found in the files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CardDelimiters {
+    /// A card is a `#card(...)` call, up to its closing parenthesis
+    Heuristic,
+    /// A card is everything between a `// card-begin` line and a `// card-end` line
+    Markers,
+}
+
+impl CardDelimiters {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CardDelimiters::Heuristic => "heuristic",
+            CardDelimiters::Markers => "markers",
+        }
+    }
+}
+
+// Settings of a `[decks."Pattern"]` table, applied to the cards of the decks matching the pattern
+#[derive(Debug, Clone, Default)]
+pub struct DeckOverrides {
+    pub pattern: String,
+    pub max_card_width: Option<String>,
+    pub image_format: Option<ImageFormat>,
+    pub tags: Vec<String>,
+    pub model: Option<String>,
+}
+
+// Settings for the cards of a given deck, after applying the overrides to the global config
+#[derive(Debug, Clone)]
+pub struct DeckConfig {
+    pub max_card_width: String,
+    pub image_format: ImageFormat,
+    pub tags: Vec<String>,
+    // Anki note type used for new notes, instead of the Basic one
+    pub model: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     // User controlled options
@@ -100,19 +574,102 @@ pub struct Config {
     pub exclude_decks: Vec<Pattern>,
     pub exclude_decks_string: Vec<String>,
     pub exclude_files: Vec<Pattern>,
-    pub asked_path: String,
+    // When not empty, only the matching decks/files are processed
+    pub include_decks: Vec<Pattern>,
+    pub include_files: Vec<Pattern>,
+    // The first of the paths, where typ2anki.toml is read from
     pub path: PathBuf,
+    // Every folder cards are searched in (zips are extracted to a temporary folder)
+    pub paths: Vec<PathBuf>,
     pub recompile_on_config_change: Arc<RwLock<Option<bool>>>,
+    // From the `[decks."Deck Name"]` tables; glob patterns first, so exact names take precedence
+    pub deck_overrides: Vec<(Pattern, DeckOverrides)>,
+    // From the `[deck_map]` table: the exact Anki deck of a target-deck, instead of guessing it
+    pub deck_map: HashMap<String, String>,
+    // HTML of the Front/Back fields, see DEFAULT_CARD_TEMPLATE
+    pub template_front: String,
+    pub template_back: String,
 
     // Processed options / defaults
     pub dry_run: bool,
+    pub fail_fast: bool,
+    pub show_warnings: bool,
+    pub strict_pages: bool,
+    pub strict_deck_names: bool,
+    pub card_timeout: Option<Duration>,
+    pub anki_max_inflight: usize,
+    // --proxy, for the package downloads
+    pub proxy: Option<String>,
+    pub offline: bool,
+    pub package_cache: PathBuf,
+    pub font_paths: Vec<PathBuf>,
+    pub ignore_system_fonts: bool,
+    pub legacy_media_names: bool,
+    pub hash_media_names: bool,
+    pub now: Option<OffsetDateTime>,
     pub max_card_width: String,
-    pub skip_cache: bool,
+    pub max_card_height: String,
+    pub render_scale: f32,
+    // A hex color, or "transparent"
+    pub card_fill: String,
+    pub card_margin: String,
+    pub image_format: ImageFormat,
+    pub image_quality: u8,
+    pub card_delimiters: CardDelimiters,
+    // Functions read as cards besides `card` and `custom-card`
+    pub card_functions: Vec<String>,
+    // The note fields of the pages after the front and back, in order
+    pub extra_fields: Vec<String>,
+    // Anki note type of new notes, unless their deck sets one
+    pub model: Option<String>,
+    // Whether notes that Anki considers duplicates are added anyway
+    pub allow_duplicate_notes: bool,
+    // Whether the cards of a file sharing an ID and a deck are merged into one
+    pub merge_by_id: bool,
+    // Whether notes are tagged with the file and deck of their card
+    pub embed_metadata: bool,
+    // Namespaces the cards cache and the ID tags of the notes
+    pub project: Option<String>,
+    // Whether to read the cards cache from Anki to skip unchanged cards, and save it back
+    pub use_cache: bool,
+    pub clear_cache: bool,
+    pub force: bool,
+    pub prune_media: bool,
+    pub update_lock: bool,
+    // The typ2anki.lock of the first path
+    pub lockfile: Arc<Lockfile>,
     pub generation_concurrency: usize,
     pub keep_terminal_open: bool,
+    pub non_interactive: bool,
+    // --yes: no confirmation is asked before uploading
+    pub yes: bool,
+    pub output_format: OutputFormat,
+    // Whether the output has ANSI colors, from --color
+    pub color: bool,
+    pub export_apkg: Option<PathBuf>,
+    pub export_csv: Option<PathBuf>,
+    pub preview_dir: Option<PathBuf>,
+    pub dump_source: Option<PathBuf>,
+    pub dump_all_sources: bool,
+    pub watch: bool,
+    pub list: bool,
+    pub stats_only: bool,
+    pub diff: bool,
+    pub only_changed: bool,
+    pub limit: Option<usize>,
+    // --bench: how many times the synthetic deck is compiled
+    pub bench: Option<usize>,
+    pub filter_ids: Vec<Pattern>,
+    pub filter_decks: Vec<Pattern>,
+    pub verbose: u8,
+    pub log_file: Option<PathBuf>,
 
     // Internal options
-    pub is_zip: bool,
+    // The config file that was loaded, if any
+    pub config_file: Option<PathBuf>,
+    // Directories where .zip inputs were extracted, removed when the last copy of the
+    // configuration is dropped
+    pub extracted_zip_dirs: Vec<Arc<TempDir>>,
     pub config_hash: Option<String>,
     pub output_type: String,
     pub typst_input: Vec<(String, String)>,
@@ -120,77 +677,222 @@ pub struct Config {
 }
 
 impl Config {
+    // Includes are applied first, then the excludes are removed from what they matched
     pub fn is_deck_excluded(&self, deck_name: &str) -> bool {
-        self.exclude_decks.iter().any(|p| p.matches(deck_name))
+        let included = self.include_decks.is_empty()
+            || self.include_decks.iter().any(|p| p.matches(deck_name));
+        !included || self.exclude_decks.iter().any(|p| p.matches(deck_name))
+    }
+
+    // Whether a card is compiled with --filter-id and --filter-deck. Unlike excluded ones, the
+    // other cards are still checked for duplicates.
+    pub fn is_card_selected(&self, deck_name: &str, card_id: &str) -> bool {
+        (self.filter_ids.is_empty() || self.filter_ids.iter().any(|p| p.matches(card_id)))
+            && (self.filter_decks.is_empty()
+                || self.filter_decks.iter().any(|p| p.matches(deck_name)))
+    }
+
+    // Whether only some of the cards found are compiled, see `is_card_selected` and --limit
+    pub fn selects_cards(&self) -> bool {
+        self.limit.is_some() || !self.filter_ids.is_empty() || !self.filter_decks.is_empty()
     }
 
     pub fn is_file_excluded(&self, file_name: &str) -> bool {
-        self.exclude_files.iter().any(|p| p.matches(file_name))
+        let included = self.include_files.is_empty()
+            || self.include_files.iter().any(|p| p.matches(file_name));
+        !included || self.exclude_files.iter().any(|p| p.matches(file_name))
     }
 
-    pub fn template_front(&self, _card_info: &CardInfo, front_image_path: &str) -> String {
-        format!(
-            r#"<img src="{}">"#,
-            encode_double_quoted_attribute(front_image_path)
-        )
+    pub fn deck_config(&self, deck_name: &str) -> DeckConfig {
+        let mut deck_config = DeckConfig {
+            max_card_width: self.max_card_width.clone(),
+            image_format: self.image_format,
+            tags: Vec::new(),
+            model: self.model.clone(),
+        };
+        for (pattern, overrides) in &self.deck_overrides {
+            if !pattern.matches(deck_name) {
+                continue;
+            }
+            if let Some(v) = &overrides.max_card_width {
+                deck_config.max_card_width = v.clone();
+            }
+            if let Some(v) = overrides.image_format {
+                deck_config.image_format = v;
+            }
+            if let Some(v) = &overrides.model {
+                deck_config.model = Some(v.clone());
+            }
+            for tag in &overrides.tags {
+                if !deck_config.tags.contains(tag) {
+                    deck_config.tags.push(tag.clone());
+                }
+            }
+        }
+        deck_config
     }
 
-    pub fn template_back(&self, _card_info: &CardInfo, back_image_path: &str) -> String {
-        format!(
-            r#"<img src="{}">"#,
-            encode_double_quoted_attribute(back_image_path)
-        )
+    // Every function whose calls are cards, the default ones first
+    pub fn card_function_names(&self) -> Vec<&str> {
+        DEFAULT_CARD_FUNCTIONS
+            .into_iter()
+            .chain(self.card_functions.iter().map(String::as_str))
+            .collect()
     }
 
-    pub fn destruct(&self) {
-        // Be careful not to panic in this function, as it is called during unwinding.
-        if self.dry_run {
-            println!("Destroying config (dry run)");
+    // The pages each card must have: the front, the back, then one per extra field
+    pub fn page_count(&self) -> usize {
+        2 + self.extra_fields.len()
+    }
+
+    // Whether cards are written to files instead of being uploaded to Anki
+    pub fn is_exporting(&self) -> bool {
+        self.export_apkg.is_some() || self.export_csv.is_some() || self.preview_dir.is_some()
+    }
+
+    // The card fill as RGBA, transparent being (0, 0, 0, 0)
+    pub fn card_fill_rgba(&self) -> [u8; 4] {
+        let Some(hex) = self.card_fill.strip_prefix('#') else {
+            return [0, 0, 0, 0];
+        };
+        // #rgb and #rgba are short for #rrggbb and #rrggbbaa
+        let hex: String = if hex.len() <= 4 {
+            hex.chars().flat_map(|c| [c, c]).collect()
+        } else {
+            hex.to_string()
+        };
+        let mut rgba = [0, 0, 0, 255];
+        for (i, channel) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap_or(0);
         }
-        if self.is_zip
-            && self.asked_path != self.path.to_string_lossy()
-            && let Err(e) = fs::remove_dir_all(&self.path)
-        {
-            eprintln!(
-                "Warning: Failed to remove temporary extracted zip directory {}: {}",
-                self.path.display(),
-                e
-            );
+        rgba
+    }
+
+    fn fill_template(template: &str, card_info: &CardInfo, image_path: &str) -> String {
+        template
+            .replace("{card_id}", &encode_text(&card_info.card_id))
+            .replace("{deck}", &encode_text(&card_info.deck_name))
+            .replace("{image}", &encode_double_quoted_attribute(image_path))
+    }
+
+    pub fn template_front(&self, card_info: &CardInfo, front_image_path: &str) -> String {
+        Self::fill_template(&self.template_front, card_info, front_image_path)
+    }
+
+    pub fn template_back(&self, card_info: &CardInfo, back_image_path: &str) -> String {
+        Self::fill_template(&self.template_back, card_info, back_image_path)
+    }
+
+    // The field of a page (1-based), the pages after the back use the back's template
+    pub fn template_page(&self, card_info: &CardInfo, page: usize, image_path: &str) -> String {
+        if page == 1 {
+            self.template_front(card_info, image_path)
+        } else {
+            self.template_back(card_info, image_path)
         }
     }
 
     pub fn compute_hash(&mut self) {
-        let relevant_config = json!({
+        let mut relevant_config = json!({
             "output_type": self.output_type,
             "max_card_width": self.max_card_width,
             "exclude_decks": self.exclude_decks_string.clone().sort(),
         });
+        // Options are only added when they differ from their default, so that adding
+        // new options doesn't invalidate existing caches
+        if !self.deck_overrides.is_empty() {
+            relevant_config["decks"] = self
+                .deck_overrides
+                .iter()
+                .map(|(_, o)| {
+                    json!({
+                        "pattern": o.pattern,
+                        "max_card_width": o.max_card_width,
+                        "image_format": o.image_format.map(|f| f.as_str()),
+                        "tags": o.tags,
+                        "model": o.model,
+                    })
+                })
+                .collect();
+        }
+        if self.template_front != DEFAULT_CARD_TEMPLATE
+            || self.template_back != DEFAULT_CARD_TEMPLATE
+        {
+            relevant_config["template_front"] = json!(self.template_front);
+            relevant_config["template_back"] = json!(self.template_back);
+        }
+        if self.render_scale != DEFAULT_RENDER_SCALE {
+            relevant_config["render_scale"] = json!(self.render_scale);
+        }
+        if self.max_card_height != "auto" {
+            relevant_config["max_card_height"] = json!(self.max_card_height);
+        }
+        if self.card_fill != DEFAULT_CARD_FILL {
+            relevant_config["card_fill"] = json!(self.card_fill);
+        }
+        if self.card_margin != DEFAULT_CARD_MARGIN {
+            relevant_config["card_margin"] = json!(self.card_margin);
+        }
+        if !self.font_paths.is_empty() {
+            relevant_config["fonts"] = json!(fonts_fingerprint(&self.font_paths));
+        }
+        if self.ignore_system_fonts {
+            relevant_config["ignore_system_fonts"] = json!(true);
+        }
+        if let Some(now) = self.now {
+            relevant_config["now"] = json!(now.format(&Rfc3339).ok());
+        }
+        if let Some(model) = &self.model {
+            relevant_config["model"] = json!(model);
+        }
+        // The tags are only set when the cards are uploaded
+        if self.embed_metadata {
+            relevant_config["embed_metadata"] = json!(true);
+        }
+        if !self.extra_fields.is_empty() {
+            relevant_config["extra_fields"] = json!(self.extra_fields);
+        }
+        let inputs: Vec<&(String, String)> = self
+            .typst_input
+            .iter()
+            .filter(|(key, _)| !RESERVED_INPUTS.contains(&key.as_str()))
+            .collect();
+        if !inputs.is_empty() {
+            relevant_config["inputs"] = json!(inputs);
+        }
+        if self.image_format != ImageFormat::Png {
+            relevant_config["image_format"] = json!(self.image_format.as_str());
+            relevant_config["image_quality"] = json!(self.image_quality);
+        }
+        // The notes point to their images by name, so renaming them means uploading them again
+        if self.legacy_media_names {
+            relevant_config["legacy_media_names"] = json!(true);
+        }
+        if self.hash_media_names {
+            relevant_config["hash_media_names"] = json!(true);
+        }
         let relevant_config = utils::json_sorted_keys(&relevant_config);
         let s = serde_json::to_string(&relevant_config).unwrap();
         self.config_hash = Some(utils::hash_string(&s));
     }
 
+    // The root a file was found in. With nested roots, the innermost one.
+    pub fn root_of(&self, p: &Path) -> &PathBuf {
+        self.paths
+            .iter()
+            .filter(|root| p.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .unwrap_or(&self.path)
+    }
+
     pub fn path_relative_to_root(&self, p: &PathBuf) -> String {
-        pathdiff::diff_paths(p, &self.path)
+        pathdiff::diff_paths(p, self.root_of(p))
             .unwrap_or(p.clone())
             .to_string_lossy()
             .into_owned()
     }
 }
 
-// RAII guard to ensure Config::destruct() is called when run() exits or unwinds.
-// We call destruct() inside catch_unwind to avoid panics during unwinding.
-pub struct ConfigGuard;
-
-impl Drop for ConfigGuard {
-    fn drop(&mut self) {
-        let _ = std::panic::catch_unwind(|| {
-            let cfg = get();
-            cfg.destruct();
-        });
-    }
-}
-
 fn parse_generation_concurrency(s: &str) -> usize {
     if s.is_empty() {
         1
@@ -201,29 +903,234 @@ fn parse_generation_concurrency(s: &str) -> usize {
     }
 }
 
+fn parse_render_scale(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        Ok(_) => Err(format!("render scale must be a positive number, got {}", s)),
+        Err(e) => Err(format!("invalid render scale '{}': {}", s, e)),
+    }
+}
+
+fn parse_card_fill(s: &str) -> Result<String, String> {
+    let s = s.trim().to_ascii_lowercase();
+    if s == "transparent" {
+        return Ok(s);
+    }
+    match s.strip_prefix('#') {
+        Some(hex)
+            if [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(s)
+        }
+        _ => Err(format!(
+            "invalid card fill '{}': expected a hex color like '#1e1e1e' or 'transparent'",
+            s
+        )),
+    }
+}
+
+fn parse_now(s: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(s.trim(), &Rfc3339).map_err(|e| {
+        format!(
+            "invalid time '{}': expected an RFC 3339 time like '2024-09-01T08:00:00Z' ({})",
+            s, e
+        )
+    })
+}
+
+// A Typst identifier, so that it can be called as `#name(...)`
+fn parse_card_function(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    let valid = s.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid card function '{}': expected a Typst function name like 'flashcard'",
+            s
+        ))
+    }
+}
+
+// The inputs typ2anki sets itself
+const RESERVED_INPUTS: [&str; 3] = ["typ2anki_compile", "max_card_width", "max_card_height"];
+
+fn parse_typst_input(s: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("invalid input '{}': expected key=value", s));
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("invalid input '{}': the key is empty", s));
+    }
+    if RESERVED_INPUTS.contains(&key) {
+        return Err(format!(
+            "invalid input '{}': '{}' is set by typ2anki, use its option instead",
+            s, key
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_extra_field(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("invalid extra field: the field name is empty".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_filter_glob(s: &str) -> Result<String, String> {
+    Pattern::new(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("invalid glob pattern '{}': {}", s, e))
+}
+
+// The name ends up in a tag and in a file name of Anki's media folder
+fn parse_project(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid project name '{}': only letters, digits, '-' and '_' are allowed",
+            s
+        ))
+    }
+}
+
+fn parse_proxy(s: &str) -> Result<String, String> {
+    match reqwest::Proxy::all(s) {
+        Ok(_) => Ok(s.to_string()),
+        Err(e) => Err(format!("invalid proxy URL '{}': {}", s, e)),
+    }
+}
+
+fn parse_card_margin(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    let valid = ["pt", "mm", "cm", "in", "em"].iter().any(|unit| {
+        s.strip_suffix(unit)
+            .and_then(|n| n.parse::<f64>().ok())
+            .is_some_and(|n| n.is_finite() && n >= 0.0)
+    });
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid card margin '{}': expected a length like '3pt' or '1mm'",
+            s
+        ))
+    }
+}
+
 pub fn parse_config() -> Config {
-    let matches = Cli::command().get_matches();
+    parse_config_from(Cli::command().get_matches())
+}
+
+// The configuration of typ2anki run with these arguments, for the tests
+#[cfg(test)]
+pub fn config_from_args(args: &[&str]) -> Config {
+    let args = std::iter::once("typ2anki").chain(args.iter().copied());
+    parse_config_from(Cli::command().get_matches_from(args))
+}
+
+fn parse_config_from(matches: clap::ArgMatches) -> Config {
     let cli = Cli::from_arg_matches(&matches).unwrap();
 
-    let asked_path = match cli.path {
-        Some(p) => {
-            if p.is_empty() {
-                ".".to_string()
+    // Set up first, so that the messages about the configuration itself are logged
+    let log_file = cli.log_file.as_ref().map(PathBuf::from);
+    if let Err(e) = logging::init(cli.verbose, log_file.as_deref()) {
+        panic!(
+            "Error opening log file {}: {}",
+            log_file.unwrap_or_default().display(),
+            e
+        );
+    }
+
+    if let Some(Command::Init { dir }) = &cli.command {
+        if let Err(e) = init::run(dir) {
+            Cli::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("Failed to create the project in {}: {}", dir.display(), e),
+                )
+                .exit();
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(Command::Doctor { dir, config_file }) = &cli.command {
+        let healthy = doctor::run(dir, config_file);
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+
+    let asked_paths: Vec<String> = match cli.path {
+        Some(p) if !p.is_empty() => {
+            // Unquoted paths with spaces arrive split into several arguments
+            let joined = p.join(" ");
+            if p.len() > 1
+                && !p.iter().all(|x| Path::new(x).exists())
+                && Path::new(&joined).exists()
+            {
+                vec![joined]
             } else {
-                p.join(" ")
+                p
             }
         }
-        None => ".".to_string(),
+        _ => vec![".".to_string()],
     };
 
     let mut check_duplicates = cli.check_duplicates;
     let mut exclude_decks = cli.exclude_decks.clone();
     let mut exclude_files = cli.exclude_files.clone();
+    let mut include_decks = cli.include_decks.clone();
+    let mut include_files = cli.include_files.clone();
     let mut dry_run = cli.dry_run;
+    let mut fail_fast = cli.fail_fast;
+    let mut show_warnings = cli.show_warnings;
+    let mut strict_pages = cli.strict_pages;
+    let mut strict_deck_names = cli.strict_deck_names;
+    let mut card_timeout = cli.card_timeout;
+    let mut anki_max_inflight = cli.anki_max_inflight;
+    let mut proxy = cli.proxy.clone();
+    let mut offline = cli.offline;
+    let mut package_cache = cli.package_cache.as_ref().map(PathBuf::from);
+    let mut font_paths: Vec<PathBuf> = cli.font_paths.iter().map(PathBuf::from).collect();
+    let mut ignore_system_fonts = cli.ignore_system_fonts;
+    let mut legacy_media_names = cli.legacy_media_names;
+    let mut hash_media_names = cli.hash_media_names;
+    let mut now = cli.now;
     let mut max_card_width = cli.max_card_width.clone();
-    let mut skip_cache = cli.no_cache;
+    let mut max_card_height = cli.max_card_height.clone();
+    let mut render_scale = cli.render_scale;
+    let mut card_fill = cli.card_fill.clone();
+    let mut card_margin = cli.card_margin.clone();
+    let mut image_format = cli.image_format;
+    let mut card_delimiters = cli.card_delimiters;
+    let mut card_functions = cli.card_functions.clone();
+    let mut extra_fields = cli.extra_fields.clone();
+    let mut model = cli.model.clone();
+    let mut allow_duplicate_notes = cli.allow_duplicate_notes;
+    let mut merge_by_id = cli.merge_by_id;
+    let mut embed_metadata = cli.embed_metadata;
+    let mut project = cli.project.clone();
+    let mut inputs = cli.inputs.clone();
+    let mut image_quality = cli.image_quality;
+    let mut use_cache = !cli.no_cache;
     let mut generation_concurrency = parse_generation_concurrency(&cli.generation_concurrency);
     let mut recompile_on_config_change = cli.recompile_on_config_change.clone();
+    let mut non_interactive = cli.non_interactive;
+    let mut yes = cli.yes;
+    let mut output_format = cli.output_format;
+    let mut color = cli.color;
+    let mut deck_overrides: Vec<DeckOverrides> = Vec::new();
+    let mut deck_map: HashMap<String, String> = HashMap::new();
+    let mut template_front = DEFAULT_CARD_TEMPLATE.to_string();
+    let mut template_back = DEFAULT_CARD_TEMPLATE.to_string();
 
     #[derive(Debug)]
     enum ConfigSource {
@@ -248,113 +1155,647 @@ pub fn parse_config() -> Config {
                 source_map.insert(name, ConfigSource::Default);
             }
             _ => {
-                eprintln!("Unknown value source for arg {}", name);
+                log::debug!("Unknown value source for arg {}", name);
             }
         }
     });
 
-    let mut path = get_real_path_simple(&asked_path);
-    let is_zip = path.to_lowercase().ends_with(".zip");
-
-    if is_zip {
-        let dir = utils::get_typ2anki_tmp();
-        let dir = tempdir_in(dir)
-            .expect("Failed to create temporary directory for zip extraction")
-            .path()
-            .to_path_buf();
-        utils::unzip_file_to_dir(Path::new(&path), &dir).expect("Failed to extract zip file");
-        path = dir.to_string_lossy().to_string();
-    }
-
-    if !cli.config_file.is_empty() {
-        let config_file_path = Path::new(&path).join(&cli.config_file);
-        if let Some(table) = load_toml_config(&config_file_path) {
-            if let Some(&ConfigSource::Default) = source_map.get("check_duplicates")
-                && let Some(v) = table.get("check_duplicates")
-                && let Some(b) = v.as_bool()
-            {
-                check_duplicates = b;
-                source_map.insert("check_duplicates", ConfigSource::File);
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut extracted_zip_dirs: Vec<Arc<TempDir>> = Vec::new();
+    for asked in &asked_paths {
+        let path = get_real_path_simple(asked);
+        if path.to_lowercase().ends_with(".zip") {
+            let dir = tempdir_in(utils::get_typ2anki_tmp())
+                .expect("Failed to create temporary directory for zip extraction");
+            if let Err(e) = utils::unzip_file_to_dir(Path::new(&path), dir.path()) {
+                // Exiting skips destructors
+                drop(dir);
+                drop(extracted_zip_dirs);
+                Cli::command()
+                    .error(ErrorKind::Io, format!("Failed to extract {}: {}", path, e))
+                    .exit();
             }
+            paths.push(dir.path().to_path_buf());
+            extracted_zip_dirs.push(Arc::new(dir));
+        } else {
+            paths.push(PathBuf::from(path));
+        }
+    }
+    // The config file is read from the first path
+    let path = paths[0].to_string_lossy().to_string();
+    let lockfile = Arc::new(Lockfile::load(&paths[0], cli.update_lock));
 
-            if let Some(&ConfigSource::Default) = source_map.get("exclude_decks")
-                && let Some(v) = table.get("exclude_decks").and_then(|x| x.as_array())
-            {
-                exclude_decks = v
-                    .iter()
-                    .filter_map(|e| e.as_str().map(|s| s.to_string()))
-                    .collect();
-                source_map.insert("exclude_decks", ConfigSource::File);
+    let config_file = if cli.config_file.is_empty() {
+        None
+    } else {
+        find_config_file(Path::new(&path), &cli.config_file)
+    };
+    if let Some(table) = config_file.as_deref().and_then(load_config_file) {
+        if let Some(&ConfigSource::Default) = source_map.get("check_duplicates")
+            && let Some(v) = table.get("check_duplicates")
+            && let Some(b) = v.as_bool()
+        {
+            check_duplicates = b;
+            source_map.insert("check_duplicates", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("exclude_decks")
+            && let Some(v) = table.get("exclude_decks").and_then(|x| x.as_array())
+        {
+            exclude_decks = v
+                .iter()
+                .filter_map(|e| e.as_str().map(|s| s.to_string()))
+                .collect();
+            source_map.insert("exclude_decks", ConfigSource::File);
+        }
+        if let Some(&ConfigSource::Default) = source_map.get("exclude_files")
+            && let Some(v) = table.get("exclude_files").and_then(|x| x.as_array())
+        {
+            exclude_files = v
+                .iter()
+                .filter_map(|e| e.as_str().map(|s| s.to_string()))
+                .collect();
+            source_map.insert("exclude_files", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("include_decks")
+            && let Some(v) = table.get("include_decks").and_then(|x| x.as_array())
+        {
+            include_decks = v
+                .iter()
+                .filter_map(|e| e.as_str().map(|s| s.to_string()))
+                .collect();
+            source_map.insert("include_decks", ConfigSource::File);
+        }
+        if let Some(&ConfigSource::Default) = source_map.get("include_files")
+            && let Some(v) = table.get("include_files").and_then(|x| x.as_array())
+        {
+            include_files = v
+                .iter()
+                .filter_map(|e| e.as_str().map(|s| s.to_string()))
+                .collect();
+            source_map.insert("include_files", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("dry_run")
+            && let Some(v) = table.get("dry_run").and_then(|x| x.as_bool())
+        {
+            dry_run = v;
+            source_map.insert("dry_run", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("fail_fast")
+            && let Some(v) = table.get("fail_fast").and_then(|x| x.as_bool())
+        {
+            fail_fast = v;
+            source_map.insert("fail_fast", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("show_warnings")
+            && let Some(v) = table.get("show_warnings").and_then(|x| x.as_bool())
+        {
+            show_warnings = v;
+            source_map.insert("show_warnings", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("strict_pages")
+            && let Some(v) = table.get("strict_pages").and_then(|x| x.as_bool())
+        {
+            strict_pages = v;
+            source_map.insert("strict_pages", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("strict_deck_names")
+            && let Some(v) = table.get("strict_deck_names").and_then(|x| x.as_bool())
+        {
+            strict_deck_names = v;
+            source_map.insert("strict_deck_names", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("card_timeout")
+            && let Some(v) = table.get("card_timeout").and_then(|x| x.as_integer())
+        {
+            if v < 1 {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!(
+                            "card_timeout must be at least 1 second, got {} (in config file)",
+                            v
+                        ),
+                    )
+                    .exit()
             }
-            if let Some(&ConfigSource::Default) = source_map.get("exclude_files")
-                && let Some(v) = table.get("exclude_files").and_then(|x| x.as_array())
-            {
-                exclude_files = v
-                    .iter()
-                    .filter_map(|e| e.as_str().map(|s| s.to_string()))
-                    .collect();
-                source_map.insert("exclude_files", ConfigSource::File);
+            card_timeout = Some(v as u64);
+            source_map.insert("card_timeout", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("anki_max_inflight")
+            && let Some(v) = table.get("anki_max_inflight").and_then(|x| x.as_integer())
+        {
+            if v < 1 {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!(
+                            "anki_max_inflight must be at least 1, got {} (in config file)",
+                            v
+                        ),
+                    )
+                    .exit()
             }
+            anki_max_inflight = v as u64;
+            source_map.insert("anki_max_inflight", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("proxy")
+            && let Some(v) = table.get("proxy").and_then(|x| x.as_str())
+        {
+            proxy = Some(parse_proxy(v).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("{} (in config file)", e),
+                    )
+                    .exit()
+            }));
+            source_map.insert("proxy", ConfigSource::File);
+        }
 
-            if let Some(&ConfigSource::Default) = source_map.get("dry_run")
-                && let Some(v) = table.get("dry_run").and_then(|x| x.as_bool())
+        if let Some(&ConfigSource::Default) = source_map.get("offline")
+            && let Some(v) = table.get("offline").and_then(|x| x.as_bool())
+        {
+            offline = v;
+            source_map.insert("offline", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("package_cache")
+            && let Some(v) = table.get("package_cache").and_then(|x| x.as_str())
+        {
+            package_cache = Some(config_relative_path(config_file.as_deref(), v));
+            source_map.insert("package_cache", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("font_paths")
+            && let Some(v) = table.get("font_paths").and_then(|x| x.as_array())
+        {
+            font_paths = v
+                .iter()
+                .filter_map(|e| e.as_str())
+                .map(|p| config_relative_path(config_file.as_deref(), p))
+                .collect();
+            source_map.insert("font_paths", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("legacy_media_names")
+            && let Some(v) = table.get("legacy_media_names").and_then(|x| x.as_bool())
+        {
+            legacy_media_names = v;
+            source_map.insert("legacy_media_names", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("hash_media_names")
+            && let Some(v) = table.get("hash_media_names").and_then(|x| x.as_bool())
+        {
+            hash_media_names = v;
+            source_map.insert("hash_media_names", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("ignore_system_fonts")
+            && let Some(v) = table.get("ignore_system_fonts").and_then(|x| x.as_bool())
+        {
+            ignore_system_fonts = v;
+            source_map.insert("ignore_system_fonts", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("now")
+            && let Some(v) = table.get("now")
+        {
+            // A TOML datetime isn't a string, but is written the same way
+            let v = match v.as_datetime() {
+                Some(d) => d.to_string(),
+                None => v.as_str().unwrap_or_default().to_string(),
+            };
+            now = Some(parse_now(&v).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("{} (in config file)", e),
+                    )
+                    .exit()
+            }));
+            source_map.insert("now", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("max_card_width")
+            && let Some(v) = table.get("max_card_width").and_then(|x| x.as_str())
+        {
+            max_card_width = v.to_string();
+            source_map.insert("max_card_width", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("max_card_height")
+            && let Some(v) = table.get("max_card_height").and_then(|x| x.as_str())
+        {
+            max_card_height = v.to_string();
+            source_map.insert("max_card_height", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("render_scale")
+            && let Some(v) = table.get("render_scale").and_then(|x| {
+                x.as_float()
+                    .or(x.as_integer().map(|i| i as f64))
+                    .map(|f| f.to_string())
+            })
+        {
+            render_scale = parse_render_scale(&v).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("{} (in config file)", e),
+                    )
+                    .exit()
+            });
+            source_map.insert("render_scale", ConfigSource::File);
+        }
+
+        for (key, value, parse) in [
+            (
+                "card_fill",
+                &mut card_fill,
+                parse_card_fill as fn(&str) -> Result<String, String>,
+            ),
+            ("card_margin", &mut card_margin, parse_card_margin),
+        ] {
+            if let Some(&ConfigSource::Default) = source_map.get(key)
+                && let Some(v) = table.get(key).and_then(|x| x.as_str())
             {
-                dry_run = v;
-                source_map.insert("dry_run", ConfigSource::File);
+                *value = parse(v).unwrap_or_else(|e| {
+                    Cli::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            format!("{} (in config file)", e),
+                        )
+                        .exit()
+                });
+                source_map.insert(key, ConfigSource::File);
             }
+        }
 
-            if let Some(&ConfigSource::Default) = source_map.get("max_card_width")
-                && let Some(v) = table.get("max_card_width").and_then(|x| x.as_str())
-            {
-                max_card_width = v.to_string();
-                source_map.insert("max_card_width", ConfigSource::File);
+        if let Some(&ConfigSource::Default) = source_map.get("image_format")
+            && let Some(v) = table.get("image_format").and_then(|x| x.as_str())
+        {
+            image_format = ImageFormat::from_str(v, true).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!("invalid image_format '{}' in config file: {}", v, e),
+                    )
+                    .exit()
+            });
+            source_map.insert("image_format", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("card_functions")
+            && let Some(v) = table.get("card_functions").and_then(|x| x.as_array())
+        {
+            card_functions = v
+                .iter()
+                .filter_map(|e| e.as_str())
+                .map(|name| {
+                    parse_card_function(name).unwrap_or_else(|e| {
+                        Cli::command()
+                            .error(
+                                ErrorKind::ValueValidation,
+                                format!("{} (in config file)", e),
+                            )
+                            .exit()
+                    })
+                })
+                .collect();
+            source_map.insert("card_functions", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("model")
+            && let Some(v) = table.get("model").and_then(|x| x.as_str())
+        {
+            model = Some(v.to_string());
+            source_map.insert("model", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("project")
+            && let Some(v) = table.get("project").and_then(|x| x.as_str())
+        {
+            project = Some(parse_project(v).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("{} (in config file)", e),
+                    )
+                    .exit()
+            }));
+            source_map.insert("project", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("embed_metadata")
+            && let Some(v) = table.get("embed_metadata").and_then(|x| x.as_bool())
+        {
+            embed_metadata = v;
+            source_map.insert("embed_metadata", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("merge_by_id")
+            && let Some(v) = table.get("merge_by_id").and_then(|x| x.as_bool())
+        {
+            merge_by_id = v;
+            source_map.insert("merge_by_id", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("allow_duplicate_notes")
+            && let Some(v) = table.get("allow_duplicate_notes").and_then(|x| x.as_bool())
+        {
+            allow_duplicate_notes = v;
+            source_map.insert("allow_duplicate_notes", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("extra_fields")
+            && let Some(v) = table.get("extra_fields").and_then(|x| x.as_array())
+        {
+            extra_fields = v
+                .iter()
+                .filter_map(|e| e.as_str())
+                .map(|name| {
+                    parse_extra_field(name).unwrap_or_else(|e| {
+                        Cli::command()
+                            .error(
+                                ErrorKind::ValueValidation,
+                                format!("{} (in config file)", e),
+                            )
+                            .exit()
+                    })
+                })
+                .collect();
+            source_map.insert("extra_fields", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("inputs")
+            && let Some(v) = table.get("inputs").and_then(|x| x.as_table())
+        {
+            inputs = v
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        TomlValue::String(s) => s.clone(),
+                        v => v.to_string(),
+                    };
+                    parse_typst_input(&format!("{}={}", key, value)).unwrap_or_else(|e| {
+                        Cli::command()
+                            .error(
+                                ErrorKind::ValueValidation,
+                                format!("{} (in config file)", e),
+                            )
+                            .exit()
+                    })
+                })
+                .collect();
+            source_map.insert("inputs", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("card_delimiters")
+            && let Some(v) = table.get("card_delimiters").and_then(|x| x.as_str())
+        {
+            card_delimiters = CardDelimiters::from_str(v, true).unwrap_or_else(|e| {
+                Cli::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!("invalid card_delimiters '{}' in config file: {}", v, e),
+                    )
+                    .exit()
+            });
+            source_map.insert("card_delimiters", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("image_quality")
+            && let Some(v) = table.get("image_quality").and_then(|x| x.as_integer())
+        {
+            if !(1..=100).contains(&v) {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!(
+                            "image_quality must be between 1 and 100, got {} (in config file)",
+                            v
+                        ),
+                    )
+                    .exit();
             }
+            image_quality = v as u8;
+            source_map.insert("image_quality", ConfigSource::File);
+        }
 
-            if let Some(&ConfigSource::Default) = source_map.get("no_cache")
-                && let Some(v) = table.get("check_checksums").and_then(|x| x.as_bool())
-            {
-                skip_cache = v;
+        if let Some(&ConfigSource::Default) = source_map.get("no_cache") {
+            if let Some(v) = table.get("no_cache").and_then(|x| x.as_bool()) {
+                use_cache = !v;
+                source_map.insert("no_cache", ConfigSource::File);
+            } else if let Some(v) = table.get("check_checksums").and_then(|x| x.as_bool()) {
+                // Older name of the option, with the opposite meaning
+                use_cache = v;
                 source_map.insert("no_cache", ConfigSource::File);
             }
-            if let Some(&ConfigSource::Default) = source_map.get("generation_concurrency")
-                && let Some(v) = table.get("generation_concurrency").map(|x| {
-                    parse_generation_concurrency(
-                        x.as_str()
-                            .unwrap_or(x.as_integer().unwrap_or(1).to_string().as_str()),
-                    )
-                })
-            {
-                generation_concurrency = v;
-                source_map.insert("generation_concurrency", ConfigSource::File);
+        }
+        if let Some(&ConfigSource::Default) = source_map.get("generation_concurrency")
+            && let Some(v) = table.get("generation_concurrency").map(|x| {
+                parse_generation_concurrency(
+                    x.as_str()
+                        .unwrap_or(x.as_integer().unwrap_or(1).to_string().as_str()),
+                )
+            })
+        {
+            generation_concurrency = v;
+            source_map.insert("generation_concurrency", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("recompile_on_config_change")
+            && let Some(v) = table
+                .get("recompile_on_config_change")
+                .and_then(|x| x.as_str())
+        {
+            recompile_on_config_change = v.to_string();
+            source_map.insert("recompile_on_config_change", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("non_interactive")
+            && let Some(v) = table.get("non_interactive").and_then(|x| x.as_bool())
+        {
+            non_interactive = v;
+            source_map.insert("non_interactive", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("yes")
+            && let Some(v) = table.get("yes").and_then(|x| x.as_bool())
+        {
+            yes = v;
+            source_map.insert("yes", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("output_format")
+            && let Some(v) = table
+                .get("output_format")
+                .and_then(|x| x.as_str())
+                .and_then(|x| OutputFormat::from_str(x, true).ok())
+        {
+            output_format = v;
+            source_map.insert("output_format", ConfigSource::File);
+        }
+
+        if let Some(&ConfigSource::Default) = source_map.get("color")
+            && let Some(v) = table
+                .get("color")
+                .and_then(|x| x.as_str())
+                .and_then(|x| ColorChoice::from_str(x, true).ok())
+        {
+            color = v;
+            source_map.insert("color", ConfigSource::File);
+        }
+
+        for (key, template) in [
+            ("template_front", &mut template_front),
+            ("template_back", &mut template_back),
+        ] {
+            if let Some(v) = table.get(key).and_then(|x| x.as_str()) {
+                if !v.contains("{image}") {
+                    Cli::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            format!(
+                                "{} must contain the {{image}} placeholder (in config file)",
+                                key
+                            ),
+                        )
+                        .exit()
+                }
+                *template = v.to_string();
             }
+        }
 
-            if let Some(&ConfigSource::Default) = source_map.get("recompile_on_config_change")
-                && let Some(v) = table
-                    .get("recompile_on_config_change")
-                    .and_then(|x| x.as_str())
-            {
-                recompile_on_config_change = v.to_string();
-                source_map.insert("recompile_on_config_change", ConfigSource::File);
+        if let Some(decks) = table.get("decks").and_then(|x| x.as_table()) {
+            for (pattern, deck_table) in decks {
+                deck_overrides.push(DeckOverrides {
+                        pattern: pattern.clone(),
+                        max_card_width: deck_table
+                            .get("max_card_width")
+                            .and_then(|x| x.as_str())
+                            .map(|x| x.to_string()),
+                        image_format: deck_table
+                            .get("image_format")
+                            .and_then(|x| x.as_str())
+                            .map(|v| {
+                                ImageFormat::from_str(v, true).unwrap_or_else(|e| {
+                                    Cli::command()
+                                        .error(
+                                            ErrorKind::InvalidValue,
+                                            format!(
+                                                "invalid image_format '{}' for deck '{}' in config file: {}",
+                                                v, pattern, e
+                                            ),
+                                        )
+                                        .exit()
+                                })
+                            }),
+                        tags: deck_table
+                            .get("tags")
+                            .and_then(|x| x.as_array())
+                            .map(|v| {
+                                v.iter()
+                                    .filter_map(|e| e.as_str().map(utils::sanitize_tag))
+                                    .filter(|s| !s.is_empty())
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        model: deck_table
+                            .get("model")
+                            .and_then(|x| x.as_str())
+                            .map(|x| x.to_string()),
+                    });
+            }
+        }
+
+        if let Some(map) = table.get("deck_map").and_then(|x| x.as_table()) {
+            for (deck_name, anki_deck_name) in map {
+                match anki_deck_name.as_str() {
+                    Some(v) if !v.trim().is_empty() => {
+                        deck_map.insert(deck_name.clone(), v.to_string());
+                    }
+                    _ => Cli::command()
+                        .error(
+                            ErrorKind::InvalidValue,
+                            format!(
+                                "invalid deck_map entry for '{}' in config file: expected the name of an Anki deck",
+                                deck_name
+                            ),
+                        )
+                        .exit(),
+                }
             }
         }
     }
     // println!("Config sources: {:#?}", source_map);
 
+    // Everything printed with `colored` follows --color, the log messages included
+    // The JSON output is read by programs, which don't expect colors in the messages
+    let color_enabled = match (color, output_format) {
+        (ColorChoice::Auto, OutputFormat::Json) => false,
+        _ => color.enabled(),
+    };
+    colored::control::set_override(color_enabled);
+
     let mut typst_input: Vec<(String, String)> = Vec::new();
     typst_input.push(("typ2anki_compile".to_string(), "1".to_string()));
 
     if max_card_width != "auto" {
         typst_input.push(("max_card_width".to_string(), max_card_width.clone()));
     }
+    if max_card_height != "auto" {
+        typst_input.push(("max_card_height".to_string(), max_card_height.clone()));
+    }
+    for (key, value) in &inputs {
+        if typst_input.iter().any(|(k, _)| k == key) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("the input '{}' is given more than once", key),
+                )
+                .exit();
+        }
+        typst_input.push((key.clone(), value.clone()));
+    }
 
-    if !check_duplicates && generation_concurrency > 1 {
-        eprintln!(
-            "WARNING: Concurrent generation can't be enabled without duplicate checking. Disabling concurrent generation."
-        );
-        generation_concurrency = 1;
-    } else if generation_concurrency > num_cpus::get() {
-        eprintln!(
-            "WARNING: Requested generation concurrency ({}) exceeds number of CPU cores ({}). It is inefficient. Reducing to {}. You can set generation-concurrency to 'max' so that it always takes the amount of logical threads on a given machine.",
+    if let Some((i, name)) = extra_fields
+        .iter()
+        .enumerate()
+        .find(|(i, name)| extra_fields[..*i].contains(name))
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "the extra field '{}' is given more than once (for page {})",
+                    name,
+                    i + 3
+                ),
+            )
+            .exit();
+    }
+
+    // An exported file must contain every card, not only the ones that changed since the
+    // last upload to Anki, and so must a preview
+    if cli.export_apkg.is_some() || cli.export_csv.is_some() || cli.preview_dir.is_some() {
+        use_cache = false;
+    }
+
+    if generation_concurrency > num_cpus::get() {
+        log::warn!(
+            "Requested generation concurrency ({}) exceeds number of CPU cores ({}). It is inefficient. Reducing to {}. You can set generation-concurrency to 'max' so that it always takes the amount of logical threads on a given machine.",
             generation_concurrency,
             num_cpus::get(),
             num_cpus::get()
@@ -371,6 +1812,26 @@ pub fn parse_config() -> Config {
             "print_config",
             "version",
             "keep_terminal_open",
+            "export_apkg",
+            "export_csv",
+            "preview_dir",
+            "dump_source",
+            "dump_all_sources",
+            "watch",
+            "list",
+            "stats_only",
+            "diff",
+            "limit",
+            "bench",
+            "filter_ids",
+            "filter_decks",
+            "only_changed",
+            "clear_cache",
+            "force",
+            "prune_media",
+            "update_lock",
+            "verbose",
+            "log_file",
         ]
         .iter()
         .map(|s| s.to_string())
@@ -397,11 +1858,46 @@ pub fn parse_config() -> Config {
                 "check_duplicates" => json!(check_duplicates),
                 "exclude_decks" => json!(exclude_decks),
                 "exclude_files" => json!(exclude_files),
+                "include_decks" => json!(include_decks),
+                "include_files" => json!(include_files),
                 "dry_run" => json!(dry_run),
+                "fail_fast" => json!(fail_fast),
+                "show_warnings" => json!(show_warnings),
+                "strict_pages" => json!(strict_pages),
+                "strict_deck_names" => json!(strict_deck_names),
+                "card_timeout" => json!(card_timeout),
+                "anki_max_inflight" => json!(anki_max_inflight),
+                "proxy" => json!(proxy),
+                "offline" => json!(offline),
+                "package_cache" => json!(package_cache),
+                "font_paths" => json!(font_paths),
+                "ignore_system_fonts" => json!(ignore_system_fonts),
+                "legacy_media_names" => json!(legacy_media_names),
+                "hash_media_names" => json!(hash_media_names),
+                "now" => json!(now.and_then(|n| n.format(&Rfc3339).ok())),
                 "max_card_width" => json!(max_card_width),
-                "no_cache" => json!(skip_cache),
+                "max_card_height" => json!(max_card_height),
+                "render_scale" => json!(render_scale),
+                "card_fill" => json!(card_fill),
+                "card_margin" => json!(card_margin),
+                "image_format" => json!(image_format.as_str()),
+                "card_delimiters" => json!(card_delimiters.as_str()),
+                "card_functions" => json!(card_functions),
+                "extra_fields" => json!(extra_fields),
+                "model" => json!(model),
+                "allow_duplicate_notes" => json!(allow_duplicate_notes),
+                "merge_by_id" => json!(merge_by_id),
+                "embed_metadata" => json!(embed_metadata),
+                "project" => json!(project),
+                "inputs" => json!(inputs.iter().cloned().collect::<BTreeMap<_, _>>()),
+                "image_quality" => json!(image_quality),
+                "no_cache" => json!(!use_cache),
                 "generation_concurrency" => json!(generation_concurrency),
                 "recompile_on_config_change" => json!(recompile_on_config_change),
+                "non_interactive" => json!(non_interactive),
+                "yes" => json!(yes),
+                "output_format" => json!(output_format.as_str()),
+                "color" => json!(color.as_str()),
                 _ => json!(null),
             };
             let t = match arg.get_action() {
@@ -421,6 +1917,7 @@ pub fn parse_config() -> Config {
         });
         let output = json!({ "options": options });
         println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        drop(extracted_zip_dirs);
         std::process::exit(0);
     }
 
@@ -434,9 +1931,17 @@ pub fn parse_config() -> Config {
             .iter()
             .map(|s| Pattern::new(s).unwrap_or_default())
             .collect(),
+        include_decks: include_decks
+            .iter()
+            .map(|s| Pattern::new(s).unwrap_or_default())
+            .collect(),
+        include_files: include_files
+            .iter()
+            .map(|s| Pattern::new(s).unwrap_or_default())
+            .collect(),
         exclude_decks_string: exclude_decks,
-        asked_path: asked_path.clone(),
         path: PathBuf::from(path),
+        paths,
         recompile_on_config_change: Arc::new(
             match recompile_on_config_change.to_ascii_lowercase().as_str() {
                 "y" | "yes" => Some(true),
@@ -446,15 +1951,92 @@ pub fn parse_config() -> Config {
             }
             .into(),
         ),
+        deck_overrides: {
+            // Globs are applied before exact deck names, so that the most specific table wins
+            let is_glob = |p: &str| p.contains(['*', '?', '[']);
+            let mut v: Vec<(Pattern, DeckOverrides)> = deck_overrides
+                .into_iter()
+                .map(|o| (Pattern::new(&o.pattern).unwrap_or_default(), o))
+                .collect();
+            v.sort_by_key(|(_, o)| !is_glob(&o.pattern));
+            v
+        },
+        deck_map,
+        template_front,
+        template_back,
         dry_run,
+        fail_fast,
+        show_warnings,
+        strict_pages,
+        strict_deck_names,
+        card_timeout: card_timeout.map(Duration::from_secs),
+        anki_max_inflight: anki_max_inflight as usize,
+        proxy,
+        offline,
+        package_cache: package_cache.unwrap_or_else(utils::default_package_cache),
+        font_paths,
+        ignore_system_fonts,
+        legacy_media_names,
+        hash_media_names,
+        now,
         max_card_width,
-        skip_cache,
+        max_card_height,
+        render_scale,
+        card_fill,
+        card_margin,
+        image_format,
+        image_quality,
+        card_delimiters,
+        card_functions,
+        extra_fields,
+        model,
+        allow_duplicate_notes,
+        merge_by_id,
+        embed_metadata,
+        project,
+        use_cache,
+        clear_cache: cli.clear_cache,
+        force: cli.force,
+        prune_media: cli.prune_media,
+        update_lock: cli.update_lock,
+        lockfile,
         generation_concurrency,
-        is_zip,
+        config_file,
+        extracted_zip_dirs,
         config_hash: None,
         output_type: "png".to_string(),
         typst_input,
-        keep_terminal_open: cli.keep_terminal_open,
+        // Non interactive mode takes precedence over -i, as nobody would be there to press Enter
+        keep_terminal_open: cli.keep_terminal_open && !non_interactive,
+        non_interactive,
+        yes,
+        output_format,
+        color: color_enabled,
+        export_apkg: cli.export_apkg.as_ref().map(PathBuf::from),
+        export_csv: cli.export_csv.as_ref().map(PathBuf::from),
+        preview_dir: cli.preview_dir.as_ref().map(PathBuf::from),
+        dump_source: cli.dump_source.as_ref().map(PathBuf::from),
+        dump_all_sources: cli.dump_all_sources,
+        watch: cli.watch,
+        list: cli.list,
+        stats_only: cli.stats_only,
+        diff: cli.diff,
+        // Without the cache every card is uploaded again, so no file can be skipped
+        only_changed: cli.only_changed && use_cache,
+        limit: cli.limit.map(|n| n as usize),
+        bench: cli.bench.map(|n| n as usize),
+        filter_ids: cli
+            .filter_ids
+            .iter()
+            .map(|s| Pattern::new(s).unwrap_or_default())
+            .collect(),
+        filter_decks: cli
+            .filter_decks
+            .iter()
+            .map(|s| Pattern::new(s).unwrap_or_default())
+            .collect(),
+        verbose: cli.verbose,
+        log_file,
         auto_number_file: cli.auto_number.clone(),
     };
     cfg.compute_hash();
@@ -462,8 +2044,64 @@ pub fn parse_config() -> Config {
     cfg
 }
 
-static CACHED_CONFIG: OnceCell<Config> = OnceCell::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn get() -> &'static Config {
-    CACHED_CONFIG.get_or_init(parse_config)
+    fn config(args: &[&str]) -> Config {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().into_owned();
+        let mut args = args.to_vec();
+        args.push(&path);
+        config_from_args(&args)
+    }
+
+    #[test]
+    fn decks_include_only() {
+        let cfg = config(&["--include-decks", "Bio*"]);
+        assert!(!cfg.is_deck_excluded("Biology"));
+        assert!(cfg.is_deck_excluded("Physics"));
+    }
+
+    #[test]
+    fn decks_exclude_only() {
+        let cfg = config(&["-e", "Physics"]);
+        assert!(!cfg.is_deck_excluded("Biology"));
+        assert!(cfg.is_deck_excluded("Physics"));
+    }
+
+    #[test]
+    fn decks_exclude_after_include() {
+        let cfg = config(&["--include-decks", "Bio*", "-e", "Biology::Drafts"]);
+        assert!(!cfg.is_deck_excluded("Biology"));
+        assert!(cfg.is_deck_excluded("Biology::Drafts"));
+        assert!(cfg.is_deck_excluded("Physics"));
+    }
+
+    #[test]
+    fn files_include_only() {
+        let cfg = config(&["--include-files", "chapter-*.typ"]);
+        assert!(!cfg.is_file_excluded("chapter-1.typ"));
+        assert!(cfg.is_file_excluded("notes.typ"));
+    }
+
+    #[test]
+    fn files_exclude_only() {
+        let cfg = config(&["--exclude-files", "*.wip.typ"]);
+        assert!(!cfg.is_file_excluded("chapter-1.typ"));
+        assert!(cfg.is_file_excluded("chapter-2.wip.typ"));
+    }
+
+    #[test]
+    fn files_exclude_after_include() {
+        let cfg = config(&[
+            "--include-files",
+            "chapter-*.typ",
+            "--exclude-files",
+            "*.wip.typ",
+        ]);
+        assert!(!cfg.is_file_excluded("chapter-1.typ"));
+        assert!(cfg.is_file_excluded("chapter-2.wip.typ"));
+        assert!(cfg.is_file_excluded("notes.typ"));
+    }
 }
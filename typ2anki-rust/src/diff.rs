@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    card_wrapper::CardInfo,
+    cards_cache::{CardsCacheManager, card_key},
+};
+
+// The card IDs of a deck that changed since they were last uploaded
+#[derive(Debug, Default)]
+pub struct DeckDiff {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+// --diff: compares the cards found to the cache of the last upload. The cache only keeps
+// hashes, so only the IDs are known for the removed cards. The cache is shared by every
+// project uploading to the same Anki, so removed cards are only looked for in the decks
+// of this run.
+pub fn cards_diff(
+    cards: &[CardInfo],
+    cards_cache_manager: &CardsCacheManager,
+) -> BTreeMap<String, DeckDiff> {
+    let mut diff: BTreeMap<String, DeckDiff> = BTreeMap::new();
+    let mut live: HashSet<String> = HashSet::new();
+    for card in cards {
+        let key = card_key(&card.deck_name, &card.card_id);
+        let deck = diff.entry(card.deck_name.clone()).or_default();
+        match cards_cache_manager.old_cache.get(&key) {
+            None => deck.added.push(card.card_id.clone()),
+            Some(cached) if cached.content_hash != card.content_hash => {
+                deck.modified.push(card.card_id.clone())
+            }
+            Some(_) => {}
+        }
+        live.insert(key);
+    }
+
+    let decks: Vec<String> = diff.keys().cloned().collect();
+    for key in cards_cache_manager.old_cache.keys() {
+        if live.contains(key) {
+            continue;
+        }
+        // Deck names may contain `_` too, the longest matching deck is the card's
+        let Some(deck) = decks
+            .iter()
+            .filter(|d| key.starts_with(&card_key(d, "")))
+            .max_by_key(|d| d.len())
+        else {
+            continue;
+        };
+        let id = key[card_key(deck, "").len()..].to_string();
+        if let Some(deck) = diff.get_mut(deck) {
+            deck.removed.push(id);
+        }
+    }
+
+    diff.retain(|_, d| !d.added.is_empty() || !d.modified.is_empty() || !d.removed.is_empty());
+    for deck in diff.values_mut() {
+        deck.added.sort();
+        deck.modified.sort();
+        deck.removed.sort();
+    }
+    diff
+}
@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use typst_kit::fonts::FontSearcher;
+
+use crate::{
+    anki_api,
+    config::{self, DEFAULT_CONFIG_FILENAME},
+    utils,
+};
+
+// The result of one check, printed as soon as it is known
+fn report(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(details) => {
+            println!("{} {}: {}", "[ OK ]".green(), name, details);
+            true
+        }
+        Err(details) => {
+            println!("{} {}: {}", "[FAIL]".red(), name, details);
+            false
+        }
+    }
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+// Only a config file asked for by name must exist
+fn check_config_file(path: Option<&Path>, config_file: &str) -> Result<String, String> {
+    let Some(path) = path else {
+        if config_file.is_empty() || config_file == DEFAULT_CONFIG_FILENAME {
+            return Ok("none found, the defaults are used".to_string());
+        }
+        return Err(format!("{} not found", config_file));
+    };
+    match config::read_config_file(path) {
+        Ok(_) => Ok(format!("{} loads", path.display())),
+        Err(e) => Err(format!("{} {}", path.display(), e)),
+    }
+}
+
+fn check_package_cache(package_cache: &Path) -> Result<String, String> {
+    fs::create_dir_all(package_cache)
+        .map(|()| format!("{}", package_cache.display()))
+        .map_err(|e| format!("{} can't be created: {}", package_cache.display(), e))
+}
+
+fn check_fonts(font_paths: &[PathBuf], include_system_fonts: bool) -> Result<String, String> {
+    let missing: Vec<String> = font_paths
+        .iter()
+        .filter(|p| !p.is_dir())
+        .map(|p| p.display().to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("font folders not found: {}", missing.join(", ")));
+    }
+    let fonts = FontSearcher::new()
+        .include_embedded_fonts(true)
+        .include_system_fonts(include_system_fonts)
+        .search_with(font_paths);
+    let dirs: Vec<String> = font_paths.iter().map(|p| p.display().to_string()).collect();
+    Ok(format!(
+        "{} fonts found (font folders: {}, system fonts: {})",
+        fonts.fonts.len(),
+        list(&dirs),
+        if include_system_fonts { "yes" } else { "no" }
+    ))
+}
+
+fn check_basic_model() -> Result<String, String> {
+    let (name, fields) = anki_api::get_basic_model_name()?;
+    Ok(format!("'{}' with fields {}", name, fields.join(", ")))
+}
+
+// `typ2anki doctor [dir]`: checks everything typ2anki needs, one line per check, so that
+// most problems can be found without a bug report. Returns whether every check passed.
+pub fn run(dir: &Path, config_file: &str) -> bool {
+    let mut healthy = true;
+
+    let start = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let config_path = if config_file.is_empty() {
+        None
+    } else {
+        config::find_config_file(&start, config_file)
+    };
+    healthy &= report(
+        "Config file",
+        check_config_file(config_path.as_deref(), config_file),
+    );
+    let table = config_path
+        .as_deref()
+        .and_then(|p| config::read_config_file(p).ok().flatten());
+
+    // The same paths as a run with this config file, the command line options aside
+    let get_str = |key: &str| table.as_ref()?.get(key)?.as_str().map(|s| s.to_string());
+    let package_cache = get_str("package_cache")
+        .map(|p| config::config_relative_path(config_path.as_deref(), &p))
+        .unwrap_or_else(utils::default_package_cache);
+    healthy &= report("Package cache", check_package_cache(&package_cache));
+
+    let font_paths: Vec<PathBuf> = table
+        .as_ref()
+        .and_then(|t| t.get("font_paths")?.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|p| p.as_str())
+        .map(|p| config::config_relative_path(config_path.as_deref(), p))
+        .collect();
+    let ignore_system_fonts = table
+        .as_ref()
+        .and_then(|t| t.get("ignore_system_fonts")?.as_bool())
+        .unwrap_or(false);
+    healthy &= report("Fonts", check_fonts(&font_paths, !ignore_system_fonts));
+
+    if !anki_api::check_anki_running() {
+        report(
+            "Anki",
+            Err("AnkiConnect doesn't answer at http://localhost:8765: open Anki, and install the AnkiConnect add-on (code 2055492159) if needed".to_string()),
+        );
+        return false;
+    }
+    report("Anki", Ok("AnkiConnect answers".to_string()));
+
+    report("Decks", Ok(list(&anki_api::get_deck_names())));
+    // Not needed to upload cards, only to prune the unused images
+    report(
+        "Media folder",
+        anki_api::media_dir()
+            .map(|dir| dir.display().to_string())
+            .map_err(|e| format!("{} (only --prune-media needs it)", e)),
+    );
+    match anki_api::get_model_names() {
+        Ok(models) => {
+            report("Note types", Ok(list(&models)));
+        }
+        Err(e) => healthy &= report("Note types", Err(e.to_string())),
+    }
+    // With `model` set, the cards of the decks without their own note type use it instead
+    match get_str("model") {
+        Some(model) => {
+            healthy &= report(
+                "Note type",
+                anki_api::get_model_names()
+                    .map_err(String::from)
+                    .and_then(|models| {
+                        if models.contains(&model) {
+                            Ok(format!("'{}' is set in the config file", model))
+                        } else {
+                            Err(format!("'{}' set in the config file doesn't exist", model))
+                        }
+                    }),
+            )
+        }
+        None => healthy &= report("Basic note type", check_basic_model()),
+    }
+
+    healthy
+}
@@ -0,0 +1,62 @@
+use thiserror::Error;
+
+// What went wrong, so that callers can tell a network failure (worth retrying) from a card
+// that doesn't compile. Each variant holds the message shown to the user.
+#[derive(Debug, Clone, Error)]
+pub enum Typ2AnkiError {
+    // AnkiConnect answered, with an error or something unexpected
+    #[error("{0}")]
+    Anki(String),
+    // A card couldn't be compiled or rendered
+    #[error("{0}")]
+    Compile(String),
+    // A card couldn't be read from its file
+    #[error("{0}")]
+    Parse(String),
+    // A file couldn't be read or written
+    #[error("{0}")]
+    Io(String),
+    // AnkiConnect couldn't be reached
+    #[error("{0}")]
+    Network(String),
+}
+
+pub type Result<T> = std::result::Result<T, Typ2AnkiError>;
+
+impl Typ2AnkiError {
+    // The name of the variant, for the JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Anki(_) => "anki",
+            Self::Compile(_) => "compile",
+            Self::Parse(_) => "parse",
+            Self::Io(_) => "io",
+            Self::Network(_) => "network",
+        }
+    }
+
+    // Prefixes the message, keeping the kind of error
+    pub fn context(self, context: &str) -> Self {
+        let with = |msg: String| format!("{}: {}", context, msg);
+        match self {
+            Self::Anki(m) => Self::Anki(with(m)),
+            Self::Compile(m) => Self::Compile(with(m)),
+            Self::Parse(m) => Self::Parse(with(m)),
+            Self::Io(m) => Self::Io(with(m)),
+            Self::Network(m) => Self::Network(with(m)),
+        }
+    }
+}
+
+impl From<std::io::Error> for Typ2AnkiError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
+// The functions that still return their errors as text
+impl From<Typ2AnkiError> for String {
+    fn from(e: Typ2AnkiError) -> Self {
+        e.to_string()
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context as _;
+
+use crate::{
+    card_wrapper::CardInfo,
+    config::Config,
+    error::{self, Typ2AnkiError},
+    utils,
+};
+
+/// Receives compiled cards instead of them being uploaded to Anki, and writes them to a file
+pub trait CardExporter: Send {
+    // `pages_base64` has the front, the back, then the pages of the extra fields
+    fn add_card(
+        &mut self,
+        cfg: &Config,
+        card: &CardInfo,
+        pages_base64: &[String],
+    ) -> error::Result<()>;
+
+    // Writes the exported file, returning the number of cards in it
+    fn write(&self, path: &Path) -> anyhow::Result<usize>;
+}
+
+pub type SharedExporter = Arc<Mutex<dyn CardExporter>>;
+
+struct CsvRow {
+    internal_id: usize,
+    deck_name: String,
+    card_id: String,
+    tags: Vec<String>,
+    // The `model` of the card's deck
+    model: Option<String>,
+    // The front, the back, then the extra fields
+    fields: Vec<String>,
+}
+
+/// Writes cards as a CSV (or TSV, based on the extension) file that can be imported with
+/// Anki's File → Import, with the images in a `<name>_media` directory next to it
+pub struct CsvWriter {
+    // The names of the columns after Front and Back, from --extra-field
+    extra_fields: Vec<String>,
+    rows: Vec<CsvRow>,
+    media: Vec<(String, Vec<u8>)>,
+}
+
+impl CsvWriter {
+    pub fn new(extra_fields: &[String]) -> Self {
+        Self {
+            extra_fields: extra_fields.to_vec(),
+            rows: Vec::new(),
+            media: Vec::new(),
+        }
+    }
+
+    pub fn media_dir(path: &Path) -> PathBuf {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "typ2anki".to_string());
+        path.with_file_name(format!("{}_media", stem))
+    }
+
+    fn escape_field(field: &str, separator: char) -> String {
+        if field.contains(separator)
+            || field.contains('"')
+            || field.contains('\n')
+            || field.contains('\r')
+        {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+}
+
+impl CardExporter for CsvWriter {
+    fn add_card(
+        &mut self,
+        cfg: &Config,
+        card: &CardInfo,
+        pages_base64: &[String],
+    ) -> error::Result<()> {
+        for (i, data) in pages_base64.iter().enumerate() {
+            let bytes = utils::b64_decode(data)
+                .map_err(|e| Typ2AnkiError::Compile(format!("invalid image data: {}", e)))?;
+            self.media.push((card.image_path(cfg, i + 1), bytes));
+        }
+        self.rows.push(CsvRow {
+            internal_id: card.internal_id,
+            deck_name: card
+                .anki_deck_name
+                .clone()
+                .unwrap_or_else(|| card.deck_name.clone()),
+            card_id: card.card_id.clone(),
+            tags: card.anki_tags(cfg),
+            model: card.deck_config(cfg).model,
+            fields: (1..=pages_base64.len())
+                .map(|page| cfg.template_page(card, page, &card.image_path(cfg, page)))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> anyhow::Result<usize> {
+        let is_tsv = path
+            .extension()
+            .map(|e| e.eq_ignore_ascii_case("tsv"))
+            .unwrap_or(false);
+        let (separator, separator_name) = if is_tsv {
+            ('\t', "tab")
+        } else {
+            (',', "comma")
+        };
+
+        // Anki's importer takes one note type for the file, the one of the import dialog
+        // when it isn't given
+        let models: BTreeSet<Option<&str>> = self.rows.iter().map(|r| r.model.as_deref()).collect();
+        if models.len() > 1 {
+            anyhow::bail!(
+                "The decks use different note types ({}), which can't be imported from one CSV file: export them separately, e.g. with --include-decks",
+                models
+                    .iter()
+                    .map(|m| m.unwrap_or("Basic"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let notetype_header = match models.first() {
+            Some(Some(model)) => format!("#notetype:{}\n", model),
+            _ => String::new(),
+        };
+
+        let media_dir = Self::media_dir(path);
+        fs::create_dir_all(&media_dir)
+            .with_context(|| format!("Failed to create {}", media_dir.display()))?;
+        for (filename, bytes) in &self.media {
+            fs::write(media_dir.join(filename), bytes)
+                .with_context(|| format!("Failed to write {}", filename))?;
+        }
+
+        let mut rows: Vec<&CsvRow> = self.rows.iter().collect();
+        rows.sort_by(|a, b| {
+            (&a.deck_name, &a.card_id, a.internal_id).cmp(&(
+                &b.deck_name,
+                &b.card_id,
+                b.internal_id,
+            ))
+        });
+
+        // Headers understood by Anki's importer, so that the columns are mapped automatically
+        let columns: Vec<&str> = ["Deck", "Card ID", "Tags", "Front", "Back"]
+            .into_iter()
+            .chain(self.extra_fields.iter().map(String::as_str))
+            .collect();
+        let mut out = format!(
+            "#separator:{}\n#html:true\n{}#deck column:1\n#tags column:3\n#columns:{}\n",
+            separator_name,
+            notetype_header,
+            columns
+                .iter()
+                .map(|c| Self::escape_field(c, separator))
+                .collect::<Vec<_>>()
+                .join(&separator.to_string())
+        );
+        for row in rows {
+            let tags = row.tags.join(" ");
+            let fields: Vec<&str> = [row.deck_name.as_str(), row.card_id.as_str(), &tags]
+                .into_iter()
+                .chain(row.fields.iter().map(String::as_str))
+                .collect();
+            let line: Vec<String> = fields
+                .iter()
+                .map(|f| Self::escape_field(f, separator))
+                .collect();
+            out.push_str(&line.join(&separator.to_string()));
+            out.push('\n');
+        }
+        fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(self.rows.len())
+    }
+}
+
+/// Writes the front and back images of each card to a directory, named after the card's ID,
+/// to look at the cards without uploading them (--preview-dir)
+pub struct PreviewWriter {
+    dir: PathBuf,
+    cards: usize,
+    // The position in source order of the card each image was written for. Cards with the
+    // same ID in different decks share their images: the first one in source order keeps
+    // them, whichever compiles first, so that the previews are the same between runs.
+    written: HashMap<String, usize>,
+}
+
+impl PreviewWriter {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            cards: 0,
+            written: HashMap::new(),
+        }
+    }
+
+    // `side` is "front", "back", or the page number of an extra field
+    fn image_name(cfg: &Config, card: &CardInfo, side: &str) -> String {
+        // The ID becomes a file name, it mustn't point to another directory
+        let id = card.card_id.replace(['/', '\\'], "_");
+        format!(
+            "{}-{}.{}",
+            id,
+            side,
+            card.deck_config(cfg).image_format.extension()
+        )
+    }
+}
+
+impl CardExporter for PreviewWriter {
+    // Images are written right away, so that they can be looked at during a long run
+    fn add_card(
+        &mut self,
+        cfg: &Config,
+        card: &CardInfo,
+        pages_base64: &[String],
+    ) -> error::Result<()> {
+        fs::create_dir_all(&self.dir).map_err(|e| {
+            Typ2AnkiError::Io(format!("failed to create {}: {}", self.dir.display(), e))
+        })?;
+        for (i, data) in pages_base64.iter().enumerate() {
+            let side = match i {
+                0 => "front".to_string(),
+                1 => "back".to_string(),
+                _ => format!("page{}", i + 1),
+            };
+            let name = Self::image_name(cfg, card, &side);
+            if self
+                .written
+                .get(&name)
+                .is_some_and(|&first| first < card.internal_id)
+            {
+                continue;
+            }
+            let bytes = utils::b64_decode(data)
+                .map_err(|e| Typ2AnkiError::Compile(format!("invalid image data: {}", e)))?;
+            let path = self.dir.join(&name);
+            fs::write(&path, bytes).map_err(|e| {
+                Typ2AnkiError::Io(format!("failed to write {}: {}", path.display(), e))
+            })?;
+            self.written.insert(name, card.internal_id);
+        }
+        self.cards += 1;
+        Ok(())
+    }
+
+    fn write(&self, _path: &Path) -> anyhow::Result<usize> {
+        Ok(self.cards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::config_from_args, parse_file::DocumentDefaults};
+
+    fn card(deck: &str) -> CardInfo {
+        let card_str = format!(
+            r#"#card(id: "001", target-deck: "{}", q: [q], a: [a])"#,
+            deck
+        );
+        CardInfo::from_string(
+            &card_str,
+            PathBuf::from("cards.typ"),
+            (0, card_str.len()),
+            &DocumentDefaults::default(),
+        )
+        .unwrap()
+    }
+
+    fn export_csv(toml: &str, decks: &[&str]) -> (tempfile::TempDir, anyhow::Result<usize>) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("typ2anki.toml"), toml).unwrap();
+        let cfg = config_from_args(&[&dir.path().to_string_lossy()]);
+        let mut writer = CsvWriter::new(&[]);
+        let pages = [utils::b64_encode("front"), utils::b64_encode("back")];
+        for deck in decks {
+            writer.add_card(&cfg, &card(deck), &pages).unwrap();
+        }
+        let result = writer.write(&dir.path().join("cards.csv"));
+        (dir, result)
+    }
+
+    #[test]
+    fn csv_names_the_model_of_the_decks() {
+        let (dir, result) = export_csv("model = \"Vocabulary\"\n", &["Spanish", "French"]);
+        assert_eq!(result.unwrap(), 2);
+        let csv = fs::read_to_string(dir.path().join("cards.csv")).unwrap();
+        assert!(csv.contains("\n#notetype:Vocabulary\n"), "{}", csv);
+
+        let (dir, result) = export_csv("", &["Spanish"]);
+        assert_eq!(result.unwrap(), 1);
+        let csv = fs::read_to_string(dir.path().join("cards.csv")).unwrap();
+        assert!(!csv.contains("#notetype"), "{}", csv);
+    }
+
+    #[test]
+    fn preview_errors_have_their_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config_from_args(&[&dir.path().to_string_lossy()]);
+        let pages = [utils::b64_encode("front"), "not base64!".to_string()];
+        let mut writer = PreviewWriter::new(&dir.path().join("preview"));
+        let error = writer.add_card(&cfg, &card("D"), &pages).unwrap_err();
+        assert!(matches!(error, Typ2AnkiError::Compile(_)), "{:?}", error);
+
+        // The preview directory can't be created where a file is
+        fs::write(dir.path().join("file"), "").unwrap();
+        let mut writer = PreviewWriter::new(&dir.path().join("file"));
+        let error = writer.add_card(&cfg, &card("D"), &pages).unwrap_err();
+        assert!(matches!(error, Typ2AnkiError::Io(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn csv_rejects_decks_with_different_models() {
+        let toml = "[decks.\"Spanish\"]\nmodel = \"Vocabulary\"\n";
+        let (_dir, result) = export_csv(toml, &["Spanish", "Biology"]);
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.contains("different note types (Basic, Vocabulary)"),
+            "{}",
+            error
+        );
+    }
+}
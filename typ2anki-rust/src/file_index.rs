@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    card_wrapper::TFiles,
+    cards_cache,
+    config::Config,
+    utils::{self, hash_string},
+};
+
+// With --only-changed, the files whose cards were all uploaded are recorded next to the
+// local cards cache, so that the next runs can skip reading them until they change. The
+// configuration and ankiconf.typ are part of the key, so changing them makes every file
+// be read again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Fingerprint {
+    modified_ns: u128,
+    size: u64,
+    content_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedFile {
+    fingerprint: Fingerprint,
+    // The cards of the file, all unchanged since they were uploaded
+    cards: usize,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct IndexFile {
+    key: String,
+    files: HashMap<PathBuf, IndexedFile>,
+}
+
+pub struct FileIndex {
+    path: PathBuf,
+    key: String,
+    files: HashMap<PathBuf, IndexedFile>,
+    // The files read during this run, as they were before being parsed
+    read: HashMap<PathBuf, Fingerprint>,
+}
+
+fn modified_ns(metadata: &fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let metadata = fs::metadata(path).ok()?;
+    let content = fs::read(path).ok()?;
+    Some(Fingerprint {
+        modified_ns: modified_ns(&metadata),
+        size: metadata.len(),
+        content_hash: hash_string(&String::from_utf8_lossy(&content)),
+    })
+}
+
+impl FileIndex {
+    // The static hash covers ankiconf.typ and the configuration. The deck filters decide
+    // which cards of a file were uploaded, so they are part of the key too.
+    pub fn load(cfg: &Config, static_hash: &str) -> Self {
+        let filters: Vec<&str> = cfg
+            .include_decks
+            .iter()
+            .chain(&cfg.exclude_decks)
+            .map(|p| p.as_str())
+            .collect();
+        let key = utils::hash_string(&format!("{}{}", static_hash, filters.join("\n")));
+        let path = cards_cache::local_cache_path(cfg).with_extension("files.json");
+        let files = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<IndexFile>(&s).ok())
+            .filter(|index| index.key == key)
+            .map(|index| index.files)
+            .unwrap_or_default();
+        Self {
+            path,
+            key,
+            files,
+            read: HashMap::new(),
+        }
+    }
+
+    // The number of cards of the file if it didn't change since it was recorded. A file
+    // that was only touched is recognized by its content.
+    pub fn unchanged_cards(&mut self, path: &Path) -> Option<usize> {
+        let indexed = self.files.get_mut(path)?;
+        let metadata = fs::metadata(path).ok()?;
+        if metadata.len() != indexed.fingerprint.size {
+            return None;
+        }
+        if modified_ns(&metadata) != indexed.fingerprint.modified_ns {
+            let current = fingerprint(path)?;
+            if current.content_hash != indexed.fingerprint.content_hash {
+                return None;
+            }
+            indexed.fingerprint = current;
+        }
+        Some(indexed.cards)
+    }
+
+    // Called before a file is parsed, so that a change made while its cards are compiled
+    // is seen by the next run
+    pub fn track(&mut self, path: &Path) {
+        if let Some(fingerprint) = fingerprint(path) {
+            self.read.insert(path.to_path_buf(), fingerprint);
+        }
+    }
+
+    // Records the files read in this run whose cards were all parsed and uploaded
+    pub fn record(&mut self, files: &TFiles) {
+        let Ok(files) = files.read() else {
+            return;
+        };
+        for (path, fingerprint) in self.read.drain() {
+            match files.get(&path) {
+                Some(stats) if stats.total_errors() == 0 && stats.parse_errors == 0 => {
+                    self.files.insert(
+                        path,
+                        IndexedFile {
+                            fingerprint,
+                            cards: stats.total_cards,
+                        },
+                    );
+                }
+                _ => {
+                    self.files.remove(&path);
+                }
+            }
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let index = IndexFile {
+            key: self.key.clone(),
+            files: self.files.clone(),
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string(&index)?)
+    }
+}
@@ -1,30 +1,65 @@
-use crate::{card_wrapper::CardInfo, config};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-pub fn generate_card_file_content(ankiconf_relative_path: String, card_content: String) -> String {
-    let cfg = config::get();
+use crate::{
+    card_wrapper::CardInfo,
+    config::{Config, DeckConfig},
+    parse_file::nearest_ankiconf,
+};
 
-    // display_with_width: different when max_card_width == "auto"
-    let display_with_width = if cfg.max_card_width == "auto" {
-        r#"#let display_with_width(body) = {
-  body
-}"#
-        .to_string()
+pub fn generate_card_file_content(
+    cfg: &Config,
+    ankiconf_relative_path: String,
+    card_content: String,
+    deck_config: &DeckConfig,
+) -> String {
+    // display_with_width: clamps the width of the body to max_card_width (making it wrap),
+    // then scales it down if it is taller than max_card_height. Plain body when both are auto.
+    let clamp_width = if deck_config.max_card_width == "auto" {
+        "".to_string()
     } else {
         format!(
-            r#"#let display_with_width(body) = {{
-  layout(size => {{
+            r#"
     let (width,) = measure(body)
     if width > {max} {{
       width = {max}
     }} else {{
       width = auto
     }}
+    content = block(width: width, body)"#,
+            max = deck_config.max_card_width
+        )
+    };
+    let clamp_height = if cfg.max_card_height == "auto" {
+        "".to_string()
+    } else {
+        format!(
+            r#"
+    let (height,) = measure(content)
+    if height > {max} {{
+      let ratio = {max} / height * 100%
+      content = scale(x: ratio, y: ratio, reflow: true, content)
+    }}"#,
+            max = cfg.max_card_height
+        )
+    };
+    let display_with_width = if clamp_width.is_empty() && clamp_height.is_empty() {
+        r#"#let display_with_width(body) = {
+  body
+}"#
+        .to_string()
+    } else {
+        format!(
+            r#"#let display_with_width(body) = {{
+  layout(size => {{
+    let content = body{clamp_width}{clamp_height}
     context[
-      #block(width: width,body)
+      #content
     ]
   }})
-}}"#,
-            max = cfg.max_card_width
+}}"#
         )
     };
 
@@ -32,13 +67,21 @@ pub fn generate_card_file_content(ankiconf_relative_path: String, card_content:
     let page_configuration = if cfg.output_type == "html" {
         "".to_string()
     } else {
-        r#"#set page(
+        // A transparent page has no fill at all
+        let fill = if cfg.card_fill == "transparent" {
+            "none".to_string()
+        } else {
+            format!("rgb(\"{}\")", cfg.card_fill)
+        };
+        format!(
+            r#"#set page(
   width: auto,
   height: auto,
-  margin: 3pt,
-  fill: rgb(255,255,255),
-)"#
-        .to_string()
+  margin: {},
+  fill: {},
+)"#,
+            cfg.card_margin, fill
+        )
     };
 
     // Assemble template by concatenation to avoid format-brace escaping
@@ -56,10 +99,12 @@ pub fn generate_card_file_content(ankiconf_relative_path: String, card_content:
     template.push_str(&display_with_width);
     template.push_str("\n\n");
 
+    // `pages` are shown after the answer, one per page, for the --extra-field fields
     let cardlet = r#"#let card(
       id: "",
       q: "",
       a: "",
+      pages: (),
       ..args
     ) = {
       let args = arguments(..args, type: "basic")
@@ -68,6 +113,10 @@ pub fn generate_card_file_content(ankiconf_relative_path: String, card_content:
           #display_with_width(q)
           #pagebreak()
           #display_with_width(a)
+          #for page in pages [
+            #pagebreak()
+            #display_with_width(page)
+          ]
         ]
       }
     }
@@ -75,6 +124,9 @@ pub fn generate_card_file_content(ankiconf_relative_path: String, card_content:
     "#
     .to_string();
     template.push_str(&cardlet);
+    for name in &cfg.card_functions {
+        template.push_str(&format!("#let {} = card\n", name));
+    }
     template.push_str("\n\n");
 
     template.push_str(&card_content);
@@ -82,12 +134,40 @@ pub fn generate_card_file_content(ankiconf_relative_path: String, card_content:
 }
 
 #[allow(dead_code)]
-pub fn generate_card_file(card: &CardInfo) -> String {
-    println!(
+pub fn generate_card_file(cfg: &Config, card: &CardInfo) -> String {
+    log::debug!(
         "Generating card file for card ID {} at {}",
         card.card_id,
-        card.relative_ankiconf_path()
+        card.relative_ankiconf_path(cfg)
     );
 
-    generate_card_file_content(card.relative_ankiconf_path(), card.content.clone())
+    generate_card_file_content(
+        cfg,
+        card.relative_ankiconf_path(cfg),
+        card.content.clone(),
+        &card.deck_config(cfg),
+    )
+}
+
+// --dump-source: writes the source compiled for a card to `dir/temporal-<card id>.typ`.
+// The ankiconf.typ import is made relative to `dir`, so that the file compiles with
+// `typst compile --root <project>` when `dir` is inside the project.
+pub fn dump_card_file(cfg: &Config, card: &CardInfo, dir: &Path) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let dir = fs::canonicalize(dir)?;
+    let ankiconf_path = nearest_ankiconf(card.root(cfg), &card.source_file);
+    let ankiconf_path = pathdiff::diff_paths(&ankiconf_path, &dir).unwrap_or(ankiconf_path);
+    let content = generate_card_file_content(
+        cfg,
+        ankiconf_path.to_string_lossy().into_owned(),
+        card.content.clone(),
+        &card.deck_config(cfg),
+    );
+    // The ID becomes a file name, it mustn't point to another directory
+    let path = dir.join(format!(
+        "temporal-{}.typ",
+        card.card_id.replace(['/', '\\'], "_")
+    ));
+    fs::write(&path, content)?;
+    Ok(path)
 }
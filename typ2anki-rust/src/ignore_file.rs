@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use glob::{MatchOptions, Pattern};
+
+pub const IGNORE_FILENAME: &str = ".typ2anki-ignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// A line of an ignore file, with the gitignore syntax
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    // `!pattern` includes again what an earlier rule ignored
+    negated: bool,
+    // `pattern/` only matches folders
+    dir_only: bool,
+    // Patterns with a `/` are relative to the ignore file's folder, the others match a
+    // file or folder of that name at any depth
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let pattern = match Pattern::new(line) {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("Invalid pattern {:?} in {}: {}", line, IGNORE_FILENAME, e);
+                return None;
+            }
+        };
+        Some(Self {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    // `relative` uses `/` as the separator
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.pattern.matches_with(relative, MATCH_OPTIONS)
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            self.pattern.matches_with(name, MATCH_OPTIONS)
+        }
+    }
+}
+
+type Rules = Arc<Vec<Rule>>;
+
+// The ignore file of each folder, read once. None if the folder has none.
+static IGNORE_FILES: LazyLock<Mutex<HashMap<PathBuf, Option<Rules>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn rules_of(dir: &Path) -> Option<Rules> {
+    let mut files = IGNORE_FILES.lock().unwrap_or_else(|e| e.into_inner());
+    files
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| {
+            let content = fs::read_to_string(dir.join(IGNORE_FILENAME)).ok()?;
+            Some(Arc::new(content.lines().filter_map(Rule::parse).collect()))
+        })
+        .clone()
+}
+
+// Forgets the ignore files read so far, for watch mode when one of them changes
+pub fn clear_cache() {
+    IGNORE_FILES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+}
+
+// Whether `path`, inside `root`, is ignored by the .typ2anki-ignore files of `root` and of the
+// folders between them. Like with git, the last matching rule wins, the rules of deeper
+// files come after those of their parents, and nothing inside an ignored folder can be
+// included again.
+pub fn is_ignored(root: &Path, path: &Path, is_dir: bool) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+    let components: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let mut dir = root.to_path_buf();
+    for (i, name) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        let mut ignored = false;
+        // The rules of every folder from the root to this entry's parent
+        let mut rules_dir = root.to_path_buf();
+        for depth in 0..=i {
+            if let Some(rules) = rules_of(&rules_dir) {
+                let relative = components[depth..=i].join("/");
+                for rule in rules.iter() {
+                    if rule.matches(&relative, is_dir || !last) {
+                        ignored = !rule.negated;
+                    }
+                }
+            }
+            if depth < i {
+                rules_dir.push(&components[depth]);
+            }
+        }
+        if ignored {
+            log::debug!("{} is ignored", dir.join(name).display());
+            return true;
+        }
+        dir.push(name);
+    }
+    false
+}
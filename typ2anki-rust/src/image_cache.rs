@@ -0,0 +1,63 @@
+use std::{fs, path::PathBuf};
+
+use crate::{
+    card_wrapper::CardInfo,
+    config::{Config, ImageFormat},
+    utils,
+};
+
+// Rendered images are kept on disk between runs, so that a card whose content and
+// configuration haven't changed is never compiled twice, even when the Anki side cache
+// is skipped (--no-cache, or a different machine).
+fn cache_dir() -> PathBuf {
+    utils::get_typ2anki_tmp().join("images")
+}
+
+// The static hash covers ankiconf.typ and the configuration (render scale, image format...),
+// the path covers imports relative to the card's file
+pub fn card_key(cfg: &Config, static_hash: &str, card: &CardInfo) -> String {
+    let mut path = card.path_relative_to_root(cfg);
+    // With several folders, the same relative path may exist in more than one
+    if cfg.paths.len() > 1 {
+        path = card.source_file.to_string_lossy().into_owned();
+    }
+    utils::hash_string(&format!("{}{}{}", static_hash, path, card.content_hash))
+}
+
+fn image_path(key: &str, page: usize, format: ImageFormat) -> PathBuf {
+    cache_dir().join(format!("{}-{}.{}", key, page, format.extension()))
+}
+
+// Returns the images of the pages of a card, if they are all cached
+pub fn get(key: &str, format: ImageFormat, pages: usize) -> Option<Vec<Vec<u8>>> {
+    (1..=pages)
+        .map(|page| fs::read(image_path(key, page, format)).ok())
+        .collect()
+}
+
+// Writes through a temporary file, so that an interrupted run never leaves a truncated image
+fn write_atomic(path: &PathBuf, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+}
+
+// Failing to write to the cache only means the card is compiled again next time
+pub fn put(key: &str, format: ImageFormat, pages: &[Vec<u8>]) {
+    if fs::create_dir_all(cache_dir()).is_err() {
+        return;
+    }
+    for (i, bytes) in pages.iter().enumerate() {
+        if write_atomic(&image_path(key, i + 1, format), bytes).is_err() {
+            return;
+        }
+    }
+}
+
+pub fn clear() -> std::io::Result<()> {
+    let dir = cache_dir();
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::{fs, io, path::Path};
+
+use crate::{config::DEFAULT_CONFIG_FILENAME, parse_file::DEFAULT_ANKICONF};
+
+const SAMPLE_CARDS: &str = r#"#import "ankiconf.typ": *
+#show: doc => conf(doc)
+
+// Every #card is a note in Anki. Its id must be unique in the deck: changing the card
+// and running typ2anki again updates the note instead of creating a new one.
+#card(
+  id: "001",
+  target-deck: "My Deck",
+  q: [What is the derivative of $x^2$?],
+  a: [$2x$],
+)
+"#;
+
+const SAMPLE_CONFIG: &str = r#"# Default options of typ2anki for this project, see `typ2anki --help` for all of them
+
+# check_duplicates = true
+# exclude_decks = ["Drafts*"]
+# max_card_width = "auto"
+# render_scale = 2.0
+# image_format = "png"
+
+# Settings for the cards of a deck
+# [decks."My Deck"]
+# tags = ["my-tag"]
+"#;
+
+// `typ2anki init [dir]`: writes what a new project needs, leaving the files that already
+// exist as they are
+pub fn run(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let files = [
+        ("ankiconf.typ", DEFAULT_ANKICONF),
+        ("cards.typ", SAMPLE_CARDS),
+        (DEFAULT_CONFIG_FILENAME, SAMPLE_CONFIG),
+    ];
+    for (name, content) in files {
+        let path = dir.join(name);
+        if path.exists() {
+            log::warn!("{} already exists, leaving it as it is", path.display());
+            continue;
+        }
+        fs::write(&path, content)?;
+        println!("Created {}", path.display());
+    }
+    println!(
+        "With Anki open, run `typ2anki {}` to upload the cards.",
+        dir.display()
+    );
+    Ok(())
+}
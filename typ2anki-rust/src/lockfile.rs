@@ -0,0 +1,133 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use toml::Value as TomlValue;
+use typst::syntax::package::PackageSpec;
+use walkdir::WalkDir;
+
+pub const LOCKFILE_NAME: &str = "typ2anki.lock";
+
+// The Typst packages used by the cards, with a hash of their files. Committed with the
+// project, it makes every machine render the cards with exactly the same packages.
+#[derive(Debug)]
+pub struct Lockfile {
+    path: PathBuf,
+    // With --update-lock, packages whose files changed are accepted
+    update: bool,
+    state: Mutex<LockfileState>,
+}
+
+#[derive(Debug)]
+struct LockfileState {
+    // What the lockfile had when typ2anki started (or after the last save, in watch mode)
+    locked: BTreeMap<String, String>,
+    // The packages used during this run, hashed once each
+    resolved: BTreeMap<String, String>,
+    // The packages whose files don't match the lockfile
+    mismatched: BTreeSet<String>,
+}
+
+fn read(path: &Path) -> BTreeMap<String, String> {
+    let Ok(s) = fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+    let Ok(value) = s.parse::<TomlValue>() else {
+        return BTreeMap::new();
+    };
+    value
+        .get("packages")
+        .and_then(|p| p.as_table())
+        .map(|t| {
+            t.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Hashes the paths and contents of every file of the package, in a fixed order
+pub fn hash_dir(dir: &Path) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    for entry in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        hasher.update(relative.to_string_lossy().replace('\\', "/").as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&fs::read(entry.path()).unwrap_or_default());
+    }
+    hasher.digest().to_string()
+}
+
+impl Lockfile {
+    // Reads the lockfile of the project in `dir`, if it has one
+    pub fn load(dir: &Path, update: bool) -> Self {
+        let path = dir.join(LOCKFILE_NAME);
+        Self {
+            state: Mutex::new(LockfileState {
+                locked: read(&path),
+                resolved: BTreeMap::new(),
+                mismatched: BTreeSet::new(),
+            }),
+            path,
+            update,
+        }
+    }
+
+    // Fails if the package's files differ from the ones recorded in the lockfile,
+    // unless --update-lock is set
+    pub fn check_package(&self, package: &PackageSpec, dir: &Path) -> Result<(), String> {
+        let key = package.to_string();
+        let mut lock = self.state.lock().unwrap();
+        if lock.resolved.contains_key(&key) {
+            return Ok(());
+        }
+        if !lock.mismatched.contains(&key) {
+            let hash = hash_dir(dir);
+            if lock.locked.get(&key).is_none_or(|locked| *locked == hash) || self.update {
+                lock.resolved.insert(key, hash);
+                return Ok(());
+            }
+            lock.mismatched.insert(key.clone());
+        }
+        Err(format!(
+            "package {} differs from the one recorded in {}, run with --update-lock if the change is expected",
+            key, LOCKFILE_NAME
+        ))
+    }
+
+    // Writes the lockfile if packages were added or updated during this run. Packages that
+    // weren't used are kept, as they may be used by files that were excluded this time.
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut lock = self.state.lock().unwrap();
+        let mut packages = lock.locked.clone();
+        packages.extend(lock.resolved.clone());
+        if packages == lock.locked {
+            return Ok(());
+        }
+
+        let table: toml::map::Map<String, TomlValue> = packages
+            .iter()
+            .map(|(k, v)| (k.clone(), TomlValue::String(v.clone())))
+            .collect();
+        let mut root = toml::map::Map::new();
+        root.insert("packages".to_string(), TomlValue::Table(table));
+        let body = toml::to_string(&TomlValue::Table(root)).unwrap_or_default();
+        fs::write(
+            &self.path,
+            format!(
+                "# Generated by typ2anki: the Typst packages used by the cards and a hash of their files\n\n{}",
+                body
+            ),
+        )?;
+        lock.locked = packages;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use colored::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+// Diagnostics for users and bug reports. What typ2anki is doing (progress, card errors...)
+// goes through the OutputManager instead.
+struct Logger {
+    // Messages shown on stderr
+    level: LevelFilter,
+    // With --log-file, also written there, with at least the debug messages
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    fn max_level(&self) -> LevelFilter {
+        match self.file {
+            Some(_) => self.level.max(LevelFilter::Debug),
+            None => self.level,
+        }
+    }
+}
+
+// Debug messages of the dependencies (HTTP requests...) are only shown at the trace level
+fn is_own(metadata: &Metadata) -> bool {
+    metadata.target().starts_with(env!("CARGO_CRATE_NAME")) || metadata.level() <= Level::Info
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.max_level()
+            && (is_own(metadata) || self.max_level() == LevelFilter::Trace)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.level {
+            let level = match record.level() {
+                Level::Error => "error".red().bold(),
+                Level::Warn => "warning".yellow().bold(),
+                Level::Info => "info".green(),
+                Level::Debug => "debug".blue(),
+                Level::Trace => "trace".bright_black(),
+            };
+            eprintln!("{}: {}", level, record.args());
+        }
+        if let Some(file) = &self.file
+            && let Ok(mut file) = file.lock()
+        {
+            let now = OffsetDateTime::now_local()
+                .unwrap_or_else(|_| OffsetDateTime::now_utc())
+                .format(&Rfc3339)
+                .unwrap_or_default();
+            let _ = writeln!(
+                file,
+                "{} {:<5} {}: {}",
+                now,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file
+            && let Ok(mut file) = file.lock()
+        {
+            let _ = file.flush();
+        }
+    }
+}
+
+// Warnings and errors are always shown, -v adds info messages, -vv debug and -vvv trace
+pub fn init(verbosity: u8, log_file: Option<&Path>) -> io::Result<()> {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let logger = Logger {
+        level,
+        file: log_file.map(File::create).transpose()?.map(Mutex::new),
+    };
+    log::set_max_level(logger.max_level());
+    // Only fails if a logger was already set, which would then keep being used
+    let _ = log::set_boxed_logger(Box::new(logger));
+    Ok(())
+}
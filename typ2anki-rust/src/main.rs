@@ -1,122 +1,183 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap},
+    io::IsTerminal,
+    path::PathBuf,
+    process::ExitCode,
     sync::{Arc, Mutex, RwLock},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use crate::{
     anki_api::get_anki_deck_name,
-    card_wrapper::{CardInfo, CardModificationStatus, TFiles, TFilesExt},
-    output::{OutputManager, OutputMessage},
+    apkg::ApkgWriter,
+    card_wrapper::{
+        CardInfo, CardModificationStatus, CardsSummary, TFiles, TFilesExt, sort_in_source_order,
+    },
+    config::Config,
+    export::{CsvWriter, PreviewWriter, SharedExporter},
+    output::{OutputCompiledCardInfo, OutputFormat, OutputManager, OutputMessage, format_location},
     output_console::OutputConsole,
+    output_json::OutputJson,
 };
 
 mod anki_api;
+mod apkg;
 mod auto_number;
+mod bench;
 mod card_wrapper;
 mod cards_cache;
 mod compile;
 mod config;
+mod diff;
+mod doctor;
+mod error;
+mod export;
+mod file_index;
 mod generator;
+mod ignore_file;
+mod image_cache;
+mod init;
+mod lockfile;
+mod logging;
+mod media;
 mod output;
 mod output_console;
+mod output_json;
 mod parse_file;
+mod stats;
 mod typst_as_library;
 mod utils;
+mod watch;
 
-fn main() -> anyhow::Result<()> {
-    let cfg = config::get();
-    let _cfg_guard = config::ConfigGuard;
-    let output = OutputConsole::new();
+fn main() -> anyhow::Result<ExitCode> {
+    // The configuration is parsed once, then passed down to everything that needs it
+    let cfg = Arc::new(config::parse_config());
+    anki_api::set_max_inflight(cfg.anki_max_inflight);
 
+    match cfg.output_format {
+        OutputFormat::Console => start(&cfg, OutputConsole::new(cfg.clone())),
+        OutputFormat::Json => start(&cfg, OutputJson::new(cfg.clone())),
+    }
+}
+
+fn start(cfg: &Config, output: impl OutputManager + 'static) -> anyhow::Result<ExitCode> {
     if cfg.auto_number_file.is_some() {
-        return auto_number::run_auto_number(output);
+        return auto_number::run_auto_number(cfg, output).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(runs) = cfg.bench {
+        return bench::run(cfg, runs, output).map(|_| ExitCode::SUCCESS);
     }
-    run(output);
-    Ok(())
+    Ok(run(cfg, output))
 }
 
-fn run(output: impl OutputManager + 'static) {
+fn run(cfg: &Config, output: impl OutputManager + 'static) -> ExitCode {
     let output = Arc::new(output);
 
-    let cfg = config::get();
+    // The first Ctrl-C lets the cards in progress finish, so that the cache can be saved with
+    // the cards already uploaded. The second one quits right away, which skips destructors:
+    // the folders of the extracted zips are removed by hand. The handler is kept after the
+    // run, so it doesn't keep the output (and the configuration in it) alive.
+    let interrupt_output = Arc::downgrade(&output);
+    let zip_dirs: Vec<PathBuf> = cfg
+        .extracted_zip_dirs
+        .iter()
+        .map(|dir| dir.path().to_path_buf())
+        .collect();
+    let _ = ctrlc::set_handler(move || {
+        if compile::cancel() {
+            for dir in &zip_dirs {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            std::process::exit(130);
+        }
+        if let Some(output) = interrupt_output.upgrade() {
+            output.send(OutputMessage::Interrupted);
+        }
+    });
 
     if cfg.dry_run {
-        output.send(OutputMessage::DbgShowConfig(cfg.clone()));
+        output.send(OutputMessage::DbgShowConfig(Box::new(cfg.clone())));
     }
-    parse_file::check_ankiconf_exists();
-    let ankiconf_hash = parse_file::get_ankiconf_hash();
+    if cfg.clear_cache
+        && let Err(e) = image_cache::clear()
+    {
+        output.send(OutputMessage::ParsingError(format!(
+            "Warning: Failed to clear the image cache: {}",
+            e
+        )));
+    }
+    parse_file::check_ankiconf_exists(cfg);
+    let ankiconf_hash = parse_file::get_ankiconf_hash(cfg);
     let mut cards_cache_manager =
-        cards_cache::CardsCacheManager::init(ankiconf_hash, output.as_ref());
-
-    // find all *.typ files inside of cfg.path, including nested
-    let typ_files = walkdir::WalkDir::new(&cfg.path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.path()
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.eq_ignore_ascii_case("typ"))
-                .unwrap_or(false)
-        })
-        .map(|e| e.path().to_path_buf())
-        .filter(|p| {
-            let s = p.file_name().unwrap_or_default().to_string_lossy();
-            !(s == "ankiconf.typ" || s.starts_with("temporal-"))
-        })
-        .collect::<Vec<std::path::PathBuf>>();
-
-    let mut i = 0;
+        cards_cache::CardsCacheManager::init(cfg, ankiconf_hash, output.as_ref());
+
+    let typ_files = parse_file::find_card_files(cfg);
 
     let mut cards: Vec<CardInfo> = Vec::new();
     let files: TFiles = Arc::new(RwLock::new(HashMap::new()));
-    let mut deck_names: HashSet<String> = HashSet::new();
+    let mut deck_names: BTreeSet<String> = BTreeSet::new();
 
     let mut files_lock = files.write().unwrap();
 
-    // parse each typ file
+    // With --only-changed, the files that didn't change since their cards were uploaded
+    // aren't even read
+    let mut file_index = cfg
+        .only_changed
+        .then(|| file_index::FileIndex::load(cfg, &cards_cache_manager.static_hash));
+    let mut skipped_files = 0;
+    let mut skipped_cards = 0;
+
+    // With --only-changed, the files to skip are known before reading any
+    let mut files_to_parse: Vec<PathBuf> = Vec::new();
     for filepath in &typ_files {
-        let p = cfg.path_relative_to_root(filepath);
-        if cfg.is_file_excluded(p.as_ref()) {
-            if cfg.dry_run {
-                output.send(OutputMessage::ParsingError(
-                    format!("Skipping excluded file: {}", p).to_string(),
-                ));
+        if let Some(index) = &mut file_index
+            && !cfg.is_file_excluded(&cfg.path_relative_to_root(filepath))
+        {
+            if let Some(count) = index.unchanged_cards(filepath) {
+                skipped_files += 1;
+                skipped_cards += count;
+                continue;
             }
-            continue;
+            index.track(filepath);
         }
-        let file;
-
-        if let Ok(content) = std::fs::read_to_string(filepath) {
-            file = match parse_file::parse_cards_from_file_content(
-                filepath,
-                content,
-                &mut cards_cache_manager,
-                output.clone(),
-                &mut i,
-                &mut deck_names,
-                &mut cards,
-            ) {
-                Ok(f) => f,
-                Err(e) => {
-                    output.send(OutputMessage::ParsingError(e));
-                    continue;
-                }
-            };
-        } else {
-            output.send(OutputMessage::ParsingError(format!(
-                "Warning: Failed to read file {:?}",
-                filepath.to_string_lossy()
-            )));
-            continue;
-        }
-        if file.total_cards == 0 {
-            continue;
-        }
-        files_lock.insert(filepath.clone(), file);
+        files_to_parse.push(filepath.clone());
     }
 
+    // parse each typ file
+    let parse_start = Instant::now();
+    files_lock.extend(parse_file::parse_typ_files(
+        cfg,
+        &files_to_parse,
+        &mut cards_cache_manager,
+        output.clone(),
+        &mut deck_names,
+        &mut cards,
+    ));
+    let parse_time = parse_start.elapsed();
+
+    if skipped_files > 0 {
+        log::info!(
+            "Skipped {} unchanged files ({} cards)",
+            skipped_files,
+            skipped_cards
+        );
+    }
+    // The cards of the skipped files count as unchanged
+    let with_skipped_cards = |mut summary: CardsSummary| {
+        summary.total += skipped_cards;
+        summary.unchanged += skipped_cards;
+        summary
+    };
+
+    if cards.is_empty() && skipped_files > 0 {
+        output.send(OutputMessage::Summary {
+            compiled_cards: 0,
+            elapsed: Duration::ZERO,
+            phases: compile::take_phase_times(parse_time),
+            cards: with_skipped_cards(CardsSummary::default()),
+        });
+        return ExitCode::SUCCESS;
+    }
     if cards.is_empty() {
         output.send(OutputMessage::ParsingError(
             "No cards found, aborting.".to_string(),
@@ -124,90 +185,210 @@ fn run(output: impl OutputManager + 'static) {
         return output.fail();
     }
 
+    sort_in_source_order(&mut cards);
+
+    // Exporting to files doesn't need Anki at all
+    let exporting = cfg.is_exporting();
+
     // check anki connection
-    if !anki_api::check_anki_running() {
+    let uses_anki = !exporting && !cfg.list && !cfg.diff;
+    if uses_anki && !anki_api::check_anki_running() {
         output.send(OutputMessage::NoAnkiConnection);
-        if !cfg.dry_run {
+        if !cfg.dry_run || cfg.stats_only {
             return output.fail();
         }
-    }
-
-    // create decks in anki
-    for deck_name in &deck_names {
-        if cfg.dry_run {
-            output.send(OutputMessage::DbgCreateDeck(deck_name.to_string()));
-        } else {
-            let _ = anki_api::create_deck(&get_anki_deck_name(deck_name));
-        }
+    } else if uses_anki
+        && cfg.prune_media
+        && let Some(reason) = media::prune_disabled_reason(&anki_api::media_dir())
+    {
+        log::warn!("{}", reason);
     }
 
     // check for duplicate card IDs
     if cfg.check_duplicates {
-        let mut seen_ids = HashSet::new();
+        // The card each ID was first seen in, to report both sides of a clash
+        let mut seen_ids: HashMap<&str, &CardInfo> = HashMap::new();
         let mut e = false;
         for card in &cards {
-            if seen_ids.contains(&card.card_id) {
+            if let Some(first) = seen_ids.get(card.card_id.as_str()) {
+                // A copy of the same card is harmless, both sides would give the same note
+                let identical = first.content_hash == card.content_hash;
                 output.send(OutputMessage::ParsingError(format!(
-                    "Warning: Duplicate card ID found: {} ({})",
+                    "Warning: Duplicate card ID found: {}{} (in {} and {})",
                     card.card_id,
-                    card.source_file.to_string_lossy()
+                    if identical {
+                        ", with the same content"
+                    } else {
+                        ", with different contents"
+                    },
+                    format_location(&first.source_file.to_string_lossy(), first.location),
+                    format_location(&card.source_file.to_string_lossy(), card.location)
                 )));
-                e = true;
+                e |= !identical;
             } else {
-                seen_ids.insert(card.card_id.clone());
+                seen_ids.insert(&card.card_id, card);
             }
         }
         if e && !cfg.dry_run {
             output.send(OutputMessage::ParsingError(
-                "Error: Duplicate card IDs found, aborting.".to_string(),
+                "Error: Duplicate card IDs with different contents found, aborting.".to_string(),
             ));
             return output.fail();
         }
     }
 
-    cards_cache_manager.detect_configuration_change(output.as_ref());
+    // --filter-id, --filter-deck and --limit: duplicates are checked among every card, but
+    // only the selected ones are processed. The others keep their cached hashes, as if they
+    // weren't found.
+    if cfg.selects_cards() {
+        let limit = cfg.limit.unwrap_or(usize::MAX);
+        let mut selected: Vec<CardInfo> = Vec::new();
+        for card in std::mem::take(&mut cards) {
+            if selected.len() < limit && cfg.is_card_selected(&card.deck_name, &card.card_id) {
+                selected.push(card);
+                continue;
+            }
+            cards_cache_manager.discard_new_card_hash(&card.deck_name, &card.card_id);
+            if let Some(stats) = files_lock.get_mut(&card.source_file) {
+                stats.total_cards -= 1;
+                stats.skipped_cards += 1;
+            }
+        }
+        cards = selected;
+        deck_names = cards.iter().map(|c| c.deck_name.clone()).collect();
+        log::info!("Only processing {} of the cards found", cards.len());
+        if cards.is_empty() {
+            log::warn!("No card matches --filter-id and --filter-deck");
+        }
+    }
+
+    // create decks in anki
+    for deck_name in deck_names
+        .iter()
+        .filter(|_| !exporting && !cfg.list && !cfg.stats_only && !cfg.diff)
+    {
+        if cfg.dry_run {
+            output.send(OutputMessage::DbgCreateDeck(deck_name.to_string()));
+        } else {
+            let _ = anki_api::create_deck(&get_anki_deck_name(cfg, deck_name));
+        }
+    }
+
+    if !cfg.stats_only && !cfg.diff {
+        cards_cache_manager.detect_configuration_change(cfg, output.as_ref());
+    }
 
     // set status for each card & assign anki deck name
     for card in &mut cards {
-        card.set_status(&cards_cache_manager);
-        card.anki_deck_name = Some(anki_api::get_anki_deck_name(&card.deck_name));
+        card.set_status(cfg, &cards_cache_manager);
+        card.anki_deck_name = Some(if exporting {
+            cfg.deck_map
+                .get(&card.deck_name)
+                .cloned()
+                .unwrap_or_else(|| card.deck_name.clone())
+        } else {
+            anki_api::get_anki_deck_name(cfg, &card.deck_name)
+        });
     }
 
     // update files stats based on card statuses
     for card in &cards {
         if let Some(file_stats) = files_lock.get_mut(&card.source_file) {
-            match card.modification_status {
-                CardModificationStatus::Unchanged => {
-                    file_stats.unchanged_cards.0 += 1;
-                }
-                CardModificationStatus::Updated => {
-                    file_stats.updated_cards.0 += 1;
-                }
-                CardModificationStatus::New => {
-                    file_stats.new_cards.0 += 1;
-                }
-                CardModificationStatus::Unknown => {}
-            }
+            file_stats.count_card_status(&card.modification_status);
         }
     }
 
     drop(files_lock);
 
+    if cfg.list {
+        output.send(OutputMessage::ListCards(
+            cards.iter().map(OutputCompiledCardInfo::from).collect(),
+        ));
+        return ExitCode::SUCCESS;
+    }
+
+    if cfg.stats_only {
+        return stats::report(cfg, &cards, output.as_ref());
+    }
+
+    if cfg.diff {
+        output.send(OutputMessage::CardsDiff(diff::cards_diff(
+            &cards,
+            &cards_cache_manager,
+        )));
+        return ExitCode::SUCCESS;
+    }
+
+    // A missing note type would otherwise make every card fail after being compiled
+    if !exporting
+        && !cfg.dry_run
+        && let Err(e) = anki_api::check_note_types(
+            cfg,
+            cards
+                .iter()
+                .filter(|c| c.modification_status != CardModificationStatus::Unchanged),
+        )
+    {
+        return output.fail_with_reason(e.to_string());
+    }
+
+    if !exporting
+        && !cfg.dry_run
+        && !confirm_upload(cfg, &cards, &cards_cache_manager, output.as_ref())
+    {
+        return output.fail_with_reason("Cancelled, nothing was uploaded".to_string());
+    }
+
     output.send(OutputMessage::ListTypstFiles(files.clone()));
 
     // Compile and upload cards concurrently
     let cards_cache_manager = Arc::new(Mutex::new(cards_cache_manager));
 
+    let mut exporters: Vec<(PathBuf, SharedExporter)> = Vec::new();
+    if let Some(path) = &cfg.export_apkg {
+        exporters.push((
+            path.clone(),
+            Arc::new(Mutex::new(ApkgWriter::new(&cfg.extra_fields))),
+        ));
+    }
+    if let Some(path) = &cfg.export_csv {
+        exporters.push((
+            path.clone(),
+            Arc::new(Mutex::new(CsvWriter::new(&cfg.extra_fields))),
+        ));
+    }
+    if let Some(dir) = &cfg.preview_dir {
+        exporters.push((dir.clone(), Arc::new(Mutex::new(PreviewWriter::new(dir)))));
+    }
+
     let now = Instant::now();
     compile::compile_cards_concurrent(
+        cfg,
         &cards,
         output.clone(),
         cards_cache_manager.clone(),
         files.clone(),
+        exporters.iter().map(|(_, e)| e.clone()).collect(),
     );
     let elapsed = now.elapsed();
 
-    let cards_cache_manager = match Arc::try_unwrap(cards_cache_manager) {
+    for (path, exporter) in &exporters {
+        match exporter.lock().unwrap().write(path) {
+            Ok(cards) => output.send(OutputMessage::ExportedFile {
+                path: path.clone(),
+                cards,
+            }),
+            Err(e) => {
+                return output.fail_with_reason(format!(
+                    "Failed to write {}: {:#}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+    }
+
+    let mut cards_cache_manager = match Arc::try_unwrap(cards_cache_manager) {
         Ok(mutex) => mutex.into_inner().unwrap(),
         Err(_) => panic!("Failed to unwrap Arc for CardsCacheManager"),
     };
@@ -221,20 +402,56 @@ fn run(output: impl OutputManager + 'static) {
         .filter(|c| c.modification_status != CardModificationStatus::Unchanged)
         .count();
 
-    println!(
-        "Compiled {} cards in {:.2?} ({:.2} cards/sec)",
-        compiled_count,
+    output.send(OutputMessage::Summary {
+        compiled_cards: compiled_count,
         elapsed,
-        compiled_count as f64 / elapsed.as_secs_f64()
-    );
+        phases: compile::take_phase_times(parse_time),
+        cards: with_skipped_cards(files.summary()),
+    });
 
-    // At the end, save the cache
+    if cfg.prune_media && !compile::is_cancelled() {
+        media::prune(
+            cfg,
+            &cards,
+            files.total_errors(),
+            &mut cards_cache_manager,
+            output.as_ref(),
+        );
+    }
+
+    // At the end, save the cache (only the local copy in a dry run)
+    cards_cache_manager.save_cache(cfg, output.as_ref());
     if !cfg.dry_run {
-        cards_cache_manager.save_cache(output.as_ref());
+        save_lockfile(cfg, output.as_ref());
+    }
+    // Cards left unprocessed by an interruption or --fail-fast have no error of their own
+    if let Some(index) = &mut file_index
+        && !cfg.dry_run
+        && !compile::is_cancelled()
+        && !(cfg.fail_fast && files.total_errors() > 0)
+    {
+        index.record(&files);
+        if let Err(e) = index.save() {
+            log::warn!("Failed to save the index of unchanged files: {}", e);
+        }
+    }
+
+    if cfg.watch && !compile::is_cancelled() {
+        return watch::watch(cfg, output, cards_cache_manager);
+    }
+
+    if compile::is_cancelled() {
+        return output.fail_with_reason("Interrupted before every card was processed".to_string());
     }
-    
-    if files.total_errors() > 0 {
-        output.fail_with_reason("There were some compilation errors".to_string());
+
+    // Failed cards make the run fail, so that scripts and CI notice them
+    let failed_cards = files.total_errors();
+    if failed_cards > 0 {
+        return output.fail_with_reason(format!(
+            "{} card{} failed to compile or upload",
+            failed_cards,
+            if failed_cards == 1 { "" } else { "s" }
+        ));
     }
 
     output.send(OutputMessage::DbgDone);
@@ -244,8 +461,124 @@ fn run(output: impl OutputManager + 'static) {
         let mut input = String::new();
         let _ = std::io::stdin().read_line(&mut input);
     }
-    
-    if files.total_errors() > 0 {
-        
+    ExitCode::SUCCESS
+}
+
+// Asks before uploading, with how many cards are added and updated, to catch mass changes
+// like a configuration change marking every card as updated. Only asked in a terminal,
+// without --yes or --non-interactive.
+fn confirm_upload(
+    cfg: &Config,
+    cards: &[CardInfo],
+    cards_cache_manager: &cards_cache::CardsCacheManager,
+    output: &impl OutputManager,
+) -> bool {
+    if cfg.yes
+        || cfg.non_interactive
+        || cfg.output_format != OutputFormat::Console
+        || !std::io::stdin().is_terminal()
+    {
+        return true;
+    }
+    let count = |status: CardModificationStatus| {
+        cards
+            .iter()
+            .filter(|c| c.modification_status == status)
+            .count()
+    };
+    let (new, updated) = (
+        count(CardModificationStatus::New),
+        count(CardModificationStatus::Updated),
+    );
+    if new + updated == 0 {
+        return true;
+    }
+    let removed: usize = diff::cards_diff(cards, cards_cache_manager)
+        .values()
+        .map(|d| d.removed.len())
+        .sum();
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let mut question = format!(
+        "{} new card{} and {} updated card{} will be uploaded",
+        new,
+        plural(new),
+        updated,
+        plural(updated)
+    );
+    if removed > 0 {
+        question.push_str(&format!(
+            ", {} card{} removed from the sources keep their notes",
+            removed,
+            plural(removed)
+        ));
+    }
+    question.push_str(". Proceed with upload?");
+    output.ask_yes_no(&question, true)
+}
+
+pub fn save_lockfile(cfg: &Config, output: &impl OutputManager) {
+    if let Err(e) = cfg.lockfile.save() {
+        output.send(OutputMessage::ParsingError(format!(
+            "Warning: Failed to write {}: {}",
+            lockfile::LOCKFILE_NAME,
+            e
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::Path};
+
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+    use crate::config::config_from_args;
+
+    // Two cards with the same ID and different contents, which --check-duplicates refuses
+    fn write_zip(path: &Path) {
+        let mut zip = ZipWriter::new(fs::File::create(path).unwrap());
+        zip.start_file("cards.typ", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(
+            br#"#card(id: "001", target-deck: "D", q: [q], a: [a])
+#card(id: "001", target-deck: "D", q: [other q], a: [a])
+"#,
+        )
+        .unwrap();
+        zip.finish().unwrap();
+    }
+
+    // Runs typ2anki on a zip (without Anki), returning the folder it was extracted to
+    fn run_zip(args: &[&str]) -> (PathBuf, ExitCode) {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("cards.zip");
+        write_zip(&zip_path);
+        let csv_path = dir.path().join("cards.csv");
+        let mut args = args.to_vec();
+        let (zip_path, csv_path) = (zip_path.to_string_lossy(), csv_path.to_string_lossy());
+        args.extend(["--no-cache", "--export-csv", &csv_path, &zip_path]);
+
+        let cfg = Arc::new(config_from_args(&args));
+        let extracted = cfg.extracted_zip_dirs[0].path().to_path_buf();
+        assert!(extracted.join("cards.typ").is_file());
+        let code = run(&cfg, OutputJson::new(cfg.clone()));
+        assert!(extracted.is_dir());
+        drop(cfg);
+        (extracted, code)
+    }
+
+    #[test]
+    fn zip_folder_is_removed_after_a_run() {
+        let (extracted, code) = run_zip(&["--list"]);
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(!extracted.exists());
+    }
+
+    #[test]
+    fn zip_folder_is_removed_after_a_failing_run() {
+        let (extracted, code) = run_zip(&["--check-duplicates"]);
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(!extracted.exists());
     }
 }
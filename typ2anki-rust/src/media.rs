@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    anki_api,
+    card_wrapper::{CardInfo, CardModificationStatus, deck_media_prefix},
+    cards_cache::{CardsCacheManager, card_key},
+    config::Config,
+    error,
+    output::{OutputManager, OutputMessage},
+    parse_file,
+};
+
+// Anki never deletes media by itself: the images of the cards removed from the project, and
+// the previous versions of the images with --hash-media-names, stay in its media folder.
+// Only the images of the decks found in this run are considered, so that other projects
+// and excluded decks keep theirs.
+fn unused_media(cfg: &Config, cards: &[CardInfo], dir: &Path) -> Result<BTreeSet<String>, String> {
+    let prefixes: HashSet<String> = cards
+        .iter()
+        .map(|c| format!("{}-", deck_media_prefix(&c.deck_name)))
+        .collect();
+    let referenced: HashSet<String> = cards
+        .iter()
+        .flat_map(|c| (1..=cfg.page_count()).map(|page| c.image_path(cfg, page)))
+        .collect();
+
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("failed to list {}: {}", dir.display(), e))?;
+    Ok(entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| prefixes.iter().any(|p| name.starts_with(p)))
+        .filter(|name| !referenced.contains(name))
+        .collect())
+}
+
+// Why the images of the cards can't be told apart from unused ones in this run, if they can't
+fn cannot_prune(
+    cfg: &Config,
+    cards: &[CardInfo],
+    failed_cards: usize,
+    cards_cache_manager: &CardsCacheManager,
+) -> Option<&'static str> {
+    if cfg.legacy_media_names {
+        return Some("images named without a hash of the deck can't be attributed to a project");
+    }
+    if !cfg.include_files.is_empty() || !cfg.exclude_files.is_empty() {
+        return Some("the cards of the files that were filtered out would lose their images");
+    }
+    if parse_file::has_ignored_card_files(cfg) {
+        return Some("the cards of the files left out by .typ2anki-ignore would lose their images");
+    }
+    if failed_cards > 0 {
+        return Some("the cards that failed still show their previous images");
+    }
+    // Cards kept after a configuration change without being recompiled may still show images
+    // named after the previous configuration (its image format, --hash-media-names...)
+    let outdated = cards.iter().any(|c| {
+        c.modification_status == CardModificationStatus::Unchanged
+            && cards_cache_manager
+                .old_cache
+                .get(&card_key(&c.deck_name, &c.card_id))
+                .is_some_and(|e| e.config_hash != cards_cache_manager.static_hash)
+    });
+    if outdated {
+        return Some("some cards weren't recompiled after a configuration change");
+    }
+    None
+}
+
+// Why --prune-media can't be used with this media folder, reported before compiling. Only
+// pruning needs the folder, the images are uploaded with storeMediaFile all the same.
+pub fn prune_disabled_reason(media_dir: &error::Result<PathBuf>) -> Option<String> {
+    media_dir
+        .as_ref()
+        .err()
+        .map(|e| format!("--prune-media is disabled: {}", e))
+}
+
+// --prune-media: deletes the images of the decks of this run that no card uses anymore
+pub fn prune(
+    cfg: &Config,
+    cards: &[CardInfo],
+    failed_cards: usize,
+    cards_cache_manager: &mut CardsCacheManager,
+    output: &impl OutputManager,
+) {
+    prune_in(
+        anki_api::media_dir(),
+        cfg,
+        cards,
+        failed_cards,
+        cards_cache_manager,
+        output,
+    );
+}
+
+fn prune_in(
+    media_dir: error::Result<PathBuf>,
+    cfg: &Config,
+    cards: &[CardInfo],
+    failed_cards: usize,
+    cards_cache_manager: &mut CardsCacheManager,
+    output: &impl OutputManager,
+) {
+    // Already reported before compiling
+    let Ok(media_dir) = media_dir else {
+        return;
+    };
+    if let Some(reason) = cannot_prune(cfg, cards, failed_cards, cards_cache_manager) {
+        log::warn!("Not pruning the media folder: {}", reason);
+        return;
+    }
+    let unused = match unused_media(cfg, cards, &media_dir) {
+        Ok(unused) => unused,
+        Err(e) => {
+            log::warn!("Not pruning the media folder: {}", e);
+            return;
+        }
+    };
+    output.send(OutputMessage::UnusedMedia(unused.iter().cloned().collect()));
+    if unused.is_empty() || cfg.dry_run {
+        return;
+    }
+    if !output.ask_yes_no(
+        &format!(
+            "Delete these {} images from Anki's media folder?",
+            unused.len()
+        ),
+        false,
+    ) {
+        return;
+    }
+
+    let mut deleted = 0;
+    for name in &unused {
+        match anki_api::delete_media_file(name) {
+            Ok(()) => deleted += 1,
+            Err(e) => log::warn!("Failed to delete {}: {}", name, e),
+        }
+    }
+    output.send(OutputMessage::PrunedMedia { deleted });
+
+    let decks: HashSet<&str> = cards.iter().map(|c| c.deck_name.as_str()).collect();
+    let live: HashSet<String> = cards
+        .iter()
+        .map(|c| card_key(&c.deck_name, &c.card_id))
+        .collect();
+    cards_cache_manager.forget_missing_cards(&decks, &live);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, process::ExitCode, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        cards_cache::CachedCard, config::config_from_args, error::Typ2AnkiError,
+        parse_file::DocumentDefaults,
+    };
+
+    // Records the unused images it is told about, and refuses to delete them
+    #[derive(Default)]
+    struct Recorder {
+        unused: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl OutputManager for Recorder {
+        fn send(&self, msg: OutputMessage) {
+            if let OutputMessage::UnusedMedia(files) = msg {
+                self.unused.lock().unwrap().push(files);
+            }
+        }
+        fn ask_yes_no(&self, _: &str, _: bool) -> bool {
+            false
+        }
+        fn fail(&self) -> ExitCode {
+            ExitCode::FAILURE
+        }
+        fn fail_with_reason(&self, _: String) -> ExitCode {
+            ExitCode::FAILURE
+        }
+    }
+
+    fn cache_manager() -> CardsCacheManager {
+        CardsCacheManager {
+            static_hash: String::new(),
+            old_cache: HashMap::new(),
+            new_cache: HashMap::new(),
+        }
+    }
+
+    fn card(id: &str) -> CardInfo {
+        CardInfo::from_string(
+            &format!(r#"#card(id: "{}", target-deck: "D", q: [q], a: [a])"#, id),
+            "cards.typ".into(),
+            (0, 0),
+            &DocumentDefaults::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn disabled_without_get_media_dir_path() {
+        let missing_action = Err(Typ2AnkiError::Anki("unsupported action".to_string()));
+        assert_eq!(
+            prune_disabled_reason(&anki_api::resolve_media_dir(missing_action)).as_deref(),
+            Some(
+                "--prune-media is disabled: this version of AnkiConnect can't tell where Anki's media folder is, update the add-on to use it"
+            )
+        );
+        let unreachable = Err(Typ2AnkiError::Network("connection refused".to_string()));
+        assert_eq!(
+            prune_disabled_reason(&anki_api::resolve_media_dir(unreachable)).as_deref(),
+            Some(
+                "--prune-media is disabled: Anki's media folder couldn't be found: connection refused"
+            )
+        );
+    }
+
+    #[test]
+    fn disabled_without_media_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("collection.media");
+        let media_dir = anki_api::resolve_media_dir(Ok(missing.to_string_lossy().into_owned()));
+        let reason = prune_disabled_reason(&media_dir).unwrap();
+        assert!(
+            reason.starts_with("--prune-media is disabled: Anki's media folder"),
+            "{}",
+            reason
+        );
+
+        let cfg = config_from_args(&["--prune-media", &dir.path().to_string_lossy()]);
+        let output = Recorder::default();
+        prune_in(
+            media_dir,
+            &cfg,
+            &[card("001")],
+            0,
+            &mut cache_manager(),
+            &output,
+        );
+        assert!(output.unused.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_unused_media() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config_from_args(&["--prune-media", &dir.path().to_string_lossy()]);
+        let cards = [card("001")];
+        let used = cards[0].image_path(&cfg, 1);
+        let unused = format!("{}-002-1.png", deck_media_prefix("D"));
+        let other_deck = format!("{}-002-1.png", deck_media_prefix("Other"));
+        for name in [&used, &unused, &other_deck] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let media_dir = anki_api::resolve_media_dir(Ok(dir.path().to_string_lossy().into_owned()));
+        assert_eq!(prune_disabled_reason(&media_dir), None);
+        let output = Recorder::default();
+        prune_in(media_dir, &cfg, &cards, 0, &mut cache_manager(), &output);
+        assert_eq!(*output.unused.lock().unwrap(), [[unused]]);
+        assert!(dir.path().join(&used).exists());
+    }
+
+    #[test]
+    fn keeps_images_of_cards_not_recompiled_after_renaming() {
+        for (before, after) in [
+            (&[][..], &["--hash-media-names"][..]),
+            (&["--hash-media-names"], &[]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().to_string_lossy();
+            let old_cfg = config_from_args(&[before, &[&path]].concat());
+            let cfg = config_from_args(&[after, &["--prune-media", &path]].concat());
+            assert_ne!(old_cfg.config_hash, cfg.config_hash);
+
+            // The card was uploaded with the previous names, and isn't recompiled
+            let mut card = card("001");
+            for page in 1..=cfg.page_count() {
+                fs::write(dir.path().join(card.image_path(&old_cfg, page)), "").unwrap();
+            }
+            let mut manager = cache_manager();
+            manager.static_hash = cfg.config_hash.clone().unwrap();
+            manager.old_cache.insert(
+                card_key(&card.deck_name, &card.card_id),
+                CachedCard {
+                    config_hash: old_cfg.config_hash.clone().unwrap(),
+                    content_hash: card.content_hash.clone(),
+                },
+            );
+            *cfg.recompile_on_config_change.write().unwrap() = Some(false);
+            card.set_status(&cfg, &manager);
+            assert_eq!(card.modification_status, CardModificationStatus::Unchanged);
+
+            let output = Recorder::default();
+            let media_dir = anki_api::resolve_media_dir(Ok(path.to_string()));
+            prune_in(media_dir, &cfg, &[card], 0, &mut manager, &output);
+            assert!(output.unused.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn keeps_images_of_ignored_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = config_from_args(&["--prune-media", &dir.path().to_string_lossy()]);
+        fs::write(dir.path().join(".typ2anki-ignore"), "old/\n").unwrap();
+        fs::create_dir(dir.path().join("old")).unwrap();
+        fs::write(dir.path().join("old/cards.typ"), "").unwrap();
+        assert!(parse_file::has_ignored_card_files(&cfg));
+
+        // The image of a card of the ignored file, in the same deck
+        let cards = [card("001")];
+        let ignored = format!("{}-002-1.png", deck_media_prefix("D"));
+        fs::write(dir.path().join(&ignored), "").unwrap();
+
+        let output = Recorder::default();
+        let media_dir = anki_api::resolve_media_dir(Ok(dir.path().to_string_lossy().into_owned()));
+        prune_in(media_dir, &cfg, &cards, 0, &mut cache_manager(), &output);
+        assert!(output.unused.lock().unwrap().is_empty());
+    }
+}
@@ -1,22 +1,102 @@
+use std::{
+    collections::BTreeMap, io::IsTerminal, path::PathBuf, process::ExitCode, time::Duration,
+};
+
+use clap::ValueEnum;
+
 use crate::{
-    card_wrapper::{CardInfo, CardModificationStatus, TFiles},
+    card_wrapper::{CardInfo, CardModificationStatus, CardsSummary, TFiles},
+    compile::PhaseTimes,
     config,
+    diff::DeckDiff,
+    error::Typ2AnkiError,
+    stats::CollectionReport,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Progress bars and human readable messages
+    Console,
+    /// One JSON object per line, for scripts and CI
+    Json,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Console => "console",
+            OutputFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Colors when writing to a terminal, unless NO_COLOR is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }
+    }
+
+    // Whether the output should have ANSI colors. See https://no-color.org for NO_COLOR.
+    pub fn enabled(&self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
 pub struct OutputCompiledCardInfo {
     pub file: String,
+    pub deck: String,
     pub card_id: String,
     pub card_status: CardModificationStatus,
     pub error_message: Option<String>,
+    // What kind of error `error_message` is, when the card failed
+    pub error_kind: Option<&'static str>,
+    // 1-based line and column of the card in the file
+    pub location: Option<(usize, usize)>,
+}
+
+// "file:line:col" when the location is known, like compilers print it
+pub fn format_location(file: &str, location: Option<(usize, usize)>) -> String {
+    match location {
+        Some((line, column)) => format!("{}:{}:{}", file, line, column),
+        None => file.to_string(),
+    }
 }
 
 impl OutputCompiledCardInfo {
     pub fn build(card: &CardInfo, error_message: Option<String>) -> Self {
         OutputCompiledCardInfo {
             file: card.source_file.to_string_lossy().into_owned(),
+            deck: card.deck_name.clone(),
             card_id: card.card_id.clone(),
             card_status: card.modification_status.clone(),
             error_message,
+            error_kind: None,
+            location: card.location,
+        }
+    }
+
+    pub fn from_error(card: &CardInfo, e: Typ2AnkiError) -> Self {
+        OutputCompiledCardInfo {
+            error_kind: Some(e.kind()),
+            ..Self::build(card, Some(e.to_string()))
         }
     }
 }
@@ -30,7 +110,9 @@ impl From<&CardInfo> for OutputCompiledCardInfo {
 
 pub enum OutputMessage {
     ListTypstFiles(TFiles),
-    DbgShowConfig(config::Config),
+    // Every card found, for --list
+    ListCards(Vec<OutputCompiledCardInfo>),
+    DbgShowConfig(Box<config::Config>),
     DbgConfigChangeDetection {
         total_cards: usize,
         config_changes: usize,
@@ -44,18 +126,57 @@ pub enum OutputMessage {
     ParsingError(String),
     SkipCompileCard(OutputCompiledCardInfo),
     CompileError(OutputCompiledCardInfo),
+    // A card compiled, but with warnings (the rendered warnings are in error_message)
+    CompileWarning(OutputCompiledCardInfo),
     PushError(OutputCompiledCardInfo),
     CompiledCard(OutputCompiledCardInfo),
     PushedCard(OutputCompiledCardInfo),
     NoAnkiConnection,
     ErrorSavingCache(String),
-    TypstDownloadingPackage(String),
+    // The version of the cache found, which this typ2anki can't read
+    UnsupportedCacheVersion(String),
+    // --stats-only
+    CollectionReport(CollectionReport),
+    // --diff, by deck
+    CardsDiff(BTreeMap<String, DeckDiff>),
+    // The images of --prune-media that no card uses, deleted after confirmation
+    UnusedMedia(Vec<String>),
+    PrunedMedia {
+        deleted: usize,
+    },
+    // The package's spec, like `@preview/cetz`, and its version
+    TypstDownloadingPackage {
+        package: String,
+        version: String,
+    },
+    ExportedFile {
+        path: PathBuf,
+        cards: usize,
+    },
+    Summary {
+        compiled_cards: usize,
+        elapsed: Duration,
+        phases: PhaseTimes,
+        cards: CardsSummary,
+    },
+    // --bench: the times of each run over the synthetic deck
+    BenchReport {
+        cards: usize,
+        runs: Vec<(Duration, PhaseTimes)>,
+    },
+    // Ctrl-C was pressed, the cards being processed are finished before stopping
+    Interrupted,
+    WatchingForChanges,
+    // Files (relative to the project root) that will be recompiled in watch mode
+    WatchFilesChanged(Vec<String>),
     Fail(Option<String>),
 }
 
 pub trait OutputManager: Send + Sync {
     fn send(&self, msg: OutputMessage);
     fn ask_yes_no(&self, question: &str, default_answer: bool) -> bool;
-    fn fail(&self);
-    fn fail_with_reason(&self, reason: String);
+    // Report that the run failed, and return the exit code it ends with. The process isn't
+    // exited here, so that what the run holds (like the folders of extracted zips) is dropped.
+    fn fail(&self) -> ExitCode;
+    fn fail_with_reason(&self, reason: String) -> ExitCode;
 }
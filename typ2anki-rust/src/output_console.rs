@@ -1,20 +1,27 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{self, Write},
     path::PathBuf,
+    process::ExitCode,
     sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 use crate::{
     card_wrapper::{TFiles, TypFileStats},
-    config,
+    compile::PhaseTimes,
+    config::Config,
+    diff::DeckDiff,
     output::*,
+    stats::{CollectionReport, DeckReport},
     utils,
 };
 
+use colored::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 pub struct OutputConsole {
+    cfg: Arc<Config>,
     multi: Arc<MultiProgress>,
     bars: Arc<Mutex<HashMap<String, ProgressBar>>>,
     bars_visible: Arc<Mutex<bool>>,
@@ -22,10 +29,22 @@ pub struct OutputConsole {
 }
 
 const PROGRESS_BAR_LENGTH: u64 = 40;
+// How many files are listed after compiling, the slowest first
+const SLOWEST_FILES_SHOWN: usize = 5;
+
+// The compile and upload times add up the time of every thread, so they are told apart from
+// the run's time
+fn format_phases(phases: &PhaseTimes) -> String {
+    format!(
+        "Parsing: {:.2?}, compiling: {:.2?}, uploading: {:.2?} (added up over the threads)",
+        phases.parse, phases.compile, phases.upload
+    )
+}
 
 impl OutputConsole {
-    pub fn new() -> Self {
+    pub fn new(cfg: Arc<Config>) -> Self {
         Self {
+            cfg,
             multi: Arc::new(MultiProgress::new()),
             bars: Arc::new(Mutex::new(HashMap::new())),
             bars_visible: Arc::new(Mutex::new(false)),
@@ -45,7 +64,7 @@ impl OutputConsole {
 
         let mut bars = self.bars.lock().unwrap();
 
-        let cfg = config::get();
+        let cfg = &self.cfg;
 
         let files_sorted: Vec<(&PathBuf, &TypFileStats)> = {
             let mut v: Vec<(&PathBuf, &TypFileStats)> = files.iter().collect();
@@ -55,31 +74,31 @@ impl OutputConsole {
 
         let filenames: Vec<String> = files_sorted
             .iter()
-            .map(|(path, _)| {
-                path.strip_prefix(&cfg.path)
-                    .unwrap_or(path)
-                    .to_string_lossy()
-                    .into_owned()
-            })
+            .map(|(path, _)| cfg.path_relative_to_root(path))
             .collect();
 
         let longest_path = filenames.iter().map(|p| p.len()).max().unwrap_or(20) as u64;
+        // Unchanged cards are skipped right away, so they would skew the rate and the ETA:
+        // the bars only count the cards that are compiled
         let longest_count = files
             .values()
-            .map(|stats| stats.total_cards)
+            .map(|stats| stats.cards_to_compile())
             .max()
             .unwrap_or(0) as u64;
         let longest_count = longest_count.to_string().len() as u64;
 
         // Create a total progress bar
         {
-            let total_cards: u64 = files.values().map(|s| s.total_cards as u64).sum();
-            let longest_pos = total_cards.to_string().len() as u64;
+            let to_compile: u64 = files.values().map(|s| s.cards_to_compile() as u64).sum();
+            let unchanged: usize = files.values().map(|s| s.unchanged_cards.0).sum();
+            let longest_pos = to_compile.to_string().len() as u64;
 
-            let pb = self.multi.add(ProgressBar::new(total_cards));
+            let pb = self.multi.add(ProgressBar::new(to_compile));
             let format = format!(
-                "{{prefix}} [{{wide_bar:.red/green}}] {{pos:>{}}}/{{len:<{}}} {{per_sec:<2}} ETA: {{eta}}",
-                longest_pos, longest_pos
+                "{{prefix}} [{{wide_bar{}}}] {{pos:>{}}}/{{len:<{}}} {{per_sec:<2}} ETA: {{eta}} {{msg}}",
+                if cfg.color { ":.red/green" } else { "" },
+                longest_pos,
+                longest_pos
             );
             pb.set_style(
                 ProgressStyle::with_template(format.as_str())
@@ -87,6 +106,9 @@ impl OutputConsole {
                     .progress_chars("##-"),
             );
             pb.set_prefix("All:");
+            if unchanged > 0 {
+                pb.set_message(format!("(+{} unchanged)", unchanged));
+            }
             bars.insert("all".to_string(), pb);
         }
 
@@ -95,8 +117,12 @@ impl OutputConsole {
                 filename,
                 longest_path + 1,
                 longest_count,
-                stats.total_cards as u64,
+                stats.cards_to_compile() as u64,
             );
+            // Nothing to wait for in a file whose cards are all unchanged
+            if stats.cards_to_compile() == 0 {
+                pb.finish_with_message(stats.stats_colored());
+            }
             bars.insert(path.to_string_lossy().to_string(), pb);
         }
     }
@@ -110,8 +136,12 @@ impl OutputConsole {
     ) -> ProgressBar {
         let pb = self.multi.add(ProgressBar::new(len));
         let format = format!(
-            "{{prefix:{}}} [{{bar:{}.cyan/blue}}] {{pos:>{}}}/{{len:<{}}} {{msg}}",
-            prefix_length, PROGRESS_BAR_LENGTH, longest_pos, longest_pos
+            "{{prefix:{}}} [{{bar:{}{}}}] {{pos:>{}}}/{{len:<{}}} {{msg}}",
+            prefix_length,
+            PROGRESS_BAR_LENGTH,
+            if self.cfg.color { ".cyan/blue" } else { "" },
+            longest_pos,
+            longest_pos
         );
         pb.set_style(
             ProgressStyle::with_template(format.as_str())
@@ -159,6 +189,119 @@ impl OutputConsole {
         self.println("=".repeat(width));
     }
 
+    // One line per card, in columns: deck, ID, status and location
+    fn print_card_list(&self, cards: Vec<OutputCompiledCardInfo>) {
+        let cfg = &self.cfg;
+        let rows: Vec<[String; 4]> = cards
+            .into_iter()
+            .map(|c| {
+                let file = cfg.path_relative_to_root(&PathBuf::from(&c.file));
+                [
+                    c.deck,
+                    c.card_id,
+                    format!("{:?}", c.card_status),
+                    format_location(&file, c.location),
+                ]
+            })
+            .collect();
+        let header = ["DECK", "ID", "STATUS", "FILE"].map(String::from);
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+                .collect::<Vec<_>>()
+                .join("  ");
+            self.println(line.trim_end().to_string());
+        }
+        self.println(format!(
+            "{} card{}",
+            rows.len(),
+            if rows.len() == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn print_collection_report(&self, report: CollectionReport) {
+        let total = report.total();
+        let row = |deck: &str, r: &DeckReport| {
+            [
+                deck.to_string(),
+                r.cards.to_string(),
+                r.new.to_string(),
+                r.existing.to_string(),
+                r.orphans.to_string(),
+            ]
+        };
+        let mut rows: Vec<[String; 5]> = report.decks.iter().map(|(d, r)| row(d, r)).collect();
+        rows.push(row("Total", &total));
+        let header = ["DECK", "CARDS", "NEW", "IN ANKI", "ORPHANS"].map(String::from);
+        let mut widths = [0; 5];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let line = row
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(i, (cell, w))| match i {
+                    0 => format!("{:<w$}", cell, w = w),
+                    _ => format!("{:>w$}", cell, w = w),
+                })
+                .collect::<Vec<_>>()
+                .join("  ");
+            self.println(line.trim_end().to_string());
+        }
+        if !report.orphans.is_empty() {
+            self.println(
+                "\nNotes made by typ2anki whose card isn't in the sources anymore:".to_string(),
+            );
+            for orphan in &report.orphans {
+                self.println(format!(
+                    "  {} in {} (tags: {})",
+                    orphan.note_id,
+                    orphan.deck,
+                    orphan.tags.join(" ")
+                ));
+            }
+        }
+    }
+
+    fn print_cards_diff(&self, diff: BTreeMap<String, DeckDiff>) {
+        if diff.is_empty() {
+            self.println("No card changed since the last upload.".to_string());
+            return;
+        }
+        let (mut added, mut modified, mut removed) = (0, 0, 0);
+        for (deck, changes) in &diff {
+            self.println(format!("{}", deck.bold()));
+            for id in &changes.added {
+                self.println(format!("  {} {}", "+".green(), id));
+            }
+            for id in &changes.modified {
+                self.println(format!("  {} {}", "~".yellow(), id));
+            }
+            for id in &changes.removed {
+                self.println(format!("  {} {}", "-".red(), id));
+            }
+            added += changes.added.len();
+            modified += changes.modified.len();
+            removed += changes.removed.len();
+        }
+        self.println(format!(
+            "{} added, {} modified, {} removed",
+            added, modified, removed
+        ));
+    }
+
     fn finish_all_bars(&self, files: TFiles) {
         let bars = self.bars.lock().unwrap();
         let files = files.read().unwrap();
@@ -181,31 +324,66 @@ impl OutputConsole {
             *visible = false;
         }
     }
+
+    // The files whose cards took the longest to compile, to find the slow ones
+    fn print_slowest_files(&self, files: &TFiles) {
+        let files = files.read().unwrap();
+        let mut slowest: Vec<(&PathBuf, &TypFileStats)> = files
+            .iter()
+            .filter(|(_, stats)| !stats.compile_time.is_zero())
+            .collect();
+        if slowest.is_empty() {
+            return;
+        }
+        slowest.sort_by(|a, b| b.1.compile_time.cmp(&a.1.compile_time).then(a.0.cmp(b.0)));
+        self.println("Slowest files to compile:".to_string());
+        for (path, stats) in slowest.into_iter().take(SLOWEST_FILES_SHOWN) {
+            self.println(format!(
+                "  {:>10.2?}  {} ({} card{})",
+                stats.compile_time,
+                self.cfg.path_relative_to_root(path),
+                stats.cards_to_compile(),
+                if stats.cards_to_compile() == 1 {
+                    ""
+                } else {
+                    "s"
+                }
+            ));
+        }
+    }
 }
 
 impl OutputManager for OutputConsole {
-    fn ask_yes_no(&self, _question: &str, _: bool) -> bool {
+    fn ask_yes_no(&self, question: &str, default_answer: bool) -> bool {
+        if self.cfg.non_interactive {
+            return default_answer;
+        }
+        let hint = if default_answer { "[Y/n]" } else { "[y/N]" };
         loop {
-            print!("{} [Y/n]: ", _question);
+            print!("{} {}: ", question, hint);
             let _ = io::stdout().flush();
             let mut input = String::new();
-            if io::stdin().read_line(&mut input).is_err() {
-                return false;
+            // A closed stdin (EOF) is answered like an empty line
+            if matches!(io::stdin().read_line(&mut input), Ok(0) | Err(_)) {
+                return default_answer;
             }
             match input.trim().to_lowercase().as_str() {
-                "y" | "yes" | "" => return true,
+                "" => return default_answer,
+                "y" | "yes" => return true,
                 "n" | "no" => return false,
                 _ => println!("Please answer 'y' or 'n'."),
             }
         }
     }
 
-    fn fail(&self) {
+    fn fail(&self) -> ExitCode {
         self.send(OutputMessage::Fail(None));
+        ExitCode::FAILURE
     }
-    
-    fn fail_with_reason(&self, reason: String) {
+
+    fn fail_with_reason(&self, reason: String) -> ExitCode {
         self.send(OutputMessage::Fail(Some(reason)));
+        ExitCode::FAILURE
     }
 
     fn send(&self, msg: OutputMessage) {
@@ -214,8 +392,15 @@ impl OutputManager for OutputConsole {
                 self.print_separator();
                 self.create_progress_bars(files);
             }
+            OutputMessage::ListCards(cards) => {
+                self.print_card_list(cards);
+            }
             OutputMessage::DbgShowConfig(cfg) => {
                 println!("Current Configuration: {:#?}", cfg);
+                match &cfg.config_file {
+                    Some(p) => println!("Loaded config file: {}", p.display()),
+                    None => println!("No config file loaded"),
+                }
             }
             OutputMessage::DbgConfigChangeDetection {
                 total_cards,
@@ -249,12 +434,37 @@ impl OutputManager for OutputConsole {
             OutputMessage::ErrorSavingCache(e) => {
                 eprintln!("Error saving cards cache: {}", e);
             }
-            OutputMessage::SkipCompileCard(OutputCompiledCardInfo {
-                file: relative_file,
-                ..
-            }) => {
-                self.progress_on_bar(&relative_file, 1);
+            OutputMessage::UnsupportedCacheVersion(version) => {
+                eprintln!(
+                    "The cards cache has an unsupported version ({}), probably from a newer typ2anki. Ignoring it: every card will be compiled again.",
+                    version
+                );
+            }
+            OutputMessage::CollectionReport(report) => {
+                self.print_collection_report(report);
+            }
+            OutputMessage::CardsDiff(diff) => {
+                self.print_cards_diff(diff);
+            }
+            OutputMessage::UnusedMedia(files) => {
+                if files.is_empty() {
+                    println!("No unused images in Anki's media folder.");
+                } else {
+                    println!("Images in Anki's media folder that no card uses:");
+                    for file in files {
+                        println!("  {}", file);
+                    }
+                }
+            }
+            OutputMessage::PrunedMedia { deleted } => {
+                println!(
+                    "Deleted {} image{} from Anki's media folder.",
+                    deleted,
+                    if deleted == 1 { "" } else { "s" }
+                );
             }
+            // Unchanged cards aren't counted by the progress bars
+            OutputMessage::SkipCompileCard(OutputCompiledCardInfo { .. }) => {}
             OutputMessage::CompiledCard(OutputCompiledCardInfo { .. }) => {}
             OutputMessage::PushedCard(OutputCompiledCardInfo {
                 file: relative_file,
@@ -267,42 +477,140 @@ impl OutputManager for OutputConsole {
                 file: relative_file,
                 card_status,
                 error_message,
+                location,
+                ..
             }) => {
                 self.println(format!(
                     "Error compiling card ID {} from file {} with status {:?}: {}",
                     card_id,
-                    relative_file,
+                    format_location(&relative_file, location),
                     card_status,
                     error_message.unwrap_or("Unknown error".to_string())
                 ));
                 self.progress_on_bar(&relative_file, 1);
             }
+            OutputMessage::CompileWarning(OutputCompiledCardInfo {
+                card_id,
+                file: relative_file,
+                error_message,
+                location,
+                ..
+            }) => {
+                self.println(format!(
+                    "Warnings compiling card ID {} from file {}:\n{}",
+                    card_id,
+                    format_location(&relative_file, location),
+                    error_message.unwrap_or_default()
+                ));
+            }
             OutputMessage::PushError(OutputCompiledCardInfo {
                 card_id,
                 file: relative_file,
                 card_status,
                 error_message,
+                location,
+                ..
             }) => {
                 self.println(format!(
                     "Error pushing card to anki: ID {} from file {} with status {:?}: {}",
                     card_id,
-                    relative_file,
+                    format_location(&relative_file, location),
                     card_status,
                     error_message.unwrap_or("Unknown error".to_string())
                 ));
                 self.progress_on_bar(&relative_file, 1);
             }
             OutputMessage::DbgCompilationDone { files } => {
-                self.finish_all_bars(files);
+                self.finish_all_bars(files.clone());
                 self.println("".to_string());
+                self.print_slowest_files(&files);
                 self.print_separator();
             }
-            OutputMessage::TypstDownloadingPackage(pkg) => {
-                self.println(format!("Downloading Typst package: {}", pkg));
+            OutputMessage::TypstDownloadingPackage { package, version } => {
+                // Printed above the bars, which would otherwise be drawn over
+                self.println(format!(
+                    "Downloading Typst package: {}:{}",
+                    package, version
+                ));
+            }
+            OutputMessage::ExportedFile { path, cards } => {
+                self.println(format!("Exported {} cards to {}", cards, path.display()));
+            }
+            OutputMessage::Summary {
+                compiled_cards,
+                elapsed,
+                phases,
+                cards,
+            } => {
+                self.println(format!(
+                    "Compiled {} cards in {:.2?} ({:.2} cards/sec)",
+                    compiled_cards,
+                    elapsed,
+                    compiled_cards as f64 / elapsed.as_secs_f64()
+                ));
+                self.println(format_phases(&phases));
+                let mut breakdown = format!(
+                    "{} new, {} updated, {} unchanged, {} skipped, {} failed",
+                    cards.new, cards.updated, cards.unchanged, cards.skipped, cards.errors
+                );
+                if cards.empty > 0 {
+                    breakdown.push_str(&format!(", {} empty", cards.empty));
+                }
+                if cards.media_bytes > 0 {
+                    breakdown.push_str(&format!(
+                        "; {} of images uploaded",
+                        utils::format_bytes(cards.media_bytes)
+                    ));
+                }
+                self.println(breakdown);
+            }
+            OutputMessage::BenchReport { cards, runs } => {
+                for (i, (elapsed, phases)) in runs.iter().enumerate() {
+                    self.println(format!(
+                        "Run {}: {} cards in {:.2?} ({:.2} cards/sec). {}",
+                        i + 1,
+                        cards,
+                        elapsed,
+                        cards as f64 / elapsed.as_secs_f64(),
+                        format_phases(phases)
+                    ));
+                }
+                let n = runs.len() as u32;
+                let elapsed = runs.iter().map(|(e, _)| *e).sum::<Duration>() / n;
+                let average = PhaseTimes {
+                    parse: runs.iter().map(|(_, p)| p.parse).sum::<Duration>() / n,
+                    compile: runs.iter().map(|(_, p)| p.compile).sum::<Duration>() / n,
+                    upload: runs.iter().map(|(_, p)| p.upload).sum::<Duration>() / n,
+                };
+                self.println(format!(
+                    "Average of {} runs: {:.2?} ({:.2} cards/sec). {}",
+                    n,
+                    elapsed,
+                    cards as f64 / elapsed.as_secs_f64(),
+                    format_phases(&average)
+                ));
+            }
+            OutputMessage::Interrupted => {
+                self.println(
+                    "Interrupted: finishing the cards in progress and saving the cache (press Ctrl+C again to quit now)..."
+                        .to_string(),
+                );
+            }
+            OutputMessage::WatchingForChanges => {
+                let cfg = &self.cfg;
+                let paths: Vec<String> =
+                    cfg.paths.iter().map(|p| p.display().to_string()).collect();
+                self.println(format!(
+                    "Watching for changes in {} (press Ctrl+C to stop)...",
+                    paths.join(", ")
+                ));
+            }
+            OutputMessage::WatchFilesChanged(files) => {
+                self.println(format!("Changed: {}", files.join(", ")));
             }
             OutputMessage::DbgDone => {}
             OutputMessage::Fail(reason) => {
-                let cfg = config::get();
+                let cfg = &self.cfg;
                 if let Some(r) = reason {
                     println!("Fail reason: {}", r);
                 }
@@ -311,7 +619,6 @@ impl OutputManager for OutputConsole {
                     let mut input = String::new();
                     let _ = std::io::stdin().read_line(&mut input);
                 }
-                std::process::exit(1);
             }
         }
     }
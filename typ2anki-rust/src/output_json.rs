@@ -0,0 +1,292 @@
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, RwLock},
+};
+
+use serde_json::{Value, json};
+
+use crate::{
+    card_wrapper::TFiles, compile::PhaseTimes, config::Config, output::*, stats::DeckReport,
+};
+
+/// Writes one JSON object per line to stdout for every message, so that typ2anki
+/// can be driven from scripts and CI pipelines.
+pub struct OutputJson {
+    cfg: Arc<Config>,
+    files: RwLock<Option<TFiles>>,
+}
+
+fn phases_json(phases: &PhaseTimes) -> Value {
+    json!({
+        "parse_secs": phases.parse.as_secs_f64(),
+        "compile_secs": phases.compile.as_secs_f64(),
+        "upload_secs": phases.upload.as_secs_f64(),
+    })
+}
+
+impl OutputJson {
+    pub fn new(cfg: Arc<Config>) -> Self {
+        Self {
+            cfg,
+            files: RwLock::new(None),
+        }
+    }
+
+    fn emit(&self, v: Value) {
+        println!("{}", v);
+    }
+
+    fn card_json(&self, kind: &str, info: OutputCompiledCardInfo) -> Value {
+        let cfg = &self.cfg;
+        json!({
+            "type": kind,
+            "card_id": info.card_id,
+            "file": cfg.path_relative_to_root(&PathBuf::from(&info.file)),
+            "status": format!("{:?}", info.card_status),
+            "error": info.error_message,
+            "error_kind": info.error_kind,
+            "line": info.location.map(|(line, _)| line),
+            "column": info.location.map(|(_, column)| column),
+        })
+    }
+
+    fn files_json(&self, files: &TFiles) -> Value {
+        let cfg = &self.cfg;
+        let files = files.read().unwrap();
+        let mut v: Vec<Value> = files
+            .iter()
+            .map(|(path, stats)| {
+                json!({
+                    "file": cfg.path_relative_to_root(path),
+                    "total_cards": stats.total_cards,
+                    "new_cards": stats.new_cards.0,
+                    "updated_cards": stats.updated_cards.0,
+                    "unchanged_cards": stats.unchanged_cards.0,
+                    "empty_cards": stats.empty_cards,
+                    "skipped_cards": stats.skipped_cards,
+                    "errors": stats.total_errors(),
+                    "compile_secs": stats.compile_time.as_secs_f64(),
+                })
+            })
+            .collect();
+        v.sort_by_key(|f| f["file"].as_str().unwrap_or_default().to_string());
+        Value::Array(v)
+    }
+}
+
+impl OutputManager for OutputJson {
+    fn ask_yes_no(&self, question: &str, default_answer: bool) -> bool {
+        // Never block on stdin: the JSON output is meant to be non-interactive
+        self.emit(json!({
+            "type": "question",
+            "question": question,
+            "answer": default_answer,
+        }));
+        default_answer
+    }
+
+    fn fail(&self) -> ExitCode {
+        self.send(OutputMessage::Fail(None));
+        ExitCode::FAILURE
+    }
+
+    fn fail_with_reason(&self, reason: String) -> ExitCode {
+        self.send(OutputMessage::Fail(Some(reason)));
+        ExitCode::FAILURE
+    }
+
+    fn send(&self, msg: OutputMessage) {
+        match msg {
+            OutputMessage::ListTypstFiles(files) => {
+                self.emit(json!({
+                    "type": "files",
+                    "files": self.files_json(&files),
+                }));
+                *self.files.write().unwrap() = Some(files);
+            }
+            OutputMessage::ListCards(cards) => {
+                for info in cards {
+                    let deck = info.deck.clone();
+                    let mut v = self.card_json("listed_card", info);
+                    v["deck"] = json!(deck);
+                    self.emit(v);
+                }
+            }
+            OutputMessage::DbgShowConfig(cfg) => {
+                self.emit(json!({
+                    "type": "config",
+                    "config": format!("{:?}", cfg),
+                    "config_file": cfg.config_file.as_ref().map(|p| p.display().to_string()),
+                }));
+            }
+            OutputMessage::DbgConfigChangeDetection {
+                total_cards,
+                config_changes,
+            } => {
+                self.emit(json!({
+                    "type": "config_change_detection",
+                    "total_cards": total_cards,
+                    "config_changes": config_changes,
+                }));
+            }
+            OutputMessage::DbgCreateDeck(deck_name) => {
+                self.emit(json!({ "type": "create_deck", "deck": deck_name }));
+            }
+            OutputMessage::DbgSavedCache => {
+                self.emit(json!({ "type": "saved_cache" }));
+            }
+            OutputMessage::DbgCompilationDone { files } => {
+                self.emit(json!({
+                    "type": "compilation_done",
+                    "files": self.files_json(&files),
+                }));
+            }
+            OutputMessage::DbgDone => {
+                self.emit(json!({ "type": "done" }));
+            }
+            OutputMessage::ParsingError(err) => {
+                self.emit(json!({ "type": "parsing_error", "message": err }));
+            }
+            OutputMessage::SkipCompileCard(info) => {
+                self.emit(self.card_json("skipped_card", info));
+            }
+            OutputMessage::CompileError(info) => {
+                self.emit(self.card_json("compile_error", info));
+            }
+            OutputMessage::CompileWarning(info) => {
+                self.emit(self.card_json("compile_warning", info));
+            }
+            OutputMessage::PushError(info) => {
+                self.emit(self.card_json("push_error", info));
+            }
+            OutputMessage::CompiledCard(info) => {
+                self.emit(self.card_json("compiled_card", info));
+            }
+            OutputMessage::PushedCard(info) => {
+                self.emit(self.card_json("pushed_card", info));
+            }
+            OutputMessage::NoAnkiConnection => {
+                self.emit(json!({ "type": "no_anki_connection" }));
+            }
+            OutputMessage::ErrorSavingCache(e) => {
+                self.emit(json!({ "type": "error_saving_cache", "message": e }));
+            }
+            OutputMessage::UnsupportedCacheVersion(version) => {
+                self.emit(json!({ "type": "unsupported_cache_version", "version": version }));
+            }
+            OutputMessage::CollectionReport(report) => {
+                let deck_json = |r: &DeckReport| {
+                    json!({
+                        "cards": r.cards,
+                        "new": r.new,
+                        "existing": r.existing,
+                        "orphans": r.orphans,
+                    })
+                };
+                let decks: Vec<serde_json::Value> = report
+                    .decks
+                    .iter()
+                    .map(|(deck, r)| {
+                        let mut v = deck_json(r);
+                        v["deck"] = json!(deck);
+                        v
+                    })
+                    .collect();
+                let orphans: Vec<serde_json::Value> = report
+                    .orphans
+                    .iter()
+                    .map(|o| json!({ "note_id": o.note_id, "deck": o.deck, "tags": o.tags }))
+                    .collect();
+                self.emit(json!({
+                    "type": "collection_report",
+                    "decks": decks,
+                    "total": deck_json(&report.total()),
+                    "orphans": orphans,
+                }));
+            }
+            OutputMessage::CardsDiff(diff) => {
+                let decks: Vec<Value> = diff
+                    .into_iter()
+                    .map(|(deck, changes)| {
+                        json!({
+                            "deck": deck,
+                            "added": changes.added,
+                            "modified": changes.modified,
+                            "removed": changes.removed,
+                        })
+                    })
+                    .collect();
+                self.emit(json!({ "type": "cards_diff", "decks": decks }));
+            }
+            OutputMessage::UnusedMedia(files) => {
+                self.emit(json!({ "type": "unused_media", "files": files }));
+            }
+            OutputMessage::PrunedMedia { deleted } => {
+                self.emit(json!({ "type": "pruned_media", "deleted": deleted }));
+            }
+            OutputMessage::TypstDownloadingPackage { package, version } => {
+                self.emit(json!({
+                    "type": "downloading_package",
+                    "package": package,
+                    "version": version,
+                }));
+            }
+            OutputMessage::ExportedFile { path, cards } => {
+                self.emit(json!({
+                    "type": "exported_file",
+                    "path": path.to_string_lossy(),
+                    "cards": cards,
+                }));
+            }
+            OutputMessage::Summary {
+                compiled_cards,
+                elapsed,
+                phases,
+                cards,
+            } => {
+                self.emit(json!({
+                    "type": "summary",
+                    "compiled_cards": compiled_cards,
+                    "elapsed_secs": elapsed.as_secs_f64(),
+                    "phases": phases_json(&phases),
+                    "cards": {
+                        "total": cards.total,
+                        "new": cards.new,
+                        "updated": cards.updated,
+                        "unchanged": cards.unchanged,
+                        "empty": cards.empty,
+                        "skipped": cards.skipped,
+                        "errors": cards.errors,
+                    },
+                    "media_bytes": cards.media_bytes,
+                }));
+            }
+            OutputMessage::BenchReport { cards, runs } => {
+                self.emit(json!({
+                    "type": "bench",
+                    "cards": cards,
+                    "runs": runs
+                        .iter()
+                        .map(|(elapsed, phases)| json!({
+                            "elapsed_secs": elapsed.as_secs_f64(),
+                            "phases": phases_json(phases),
+                        }))
+                        .collect::<Vec<_>>(),
+                }));
+            }
+            OutputMessage::Interrupted => {
+                self.emit(json!({ "type": "interrupted" }));
+            }
+            OutputMessage::WatchingForChanges => {
+                self.emit(json!({ "type": "watching" }));
+            }
+            OutputMessage::WatchFilesChanged(files) => {
+                self.emit(json!({ "type": "files_changed", "files": files }));
+            }
+            OutputMessage::Fail(reason) => {
+                self.emit(json!({ "type": "fail", "reason": reason }));
+            }
+        }
+    }
+}
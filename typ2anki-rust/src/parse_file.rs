@@ -1,67 +1,295 @@
 use std::{
-    collections::HashSet,
-    path::PathBuf,
-    sync::{Arc, LazyLock},
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
 use regex::Regex;
+use typst::syntax::Lines;
 
 use crate::{
-    card_wrapper::{CardInfo, TypFileStats},
+    card_wrapper::{CardInfo, TypFileStats, deck_name_problem, merge_by_id},
     cards_cache::CardsCacheManager,
-    config,
-    output::{OutputManager, OutputMessage},
+    config::{CardDelimiters, Config},
+    ignore_file, lockfile,
+    output::{OutputManager, OutputMessage, format_location},
     utils,
 };
 
-const DEFAULT_ANKICONF: &str = "#let conf(
+// A card as found by the parser: its source (with the file's prelude, if any), and the
+// range of bytes of the card's call in the file
+#[derive(Debug, Clone)]
+pub struct ParsedCard {
+    pub content: String,
+    // Where the card's call starts in `content`, after the prelude
+    pub call_start: usize,
+    pub byte_range: (usize, usize),
+}
+
+pub const DEFAULT_ANKICONF: &str = "#let conf(
   doc,
 ) = {
   doc
 }";
 
-pub fn check_ankiconf_exists() {
-    let cfg = config::get();
-    let ankiconf_path = cfg.path.join("ankiconf.typ");
-    if !ankiconf_path.exists() {
-        std::fs::write(&ankiconf_path, DEFAULT_ANKICONF).expect("Failed to create ankiconf.typ");
+pub fn check_ankiconf_exists(cfg: &Config) {
+    for root in &cfg.paths {
+        let ankiconf_path = root.join("ankiconf.typ");
+        if !ankiconf_path.exists() {
+            std::fs::write(&ankiconf_path, DEFAULT_ANKICONF)
+                .expect("Failed to create ankiconf.typ");
+        }
     }
 }
 
+// The ankiconf.typ used by a file: the one of the nearest folder containing it, up to its
+// root, so that the subfolders of a project (or of a zip) can have their own configuration.
+// Files with none above them use the root's one, created by check_ankiconf_exists.
+pub fn nearest_ankiconf(root: &Path, file: &Path) -> PathBuf {
+    file.ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(root))
+        .map(|dir| dir.join("ankiconf.typ"))
+        .find(|p| p.is_file())
+        .unwrap_or_else(|| root.join("ankiconf.typ"))
+}
+
 pub static QUESTION_EMPTY_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"q:\s*(\[\s*\]|"\s*")"#).unwrap());
 pub static ANSWER_EMPTY_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"a:\s*(\[\s*\]|"\s*")"#).unwrap());
 
-pub static ID_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"id:\s*"([^"]*)""#).unwrap());
+// The name of an argument, up to its value (parsed by `argument_value`)
+pub static ID_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?:^|[^\w-])id\s*:\s*"#).unwrap());
 pub static DECK_RE: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r#"target-deck:\s*"([^"]+)""#).unwrap());
+    LazyLock::new(|| Regex::new(r#"(?:^|[^\w-])target-deck\s*:\s*"#).unwrap());
 pub static QUESTION_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"q:\s*(\[(?:.|\n)*\]|"(?:.|\n)*")"#).unwrap());
 pub static ANSWER_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"a:\s*(\[(?:.|\n)*\]|"(?:.|\n)*")"#).unwrap());
+pub static TAGS_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"tags:\s*(\([^)]*\)|"[^"]*")"#).unwrap());
+static SET_DOCUMENT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"#set\s+document\("#).unwrap());
+static TITLE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?:^|[^\w-])title\s*:\s*"#).unwrap());
+static KEYWORDS_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"keywords:\s*(\([^)]*\)|"[^"]*")"#).unwrap());
+static STRING_LITERAL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""([^"]*)""#).unwrap());
 
 pub fn is_card_empty(card_str: &str) -> bool {
     QUESTION_EMPTY_RE.is_match(card_str) && ANSWER_EMPTY_RE.is_match(card_str)
 }
 
-pub fn get_ankiconf_hash() -> String {
-    let cfg = config::get();
-    let ankiconf_path = cfg.path.join("ankiconf.typ");
+/// Reads a Typst string literal at the start of `s`, decoding its escapes (`\"`, `\\`,
+/// `\n`, `\u{1F600}`...)
+fn parse_string_literal(s: &str) -> Option<String> {
+    let mut chars = s.strip_prefix('"')?.chars();
+    let mut value = String::new();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let rest = chars.as_str().strip_prefix('{')?;
+                    let (hex, after) = rest.split_once('}')?;
+                    value.push(char::from_u32(u32::from_str_radix(hex, 16).ok()?)?);
+                    chars = after.chars();
+                }
+                other => value.push(other),
+            },
+            _ => value.push(ch),
+        }
+    }
+    // Never closed
+    None
+}
+
+/// Reads a content block at the start of `s`, returning its trimmed text
+fn parse_content_block(s: &str) -> Option<String> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, ch) in s.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(s[1..i].trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The value of the first argument of a card matched by `name_re` (e.g. `ID_RE`), when it's
+/// a string (`id: "a\"b"`) or a content block (`id: [a]`)
+pub fn argument_value(name_re: &Regex, card_str: &str) -> Option<String> {
+    name_re.find_iter(card_str).find_map(|m| {
+        let value = &card_str[m.end()..];
+        match value.chars().next()? {
+            '"' => parse_string_literal(value),
+            '[' => parse_content_block(value),
+            _ => None,
+        }
+    })
+}
+
+/// Parses the `tags:` argument of a card, which is either an array of strings
+/// (`tags: ("a", "b")`) or a single string (`tags: "a"`)
+pub fn parse_tags(card_str: &str) -> Vec<String> {
+    parse_string_list(&TAGS_RE, card_str)
+}
+
+fn parse_string_list(re: &Regex, card_str: &str) -> Vec<String> {
+    let Some(value) = re
+        .captures(card_str)
+        .and_then(|caps| caps.get(1).map(|m| m.as_str()))
+    else {
+        return Vec::new();
+    };
+    let mut tags: Vec<String> = Vec::new();
+    for cap in STRING_LITERAL_RE.captures_iter(value) {
+        let tag = utils::sanitize_tag(&cap[1]);
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+// The `#set document(title: ..., keywords: ...)` of a file: the deck of its cards without a
+// `target-deck`, and the tags of those without `tags`
+#[derive(Debug, Clone, Default)]
+pub struct DocumentDefaults {
+    pub deck: Option<String>,
+    pub tags: Vec<String>,
+}
+
+// The arguments of the set rule are read up to the first card, so that the cards' own
+// arguments aren't mistaken for them
+pub fn document_defaults(cfg: &Config, content: &str) -> DocumentDefaults {
+    let Some(m) = SET_DOCUMENT_RE.find(content) else {
+        return DocumentDefaults::default();
+    };
+    let rest = &content[m.end()..];
+    let end = cfg
+        .card_function_names()
+        .iter()
+        .filter_map(|name| rest.find(&format!("#{}(", name)))
+        .min()
+        .unwrap_or(rest.len());
+    let args = &rest[..end];
+    DocumentDefaults {
+        deck: argument_value(&TITLE_RE, args).filter(|t| !t.trim().is_empty()),
+        tags: parse_string_list(&KEYWORDS_RE, args),
+    }
+}
+
+// Hash of the ankiconf.typ of every root, which affects all the cards
+pub fn get_ankiconf_hash(cfg: &Config) -> String {
+    if cfg.paths.len() == 1 {
+        return get_root_ankiconf_hash(cfg, &cfg.paths[0]);
+    }
+    let hashes: Vec<String> = cfg
+        .paths
+        .iter()
+        .map(|p| get_root_ankiconf_hash(cfg, p))
+        .collect();
+    utils::hash_string(&hashes.join(""))
+}
+
+// Subfolders may have their own ankiconf.typ, hashed along with the root's one. The hash
+// stays the same as before for projects with a single ankiconf.typ.
+fn get_root_ankiconf_hash(cfg: &Config, root: &Path) -> String {
+    let nested: Vec<String> = walkdir::WalkDir::new(root)
+        .min_depth(2)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name() == "ankiconf.typ")
+        .filter_map(|e| e.path().parent().map(Path::to_path_buf))
+        .map(|dir| {
+            let relative = dir.strip_prefix(root).unwrap_or(&dir);
+            format!(
+                "{}{}",
+                relative.display(),
+                get_dir_ankiconf_hash(cfg, root, &dir)
+            )
+        })
+        .collect();
+    let root_hash = get_dir_ankiconf_hash(cfg, root, root);
+    if nested.is_empty() {
+        return root_hash;
+    }
+    utils::hash_string(&format!("{}{}", root_hash, nested.join("")))
+}
+
+fn get_dir_ankiconf_hash(cfg: &Config, root: &Path, dir: &Path) -> String {
+    let ankiconf_path = dir.join("ankiconf.typ");
     if !ankiconf_path.exists() {
         return String::new();
     }
-    let mut content = std::fs::read_to_string(ankiconf_path).unwrap_or_default();
-    let imports = utils::get_all_typst_imports(content.as_str());
+    let mut content = utils::read_text_file(&ankiconf_path).unwrap_or_default();
+    let imports = utils::get_all_typst_imports(root, &ankiconf_path, content.as_str());
 
-    for import in imports {
-        if let Ok(import_content) = std::fs::read_to_string(&import) {
+    for import in imports.files {
+        if let Ok(import_content) = utils::read_text_file(&import) {
             content.push('\n');
             content.push_str(&import_content);
         }
     }
+    // The import lines already hash the version of the packages, which never change once
+    // published. Other namespaces (e.g. `@local`) can change without a new version, so
+    // their files are hashed too.
+    for package in imports.packages {
+        let package_dir = cfg.package_cache.join(format!(
+            "{}/{}/{}",
+            package.namespace, package.name, package.version
+        ));
+        if package.namespace != "preview" && package_dir.is_dir() {
+            content.push('\n');
+            content.push_str(&format!("{}{}", package, lockfile::hash_dir(&package_dir)));
+        }
+    }
+
+    utils::hash_text(&content)
+}
 
-    utils::hash_string(&content)
+// A comment starting with START (`// START`) begins the file's prelude: the text after it
+// that isn't a card is put before each of the following cards, so that they can share
+// `#let`s and `#import`s. The prelude of a card is everything from the marker to the card.
+const PRELUDE_STARTS: [&str; 2] = ["START", "start"];
+
+// Whether the inside of a comment (without `//`, or `/*` and `*/`) is a prelude marker
+fn is_prelude_start(comment_inside: &str) -> bool {
+    let trimmed = comment_inside.trim_start();
+    PRELUDE_STARTS
+        .iter()
+        .any(|start| trimmed.starts_with(start))
+}
+
+impl ParsedCard {
+    // The text given to Typst for a card: its prelude, then its call
+    fn new(prelude: &str, call: &str, byte_range: (usize, usize)) -> Self {
+        let prelude = prelude.trim();
+        Self {
+            content: format!("{}\n{}", prelude, call.trim()),
+            call_start: prelude.len() + 1,
+            byte_range,
+        }
+    }
 }
 
 #[cfg(feature = "tree-sitter")]
@@ -106,12 +334,12 @@ mod parse_card_tree_sitter {
     fn get_function_from_call_node<'a>(
         source: &[u8],
         node: Node<'a>,
-        function_name: &str,
+        function_names: &[&str],
     ) -> Option<Node<'a>> {
         if let Some(item) = node.child_by_field_name("item") {
             if item.kind() == "identifier" || item.kind() == "ident" {
                 let name = item.utf8_text(source).unwrap();
-                if name == function_name {
+                if function_names.contains(&name) {
                     return Some(node);
                 }
             }
@@ -120,12 +348,16 @@ mod parse_card_tree_sitter {
     }
 
     pub fn parse_cards_string(
+        cfg: &Config,
         content: &str,
         output: &Arc<impl OutputManager + 'static>,
-        _no_prelude: bool,
-    ) -> Vec<String> {
-        let cfg = config::get();
-        const CARD_FUNCTION_NAME: &str = "custom-card";
+        no_prelude: bool,
+    ) -> Vec<ParsedCard> {
+        // `card` is left to Typst here, only the calls to these functions are cards
+        let card_functions: Vec<&str> = ["custom-card"]
+            .into_iter()
+            .chain(cfg.card_functions.iter().map(String::as_str))
+            .collect();
 
         let mut ts_parser = TS_PARSER
             .get_or_init(|| {
@@ -181,15 +413,20 @@ mod parse_card_tree_sitter {
 
         let mut push_hashtag = false;
         let mut prelude = String::new();
+        // Where the prelude starts in `prelude`, once its marker has been found
+        let mut prelude_start: Option<usize> = None;
         let mut previous_was_card = false;
         for call_node in tree.root_node().children(&mut cursor) {
             if call_node.kind() == "call" {
                 if !check_isnt_let(&call_node) {
                     continue;
                 }
-                if let Some(item) =
-                    get_function_from_call_node(source, call_node, CARD_FUNCTION_NAME)
+                if let Some(item) = get_function_from_call_node(source, call_node, &card_functions)
                 {
+                    let function_name = call_node
+                        .child_by_field_name("item")
+                        .and_then(|n| n.utf8_text(source).ok())
+                        .unwrap_or_default();
                     push_hashtag = false;
                     previous_was_card = true;
                     match handle_card_node(item, &call_node) {
@@ -199,20 +436,20 @@ mod parse_card_tree_sitter {
                                 .unwrap_or("unknown_id".to_string());
                             output.send(OutputMessage::ParsingError(if !cfg.dry_run {
                                 format!(
-                                    "Warning: Failed to parse {CARD_FUNCTION_NAME} (id: {id}): {}",
+                                    "Warning: Failed to parse {function_name} (id: {id}): {}",
                                     e
                                 )
                             } else {
                                 format!(
-                                    "Failed to parse {CARD_FUNCTION_NAME} (id: {id}): {e}\n{}",
+                                    "Failed to parse {function_name} (id: {id}): {e}\n{}",
                                     call_node.utf8_text(source).unwrap_or("unknown_content")
                                 )
                             }));
                         }
                         Ok(mut c) => {
-                            c.prelude_range = Some(0..prelude.len());
+                            c.prelude_range = prelude_start.map(|start| start..prelude.len());
+                            log::trace!("Card: {:?}", c);
                             cards.push(c);
-                            // println!("Card: {:?}", c);
                         }
                     }
                 }
@@ -227,7 +464,7 @@ mod parse_card_tree_sitter {
                         .map(|n| n.utf8_text(source).ok())
                         .flatten()
                     {
-                        if func_name == CARD_FUNCTION_NAME {
+                        if card_functions.contains(&func_name) {
                             push_hashtag = false;
                             continue;
                         }
@@ -278,6 +515,14 @@ mod parse_card_tree_sitter {
                         }
                     }
                     "comment" => {
+                        let text = call_node.utf8_text(source).unwrap_or_default();
+                        let inside = text
+                            .strip_prefix("//")
+                            .or_else(|| text.strip_prefix("/*"))
+                            .unwrap_or(text);
+                        if !no_prelude && prelude_start.is_none() && is_prelude_start(inside) {
+                            prelude_start = Some(prelude.len());
+                        }
                         previous_was_card = false;
                     }
                     _ => {
@@ -290,28 +535,17 @@ mod parse_card_tree_sitter {
             }
         }
 
-        // println!("Function calls found: {:?}", calls);
-
-        // if !calls.is_empty() {
-        //     let first_call = calls[0];
-        //     println!(
-        //         "First call from {} to {}: {}",
-        //         first_call.0,
-        //         first_call.1,
-        //         &content[first_call.0..first_call.1]
-        //     );
-        // }
+        log::trace!("{} cards found by tree-sitter", cards.len());
 
         cards
             .into_iter()
             .map(|c| {
-                let mut card_str = String::new();
-                if let Some(prelude_range) = &c.prelude_range {
-                    card_str.push_str(&prelude[prelude_range.start..prelude_range.end]);
-                    card_str.push_str("\n");
-                }
-                card_str.push_str(&content[c.byte_range.0..c.byte_range.1]);
-                card_str
+                let card_prelude = c.prelude_range.clone().map_or("", |range| &prelude[range]);
+                ParsedCard::new(
+                    card_prelude,
+                    &content[c.byte_range.0..c.byte_range.1],
+                    c.byte_range,
+                )
             })
             .collect()
     }
@@ -319,13 +553,10 @@ mod parse_card_tree_sitter {
 
 #[cfg(not(feature = "tree-sitter"))]
 mod parse_card_fallback {
-    use std::ops::Range;
+    use std::{collections::HashSet, ops::Range};
 
     use super::*;
 
-    const CARD_TYPES: [&str; 2] = ["#card(", "#custom-card("];
-    const PRELUDE_STARTS: [&str; 2] = ["START", "start"];
-
     /// Checks if the `content string has a line or block comment starting at byte index `i`
     /// If it does, this returns a range indicating the inside of the comment, and the byte index
     /// of the first character after the end of the comment (after the linefeed for line comments)
@@ -351,83 +582,199 @@ mod parse_card_fallback {
         }
     }
 
+    // What is being scanned inside a card
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Mode {
+        // Code, closed by the given delimiter: `)` for function arguments and parenthesized
+        // expressions, `}` for code blocks
+        Code(char),
+        // A content block, closed by `]`
+        Markup,
+        // An equation, closed by `$`
+        Math,
+    }
+
+    /// Returns the byte index after the string literal starting at byte index `i`
+    fn skip_string(content: &str, i: usize) -> usize {
+        let mut chars = content[i + 1..].char_indices();
+        while let Some((j, ch)) = chars.next() {
+            match ch {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => return i + 1 + j + 1,
+                _ => {}
+            }
+        }
+        content.len()
+    }
+
+    /// Returns the byte index after the raw text (`` `code` `` or a ``` block) starting at
+    /// byte index `i`
+    fn skip_raw(content: &str, i: usize) -> usize {
+        let ticks = content[i..].bytes().take_while(|&b| b == b'`').count();
+        // Two backticks are an empty raw text, not the start of a longer one
+        if ticks == 2 {
+            return i + 2;
+        }
+        let fence = &content[i..i + ticks];
+        content[i + ticks..]
+            .find(fence)
+            .map_or(content.len(), |end| i + ticks + end + ticks)
+    }
+
+    /// Returns the byte index after the `#` of an expression embedded in markup or math,
+    /// and the code mode it opens, if any: `#f(x)` and `#(x)` open arguments, `#{...}` a
+    /// code block. `#f[x]` opens a content block, which is handled like any other.
+    fn skip_embedded_code(content: &str, i: usize) -> (usize, Option<Mode>) {
+        let name_end = content[i + 1..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'))
+            .map_or(content.len(), |end| i + 1 + end);
+        match content[name_end..].chars().next() {
+            Some('(') => (name_end + 1, Some(Mode::Code(')'))),
+            Some('{') => (name_end + 1, Some(Mode::Code('}'))),
+            _ => (name_end, None),
+        }
+    }
+
+    /// Returns the byte index after the `)` that closes a card call, whose arguments start at
+    /// byte index `start`, or None if the card is never closed.
+    /// The parentheses, brackets and braces are matched the way Typst reads them: not inside
+    /// strings, comments or raw text, brackets and parentheses only where they delimit
+    /// something (e.g. not as text in a content block, or in `$[0, 1)$`), and escaped ones
+    /// never.
+    fn find_card_end(content: &str, start: usize) -> Option<usize> {
+        let mut modes = vec![Mode::Code(')')];
+        let mut i = start;
+        while let Some(ch) = content[i..].chars().next() {
+            let mode = *modes.last()?;
+            // `//` in markup is part of a link when it follows `https:`
+            let is_link = mode == Mode::Markup && content[..i].ends_with(':');
+            if !is_link && let Some((_, next)) = parse_comment(content, i) {
+                i = next;
+                continue;
+            }
+            match (mode, ch) {
+                (Mode::Code(_) | Mode::Math, '"') => {
+                    i = skip_string(content, i);
+                    continue;
+                }
+                (Mode::Code(_) | Mode::Markup, '`') => {
+                    i = skip_raw(content, i);
+                    continue;
+                }
+                (Mode::Markup | Mode::Math, '\\') => {
+                    // An escaped character, like `\]`, is just text
+                    i += 1;
+                    i += content[i..].chars().next().map_or(0, char::len_utf8);
+                    continue;
+                }
+                (Mode::Markup | Mode::Math, '#') => {
+                    let (next, opened) = skip_embedded_code(content, i);
+                    modes.extend(opened);
+                    i = next;
+                    continue;
+                }
+                (Mode::Code(closer), _) if ch == closer => {
+                    modes.pop();
+                    if modes.is_empty() {
+                        return Some(i + 1);
+                    }
+                }
+                (Mode::Code(_), '(') => modes.push(Mode::Code(')')),
+                (Mode::Code(_), '{') => modes.push(Mode::Code('}')),
+                (Mode::Code(_) | Mode::Markup, '[') => modes.push(Mode::Markup),
+                (Mode::Code(_) | Mode::Markup, '$') => modes.push(Mode::Math),
+                (Mode::Markup, ']') | (Mode::Math, '$') => {
+                    modes.pop();
+                }
+                _ => {}
+            }
+            i += ch.len_utf8();
+        }
+        None
+    }
+
+    /// The name of the function called with arguments by the `#` at byte index `i`, like
+    /// `card` for `#card(`
+    fn called_function(content: &str, i: usize) -> Option<&str> {
+        let name_end = content[i + 1..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'))
+            .map_or(content.len(), |end| i + 1 + end);
+        (content.as_bytes().get(name_end) == Some(&b'(')).then(|| &content[i + 1..name_end])
+    }
+
+    /// Adds text found outside of the cards to the prelude, without its empty lines
+    fn push_prelude(prelude: &mut String, text: &str) {
+        for line in text.split_inclusive('\n') {
+            if line == "\n" && prelude.ends_with('\n') {
+                continue;
+            }
+            prelude.push_str(line);
+        }
+    }
+
+    /// Scans the file once, from comment to card call: only `/` and `#` can start them, the
+    /// text in between is skipped (or added to the prelude) all at once
     pub fn parse_cards_string(
+        cfg: &Config,
         content: &str,
         _: &Arc<impl OutputManager + 'static>,
         no_prelude: bool,
-    ) -> Vec<String> {
-        let mut results: Vec<String> = Vec::new();
+    ) -> Vec<ParsedCard> {
+        let mut results: Vec<ParsedCard> = Vec::new();
+        let card_types: HashSet<&str> = cfg.card_function_names().into_iter().collect();
 
-        let mut inside_card = false;
-        let mut balance: i32 = 0;
-        let mut current_card = String::new();
+        let bytes = content.as_bytes();
         let mut i: usize = 0;
-        let len = content.len();
 
         let mut current_prelude = String::new();
         let mut prelude_started = false;
 
-        while i < len {
-            if let Some((comment_inside, next)) = parse_comment(content, i) {
-                if !no_prelude && !inside_card && !prelude_started {
-                    let trimmed = content[comment_inside].trim_start();
-                    if PRELUDE_STARTS
-                        .iter()
-                        .any(|start| trimmed.starts_with(start))
-                    {
-                        prelude_started = true;
-                    }
-                }
-                i = next;
-                continue;
+        while i < bytes.len() {
+            let next = bytes[i..]
+                .iter()
+                .position(|&b| b == b'/' || b == b'#')
+                .map_or(bytes.len(), |p| i + p);
+            // Not inside a card and prelude only tracked after marker found
+            if prelude_started {
+                push_prelude(&mut current_prelude, &content[i..next]);
+            }
+            i = next;
+            if i == bytes.len() {
+                break;
             }
 
-            if !inside_card && CARD_TYPES.iter().any(|ct| content[i..].starts_with(ct)) {
-                inside_card = true;
-                for ct in &CARD_TYPES {
-                    if content[i..].starts_with(ct) {
-                        balance = 1;
-                        current_card.clear();
-                        current_card.push_str(ct);
-                        i += ct.len();
-                        break;
-                    }
+            if let Some((comment_inside, next)) = parse_comment(content, i) {
+                if !no_prelude && !prelude_started && is_prelude_start(&content[comment_inside]) {
+                    prelude_started = true;
                 }
+                i = next;
                 continue;
             }
 
-            if inside_card {
-                let ch = content[i..].chars().next().unwrap();
-                current_card.push(ch);
-                if ch == '(' {
-                    balance += 1;
-                } else if ch == ')' {
-                    balance -= 1;
-                }
-                i += ch.len_utf8();
-
-                if balance == 0 {
-                    results.push(format!(
-                        "{}\n{}",
-                        current_prelude.trim(),
-                        current_card.trim()
-                    ));
-                    inside_card = false;
-                    current_card.clear();
-                }
+            if bytes[i] == b'#'
+                && let Some(name) = called_function(content, i)
+                && card_types.contains(name)
+            {
+                let Some(end) = find_card_end(content, i + name.len() + 2) else {
+                    // The card is never closed, so there is nothing more to parse
+                    break;
+                };
+                results.push(ParsedCard::new(
+                    &current_prelude,
+                    &content[i..end],
+                    (i, end),
+                ));
+                i = end;
                 continue;
             }
 
-            // Not inside a card and prelude only tracked after marker found
-            let ch = content[i..].chars().next().unwrap();
+            // A `/` or `#` that starts neither
             if prelude_started {
-                if ch == '\n' && current_prelude.ends_with('\n') {
-                    // skip duplicate new line``
-                } else {
-                    current_prelude.push(ch);
-                }
+                current_prelude.push(bytes[i] as char);
             }
-            i += ch.len_utf8();
+            i += 1;
         }
 
         results
@@ -435,60 +782,396 @@ mod parse_card_fallback {
 }
 
 #[cfg(not(feature = "tree-sitter"))]
-pub use parse_card_fallback::parse_cards_string;
+use parse_card_fallback::parse_cards_string as parse_cards_heuristic;
 #[cfg(feature = "tree-sitter")]
-pub use parse_card_tree_sitter::parse_cards_string;
+use parse_card_tree_sitter::parse_cards_string as parse_cards_heuristic;
 
-pub fn parse_cards_from_file_content(
+const CARD_BEGIN_MARKER: &str = "// card-begin";
+const CARD_END_MARKER: &str = "// card-end";
+
+// With --card-delimiters markers, a card is everything between a `// card-begin` line and
+// the next `// card-end` line, so it can contain any Typst code. The text outside of the
+// cards after a `// START` line is their prelude, like with the heuristic.
+fn parse_cards_between_markers(
+    content: &str,
+    output: &Arc<impl OutputManager + 'static>,
+    no_prelude: bool,
+) -> Vec<ParsedCard> {
+    let mut results: Vec<ParsedCard> = Vec::new();
+    let mut prelude = String::new();
+    let mut prelude_started = false;
+    // Byte index of the first line of the current card, and line number of its begin marker
+    let mut card_start: Option<(usize, usize)> = None;
+    let mut offset: usize = 0;
+    for (line_number, line) in content.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        match card_start {
+            None if trimmed == CARD_BEGIN_MARKER => card_start = Some((offset, line_number + 1)),
+            None => {
+                if let Some(comment) = trimmed.strip_prefix("//") {
+                    prelude_started |= !no_prelude && is_prelude_start(comment);
+                } else if prelude_started {
+                    prelude.push_str(line);
+                }
+            }
+            Some((start, _)) if trimmed == CARD_END_MARKER => {
+                results.push(ParsedCard::new(
+                    &prelude,
+                    &content[start..line_start],
+                    (start, line_start),
+                ));
+                card_start = None;
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some((_, line_number)) = card_start {
+        output.send(OutputMessage::ParsingError(format!(
+            "Warning: The {} on line {} has no {}, the card is ignored",
+            CARD_BEGIN_MARKER, line_number, CARD_END_MARKER
+        )));
+    }
+    results
+}
+
+// Finds the cards of a file, returning their text (with the prelude) and where they are
+pub fn parse_cards_string(
+    cfg: &Config,
+    content: &str,
+    output: &Arc<impl OutputManager + 'static>,
+    no_prelude: bool,
+) -> Vec<ParsedCard> {
+    match cfg.card_delimiters {
+        CardDelimiters::Heuristic => parse_cards_heuristic(cfg, content, output, no_prelude),
+        CardDelimiters::Markers => parse_cards_between_markers(content, output, no_prelude),
+    }
+}
+
+// Whether a file can contain cards: .typ files other than ankiconf.typ and temporal files
+pub fn is_card_file(path: &Path) -> bool {
+    let is_typ = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(|s| s.eq_ignore_ascii_case("typ"))
+        .unwrap_or(false);
+    let s = path.file_name().unwrap_or_default().to_string_lossy();
+    is_typ && !(s == "ankiconf.typ" || s.starts_with("temporal-"))
+}
+
+// Finds all card files inside of every root, including nested
+pub fn find_card_files(cfg: &Config) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = cfg
+        .paths
+        .iter()
+        .flat_map(|root| {
+            // Ignored folders aren't even walked into
+            walkdir::WalkDir::new(root)
+                .into_iter()
+                .filter_entry(|e| !ignore_file::is_ignored(root, e.path(), e.file_type().is_dir()))
+        })
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_path_buf())
+        .filter(|p| is_card_file(p))
+        .collect();
+    // Roots may be nested in one another
+    files.sort();
+    files.dedup();
+    files
+}
+
+// Whether the .typ2anki-ignore files leave out card files, whose cards aren't seen in this
+// run even when their decks are
+pub fn has_ignored_card_files(cfg: &Config) -> bool {
+    cfg.paths.iter().any(|root| {
+        walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .any(|e| is_card_file(e.path()) && ignore_file::is_ignored(root, e.path(), false))
+    })
+}
+
+// The stats of a file and its cards
+type ParsedFile = (TypFileStats, Vec<CardInfo>);
+
+// Reads and parses a card file, reporting problems through the output.
+// Returns None if the file is excluded, can't be read or parsed, or has no cards.
+pub fn parse_typ_file(
+    cfg: &Config,
     filepath: &PathBuf,
-    content: String,
+    output: Arc<impl OutputManager + 'static>,
+) -> Option<ParsedFile> {
+    let p = cfg.path_relative_to_root(filepath);
+    if cfg.is_file_excluded(p.as_ref()) {
+        if cfg.dry_run {
+            output.send(OutputMessage::ParsingError(
+                format!("Skipping excluded file: {}", p).to_string(),
+            ));
+        }
+        return None;
+    }
+
+    let content = match utils::read_text_file(filepath) {
+        Ok(content) => content,
+        Err(e) => {
+            output.send(OutputMessage::ParsingError(format!(
+                "Warning: Skipping file {:?}, none of its cards are processed: {}",
+                filepath.to_string_lossy(),
+                e
+            )));
+            return None;
+        }
+    };
+    let (file, cards) = match parse_cards_from_file_content(cfg, filepath, content, output.clone())
+    {
+        Ok(f) => f,
+        Err(e) => {
+            output.send(OutputMessage::ParsingError(e));
+            return None;
+        }
+    };
+    if file.total_cards == 0 {
+        return None;
+    }
+    Some((file, cards))
+}
+
+// Parses the card files on every CPU. The files are independent, only their cards are then
+// added in the order of `typ_files`, so that the result is the same as parsing them one by
+// one. Returns the stats of the files with cards.
+pub fn parse_typ_files(
+    cfg: &Config,
+    typ_files: &[PathBuf],
     cards_cache_manager: &mut CardsCacheManager,
     output: Arc<impl OutputManager + 'static>,
-    i: &mut i64,
-    deck_names: &mut HashSet<String>,
+    deck_names: &mut BTreeSet<String>,
     cards: &mut Vec<CardInfo>,
-) -> Result<TypFileStats, String> {
-    let cfg = config::get();
+) -> Vec<(PathBuf, TypFileStats)> {
+    let next_file = AtomicUsize::new(0);
+    let parsed: Vec<Mutex<Option<ParsedFile>>> =
+        typ_files.iter().map(|_| Mutex::new(None)).collect();
+    let n_threads = num_cpus::get().min(typ_files.len());
+    std::thread::scope(|scope| {
+        for _ in 0..n_threads {
+            let output = output.clone();
+            let (next_file, parsed) = (&next_file, &parsed);
+            scope.spawn(move || {
+                loop {
+                    let i = next_file.fetch_add(1, Ordering::Relaxed);
+                    let Some(filepath) = typ_files.get(i) else {
+                        break;
+                    };
+                    *parsed[i].lock().unwrap() = parse_typ_file(cfg, filepath, output.clone());
+                }
+            });
+        }
+    });
 
+    let mut files = Vec::new();
+    for (filepath, parsed) in typ_files.iter().zip(parsed) {
+        let Some((file, file_cards)) = parsed.into_inner().unwrap() else {
+            continue;
+        };
+        for card_info in file_cards {
+            // Once per deck, the other cards of the deck have the same problem
+            if !deck_names.contains(&card_info.deck_name)
+                && let Some(problem) = deck_name_problem(&card_info.deck_name)
+            {
+                output.send(OutputMessage::ParsingError(format!(
+                    "Warning: {} ({})",
+                    problem,
+                    format_location(&filepath.to_string_lossy(), card_info.location)
+                )));
+            }
+            cards_cache_manager.add_card_hash(
+                &card_info.deck_name,
+                &card_info.card_id,
+                &card_info.content_hash,
+            );
+            deck_names.insert(card_info.deck_name.clone());
+            cards.push(card_info);
+        }
+        files.push((filepath.clone(), file));
+    }
+    files
+}
+
+// 1-based line and column of a byte offset, as editors show them
+fn line_column(lines: &Lines<String>, byte: usize) -> Option<(usize, usize)> {
+    lines
+        .byte_to_line_column(byte)
+        .map(|(line, column)| (line + 1, column + 1))
+}
+
+// The cards of a file and its stats. The problems of the deck names are only reported here
+// with --strict-deck-names, parse_typ_files warns about them otherwise.
+pub fn parse_cards_from_file_content(
+    cfg: &Config,
+    filepath: &PathBuf,
+    content: String,
+    output: Arc<impl OutputManager + 'static>,
+) -> Result<ParsedFile, String> {
     let mut file = TypFileStats::new(filepath.clone());
 
     let start = std::time::Instant::now();
-    let parsed = parse_cards_string(&content, &output, false);
+    let parsed = parse_cards_string(cfg, &content, &output, false);
     let _duration = start.elapsed();
 
     if parsed.is_empty() {
-        return Ok(file);
+        return Ok((file, Vec::new()));
     }
+    let defaults = document_defaults(cfg, &content);
+    let lines = Lines::new(content);
 
-    for card_str in parsed.into_iter() {
-        if is_card_empty(&card_str) {
+    let mut file_cards: Vec<CardInfo> = Vec::new();
+    for parsed_card in parsed.into_iter() {
+        if is_card_empty(&parsed_card.content) {
             file.empty_cards += 1;
             continue;
         }
 
-        match CardInfo::from_string(*i, &card_str, filepath.clone()) {
-            Ok(card_info) => {
+        match CardInfo::from_string(
+            &parsed_card.content,
+            filepath.clone(),
+            parsed_card.byte_range,
+            &defaults,
+        ) {
+            Ok(mut card_info) => {
+                card_info.location = line_column(&lines, parsed_card.byte_range.0);
+                card_info.call_start = parsed_card.call_start;
                 if cfg.is_deck_excluded(card_info.deck_name.as_str()) {
                     file.skipped_cards += 1;
                     continue;
                 }
-                cards_cache_manager.add_card_hash(
-                    &card_info.deck_name,
-                    &card_info.card_id,
-                    &card_info.content_hash,
-                );
-                deck_names.insert(card_info.deck_name.clone());
-                cards.push(card_info);
-                *i += 1;
-                file.total_cards += 1;
+                if cfg.strict_deck_names
+                    && let Some(problem) = deck_name_problem(&card_info.deck_name)
+                {
+                    output.send(OutputMessage::ParsingError(format!(
+                        "Failed to parse card in file {}: {}",
+                        format_location(&filepath.to_string_lossy(), card_info.location),
+                        problem
+                    )));
+                    file.parse_errors += 1;
+                    continue;
+                }
+                file_cards.push(card_info);
             }
-            Err(_) => {
+            Err(e) => {
+                file.parse_errors += 1;
                 output.send(OutputMessage::ParsingError(format!(
-                    "Warning: Failed to parse card in file {:?}",
-                    filepath.to_string_lossy()
+                    "Warning: Failed to parse card in file {}: {}",
+                    format_location(
+                        &filepath.to_string_lossy(),
+                        line_column(&lines, parsed_card.byte_range.0)
+                    ),
+                    e
                 )));
             }
         }
     }
-    Ok(file)
+
+    if cfg.merge_by_id {
+        file_cards = merge_by_id(cfg, file_cards);
+    }
+    file.total_cards = file_cards.len();
+    Ok((file, file_cards))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::config_from_args, output_json::OutputJson};
+
+    fn parse(content: &str) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Arc::new(config_from_args(&[&dir.path().to_string_lossy()]));
+        let output = Arc::new(OutputJson::new(cfg.clone()));
+        parse_cards_string(&cfg, content, &output, false)
+            .into_iter()
+            .map(|card| card.content)
+            .collect()
+    }
+
+    #[test]
+    fn parentheses_inside_cards() {
+        let first =
+            r#"#card(id: "001", target-deck: "D", q: [Is $f(x) = (x + 1)$ in $[0, 1)$?], a: "(")"#;
+        let second =
+            r#"#card(id: "002", target-deck: "D", q: [A smiley :)], a: [#calc.max(1, (2))])"#;
+        let cards = parse(&format!(
+            "{}\n\nSome text (with a parenthesis\n{}\n",
+            first, second
+        ));
+        assert_eq!(cards, [format!("\n{}", first), format!("\n{}", second)]);
+    }
+
+    #[test]
+    fn unicode_inside_cards() {
+        let card = r#"#card(id: "été", target-deck: "Café", q: [∑ (x²) → 😀], a: "日本語")"#;
+        let cards = parse(&format!("Ünïcödé before 😀\n{}\nand after ∑", card));
+        assert_eq!(cards, [format!("\n{}", card)]);
+    }
+
+    #[test]
+    fn code_block_inside_card() {
+        let card = r#"#card(id: "001", target-deck: "D", q: [How is a card written?], a: [
+```typst
+#card(id: "x", q: [)], a: ")
+```
+with `)` or `#card(`
+])"#;
+        let next = r#"#card(id: "002", target-deck: "D", q: [q], a: [a])"#;
+        let cards = parse(&format!("{}\n{}", card, next));
+        assert_eq!(cards, [format!("\n{}", card), format!("\n{}", next)]);
+    }
+
+    #[test]
+    fn prelude_is_visible_in_every_card() {
+        let content = r#"#let y = 2
+// START
+#let x = 1
+#let card(q: none, ..args) = q
+
+#card(id: "001", target-deck: "D", q: assert.eq(x, 1))
+Text between the cards
+#card(id: "002", target-deck: "D", q: assert.eq(x, 1))
+"#;
+        let cards = parse(content);
+        assert_eq!(cards.len(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Arc::new(config_from_args(&[&dir.path().to_string_lossy()]));
+        let mut world = crate::compile::new_world(&cfg, Arc::new(OutputJson::new(cfg.clone())));
+        for card in cards {
+            // The prelude starts at the marker
+            assert!(card.starts_with("#let x = 1\n#let card(q: none, ..args) = q\n"));
+            world.source = typst::syntax::Source::detached(card);
+            let document = typst::compile::<typst::layout::PagedDocument>(&world).output;
+            assert!(document.is_ok(), "{:?}", document.err());
+        }
+    }
+
+    #[test]
+    fn argument_values() {
+        let id = |card: &str| argument_value(&ID_RE, card);
+        assert_eq!(id(r#"#card(id: "a\"b")"#).as_deref(), Some("a\"b"));
+        assert_eq!(id(r#"#card(id: "a\\b\u{e9}")"#).as_deref(), Some("a\\bé"));
+        assert_eq!(id("#card(id: [x])").as_deref(), Some("x"));
+        assert_eq!(id("#card(id: [ [x] \\] ])").as_deref(), Some("[x] \\]"));
+        assert_eq!(id(r#"#card(id:"a")"#).as_deref(), Some("a"));
+        assert_eq!(id("#card(\n  id  :\n\t\"a\",\n)").as_deref(), Some("a"));
+        assert_eq!(id("#card(id: [\n  x\n])").as_deref(), Some("x"));
+        // Other arguments ending in `id`, and values that aren't literals
+        assert_eq!(
+            id(r#"#card(my-id: "a", uid: "b", id: "c")"#).as_deref(),
+            Some("c")
+        );
+        assert_eq!(id("#card(id: some-id)"), None);
+        assert_eq!(id(r#"#card(id: "never closed)"#), None);
+        assert_eq!(
+            argument_value(&DECK_RE, "#card(target-deck : \"A::B\")").as_deref(),
+            Some("A::B")
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    process::ExitCode,
+};
+
+use crate::{
+    anki_api,
+    card_wrapper::{CardInfo, is_metadata_tag},
+    config::Config,
+    output::{OutputManager, OutputMessage},
+};
+
+#[derive(Debug, Default)]
+pub struct DeckReport {
+    // Cards of the sources going to this deck
+    pub cards: usize,
+    // Of those, the ones with no note in Anki yet
+    pub new: usize,
+    pub existing: usize,
+    // Notes of the deck made by typ2anki whose card isn't in the sources anymore
+    pub orphans: usize,
+}
+
+#[derive(Debug)]
+pub struct OrphanNote {
+    pub note_id: i64,
+    pub deck: String,
+    pub tags: Vec<String>,
+}
+
+// --stats-only: how the sources compare to Anki, per Anki deck
+#[derive(Debug, Default)]
+pub struct CollectionReport {
+    pub decks: BTreeMap<String, DeckReport>,
+    pub orphans: Vec<OrphanNote>,
+}
+
+impl CollectionReport {
+    pub fn total(&self) -> DeckReport {
+        let mut total = DeckReport::default();
+        for deck in self.decks.values() {
+            total.cards += deck.cards;
+            total.new += deck.new;
+            total.existing += deck.existing;
+            total.orphans += deck.orphans;
+        }
+        total
+    }
+}
+
+// A note made by typ2anki shows images named typ-...
+fn is_typ2anki_note(note: &anki_api::NoteInfo) -> bool {
+    note.field_values.iter().any(|v| v.contains("typ-"))
+}
+
+fn build_report(cfg: &Config, cards: &[CardInfo]) -> Result<CollectionReport, String> {
+    let mut report = CollectionReport::default();
+    // Notes are found by the tag of their card's ID, in any deck, like when uploading.
+    // Anki's tags are case-insensitive.
+    let card_ids: HashSet<String> = cards.iter().map(|c| c.id_tag(cfg).to_lowercase()).collect();
+    let mut tags_in_anki: HashSet<String> = HashSet::new();
+
+    let anki_decks: HashSet<&str> = cards
+        .iter()
+        .filter_map(|c| c.anki_deck_name.as_deref())
+        .collect();
+    for deck in anki_decks {
+        let deck_report = report.decks.entry(deck.to_string()).or_default();
+        let notes = anki_api::get_notes_info(&anki_api::find_deck_note_ids(deck)?)?;
+        for note in notes {
+            let tags: Vec<String> = note.tags.iter().map(|t| t.to_lowercase()).collect();
+            if !tags.iter().any(|t| card_ids.contains(t)) && is_typ2anki_note(&note) {
+                deck_report.orphans += 1;
+                report.orphans.push(OrphanNote {
+                    note_id: note.note_id,
+                    deck: deck.to_string(),
+                    tags: note
+                        .tags
+                        .into_iter()
+                        .filter(|t| !is_metadata_tag(t))
+                        .collect(),
+                });
+            }
+            tags_in_anki.extend(tags);
+        }
+    }
+
+    for card in cards {
+        let deck = card.anki_deck_name.clone().unwrap_or_default();
+        // Cards whose note is in another deck are looked up one by one
+        let id_tag = card.id_tag(cfg);
+        let exists = tags_in_anki.contains(&id_tag.to_lowercase())
+            || !anki_api::find_note_id_by_tag(&id_tag)?.is_empty();
+        let deck_report = report.decks.entry(deck).or_default();
+        deck_report.cards += 1;
+        if exists {
+            deck_report.existing += 1;
+        } else {
+            deck_report.new += 1;
+        }
+    }
+    Ok(report)
+}
+
+// Compares the sources to Anki, without compiling or uploading anything
+pub fn report(cfg: &Config, cards: &[CardInfo], output: &impl OutputManager) -> ExitCode {
+    match build_report(cfg, cards) {
+        Ok(report) => {
+            output.send(OutputMessage::CollectionReport(report));
+            ExitCode::SUCCESS
+        }
+        Err(e) => output.fail_with_reason(format!("Failed to read the notes from Anki: {}", e)),
+    }
+}
@@ -1,7 +1,7 @@
 // Source: https://github.com/tfachmann/typst-as-library/blob/main/Cargo.toml
 use std::collections::HashMap;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use clap::ValueEnum;
@@ -17,12 +17,45 @@ use typst::utils::LazyHash;
 use typst::{Library, LibraryExt, WorldExt};
 use typst_kit::fonts::{FontSearcher, FontSlot};
 
+use crate::lockfile::Lockfile;
 use crate::output::OutputManager;
 
 // A wrapper efor the type which is used to only download a given package once at a time.
 pub type DownloadLocks = Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>;
 
+/// How the world finds fonts and packages, and what time it is.
+pub struct WorldOptions<'a> {
+    /// Where packages are downloaded to.
+    pub package_cache: PathBuf,
+    /// Whether packages missing from the package cache must not be downloaded.
+    pub offline: bool,
+    /// Directories with more fonts.
+    pub font_paths: &'a [PathBuf],
+    pub include_system_fonts: bool,
+    /// The time used by `datetime.today()`, the current time if not set.
+    pub now: Option<time::OffsetDateTime>,
+    /// Checks that the packages haven't changed since they were locked.
+    pub lockfile: Option<Arc<Lockfile>>,
+    /// The HTTP proxy packages are downloaded through, the one of the environment if not set.
+    pub proxy: Option<&'a str>,
+}
+
+// The client for packages.typst.org. Without a proxy given, reqwest uses the one of the
+// HTTPS_PROXY, HTTP_PROXY and ALL_PROXY environment variables, except for the hosts in NO_PROXY.
+fn package_client(proxy: Option<&str>) -> reqwest::blocking::Client {
+    let mut builder = reqwest::blocking::Client::builder();
+    if let Some(proxy) = proxy {
+        match reqwest::Proxy::all(proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => log::warn!("Not using the proxy {}: {}", proxy, e),
+        }
+    }
+    builder.build().unwrap_or_default()
+}
+
 /// Main interface that determines the environment for Typst.
+/// Cloning is cheap: fonts and loaded files are shared between the clones.
+#[derive(Clone)]
 pub struct TypstWrapperWorld {
     /// Root path to which files will be resolved.
     root: PathBuf,
@@ -40,7 +73,7 @@ pub struct TypstWrapperWorld {
     book: LazyHash<FontBook>,
 
     /// Metadata about all known fonts.
-    fonts: Vec<FontSlot>,
+    fonts: Arc<Vec<FontSlot>>,
 
     /// Map of all known files.
     files: Arc<Mutex<HashMap<FileId, FileEntry>>>,
@@ -51,18 +84,33 @@ pub struct TypstWrapperWorld {
     /// http agent to download packages.
     http: reqwest::blocking::Client,
 
-    /// Datetime.
-    time: time::OffsetDateTime,
+    /// Datetime, fixed for the whole run.
+    now: time::OffsetDateTime,
 
     download_locks: DownloadLocks,
 
+    /// Whether packages missing from the cache directory must not be downloaded.
+    offline: bool,
+
+    lockfile: Option<Arc<Lockfile>>,
+
     pub output_manager: Option<Arc<dyn OutputManager + 'static>>,
 }
 
 impl TypstWrapperWorld {
-    pub fn new(root: String, source: String, inputs: &Vec<(String, String)>) -> Self {
+    pub fn new(
+        root: String,
+        source: String,
+        inputs: &Vec<(String, String)>,
+        options: WorldOptions,
+    ) -> Self {
         let root = PathBuf::from(root);
-        let fonts = FontSearcher::new().include_system_fonts(true).search();
+        // Typst's own fonts are embedded in the binary, so that cards always have a font to
+        // fall back to, even on a machine without any installed
+        let fonts = FontSearcher::new()
+            .include_embedded_fonts(true)
+            .include_system_fonts(options.include_system_fonts)
+            .search_with(options.font_paths);
 
         let inputs: Dict = inputs
             .iter()
@@ -70,33 +118,61 @@ impl TypstWrapperWorld {
             .collect();
         let library = Library::builder().with_inputs(inputs).build();
 
-        let cache_directory = crate::utils::get_typ2anki_tmp();
+        // Created here, before the world is cloned into the compile threads
+        let cache_directory = options.package_cache;
+        let _ = std::fs::create_dir_all(&cache_directory);
 
         Self {
             library: LazyHash::new(library),
             book: LazyHash::new(fonts.book),
             root,
             workdir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
-            fonts: fonts.fonts,
+            fonts: Arc::new(fonts.fonts),
             source: Source::new(FileId::new(None, VirtualPath::new("main.typ")), source),
-            time: time::OffsetDateTime::now_utc(),
+            now: options.now.unwrap_or_else(time::OffsetDateTime::now_utc),
             cache_directory,
-            http: reqwest::blocking::Client::new(),
+            http: package_client(options.proxy),
             files: Arc::new(Mutex::new(HashMap::new())),
             download_locks: DownloadLocks::default(),
+            offline: options.offline,
+            lockfile: options.lockfile,
             output_manager: None,
         }
     }
 
-    pub fn new_with_download_locks(
-        root: String,
-        source: String,
-        inputs: &Vec<(String, String)>,
-        download_locks: DownloadLocks,
-    ) -> Self {
-        let mut world = Self::new(root, source, inputs);
-        world.download_locks = download_locks;
-        world
+    // Shares the package download locks with other worlds, so that two worlds never
+    // download the same package at the same time
+    pub fn with_download_locks(mut self, download_locks: DownloadLocks) -> Self {
+        self.download_locks = download_locks;
+        self
+    }
+
+    // Forgets the files read from the project, so that edits made since they were loaded are
+    // picked up. Package files never change, so they are kept.
+    pub fn clear_project_files(&self) {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|id, _| id.package().is_some());
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Used when compiling cards from several folders, each with its own project files.
+    // The world stops sharing its files with its clones, which may be using another root.
+    pub fn set_root(&mut self, root: &Path) {
+        if self.root != root {
+            self.root = root.to_path_buf();
+            let packages: HashMap<FileId, FileEntry> = self
+                .files
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| id.package().is_some())
+                .map(|(id, entry)| (*id, entry.clone()))
+                .collect();
+            self.files = Arc::new(Mutex::new(packages));
+        }
     }
 
     fn get_package_lock(&self, pkg_id: &str) -> Arc<Mutex<()>> {
@@ -140,12 +216,23 @@ impl TypstWrapperWorld {
     ///
     /// Requests will be either in packages or a local file.
     fn file(&self, id: FileId) -> FileResult<FileEntry> {
-        let mut files = self.files.lock().map_err(|_| FileError::AccessDenied)?;
-        if let Some(entry) = files.get(&id) {
+        if let Some(entry) = self
+            .files
+            .lock()
+            .map_err(|_| FileError::AccessDenied)?
+            .get(&id)
+        {
             return Ok(entry.clone());
         }
+        // The files are shared between worlds compiling on other threads, so they aren't
+        // locked while reading from disk or downloading
         let path = if let Some(package) = id.package() {
             let package_dir = self.download_package(package)?;
+            if let Some(lockfile) = &self.lockfile {
+                lockfile
+                    .check_package(package, &package_dir)
+                    .map_err(|e| FileError::Package(PackageError::Other(Some(e.into()))))?;
+            }
             id.vpath().resolve(&package_dir)
         } else {
             id.vpath().resolve(&self.root)
@@ -153,12 +240,27 @@ impl TypstWrapperWorld {
         .ok_or(FileError::AccessDenied)?;
 
         let content = std::fs::read(&path).map_err(|error| FileError::from_io(error, &path))?;
+        let mut files = self.files.lock().map_err(|_| FileError::AccessDenied)?;
         Ok(files
             .entry(id)
             .or_insert(FileEntry::new(content, None))
             .clone())
     }
 
+    /// Parses a file once, keeping the result so that files imported by every card aren't
+    /// parsed again for each of them.
+    fn file_source(&self, id: FileId) -> FileResult<Source> {
+        let mut entry = self.file(id)?;
+        if let Some(source) = &entry.source {
+            return Ok(source.clone());
+        }
+        let source = entry.source(id)?;
+        if let Ok(mut files) = self.files.lock() {
+            files.insert(id, entry);
+        }
+        Ok(source)
+    }
+
     /// Downloads the package and returns the system path of the unpacked package.
     fn download_package(&self, package: &PackageSpec) -> PackageResult<PathBuf> {
         let package_subdir = format!("{}/{}/{}", package.namespace, package.name, package.version);
@@ -172,12 +274,21 @@ impl TypstWrapperWorld {
             return Ok(path);
         }
 
+        if self.offline {
+            return Err(PackageError::Other(Some(eco_format!(
+                "package {package} is not downloaded and --offline is set, \
+                 unpack it into {} or run once without --offline",
+                path.display()
+            ))));
+        }
+
         if let Some(o) = &self.output_manager {
-            o.send(crate::output::OutputMessage::TypstDownloadingPackage(
-                format!("{package}"),
-            ));
+            o.send(crate::output::OutputMessage::TypstDownloadingPackage {
+                package: format!("@{}/{}", package.namespace, package.name),
+                version: package.version.to_string(),
+            });
         } else {
-            eprintln!("downloading {package}");
+            log::info!("Downloading {package}");
         }
         let url = format!(
             "https://packages.typst.org/{}/{}-{}.tar.gz",
@@ -209,11 +320,21 @@ impl TypstWrapperWorld {
         let raw_archive = zune_inflate::DeflateDecoder::new(&compressed_archive)
             .decode_gzip()
             .map_err(|error| PackageError::MalformedArchive(Some(eco_format!("{error}"))))?;
+        // Unpacked next to its final place then renamed, so that another typ2anki running
+        // at the same time never sees a partially unpacked package
+        let parent = path.parent().unwrap_or(&self.cache_directory);
+        let unpack_dir = std::fs::create_dir_all(parent)
+            .and_then(|_| tempfile::tempdir_in(parent))
+            .map_err(|error| PackageError::Other(Some(eco_format!("{error}"))))?;
         let mut archive = tar::Archive::new(raw_archive.as_slice());
-        archive.unpack(&path).map_err(|error| {
-            _ = std::fs::remove_dir_all(&path);
-            PackageError::MalformedArchive(Some(eco_format!("{error}")))
-        })?;
+        archive
+            .unpack(unpack_dir.path())
+            .map_err(|error| PackageError::MalformedArchive(Some(eco_format!("{error}"))))?;
+        if let Err(error) = std::fs::rename(unpack_dir.path(), &path)
+            && !path.exists()
+        {
+            return Err(PackageError::Other(Some(eco_format!("{error}"))));
+        }
         Ok(path)
     }
 }
@@ -242,7 +363,7 @@ impl typst::World for TypstWrapperWorld {
         if id == self.source.id() {
             Ok(self.source.clone())
         } else {
-            self.file(id)?.source(id)
+            self.file_source(id)
         }
     }
 
@@ -260,9 +381,14 @@ impl typst::World for TypstWrapperWorld {
     ///
     /// Optionally, an offset in hours is given.
     fn today(&self, offset: Option<i64>) -> Option<Datetime> {
-        let offset = offset.unwrap_or(0);
-        let offset = time::UtcOffset::from_hms(offset.try_into().ok()?, 0, 0).ok()?;
-        let time = self.time.checked_to_offset(offset)?;
+        // Without an offset, the date is the one of the time zone of --now (UTC by default)
+        let time = match offset {
+            Some(offset) => {
+                let offset = time::UtcOffset::from_hms(offset.try_into().ok()?, 0, 0).ok()?;
+                self.now.checked_to_offset(offset)?
+            }
+            None => self.now,
+        };
         Some(Datetime::Date(time.date()))
     }
 }
@@ -361,8 +487,14 @@ pub fn render_diagnostics(
     errors: &[SourceDiagnostic],
     warnings: &[SourceDiagnostic],
     diagnostic_format: DiagnosticFormat,
+    color: bool,
 ) -> Result<String, codespan_reporting::files::Error> {
-    let mut writer = term::termcolor::Ansi::new(Vec::new());
+    // The messages end up in a log file or a pipe as often as in a terminal
+    let mut writer = if color {
+        term::termcolor::Buffer::ansi()
+    } else {
+        term::termcolor::Buffer::no_color()
+    };
 
     let mut config = term::Config {
         tab_width: 2,
@@ -408,3 +540,49 @@ pub fn render_diagnostics(
 fn label(world: &TypstWrapperWorld, span: Span) -> Option<Label<FileId>> {
     Some(Label::primary(span.id()?, world.range(span)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use typst::layout::PagedDocument;
+
+    use super::*;
+    use crate::{compile::new_world, config::config_from_args, output_json::OutputJson};
+
+    fn render(color: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Arc::new(config_from_args(&[
+            "--color",
+            color,
+            &dir.path().to_string_lossy(),
+        ]));
+        let mut world = new_world(&cfg, Arc::new(OutputJson::new(cfg.clone())));
+        world.source = Source::detached("#set text(font: \"no such font\")\nA #missing card");
+        let out = typst::compile::<PagedDocument>(&world);
+        let errors = out.output.unwrap_err();
+        render_diagnostics(
+            &world,
+            &errors,
+            &out.warnings,
+            DiagnosticFormat::Human,
+            cfg.color,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn diagnostics_without_color() {
+        let rendered = render("never");
+        assert!(
+            rendered.contains("unknown variable: missing"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("unknown font family"), "{}", rendered);
+        assert!(!rendered.contains('\x1b'), "{:?}", rendered);
+    }
+
+    #[test]
+    fn diagnostics_with_color() {
+        assert!(render("always").contains('\x1b'));
+    }
+}
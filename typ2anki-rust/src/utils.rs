@@ -2,9 +2,10 @@ use base64::{DecodeError, Engine as _, engine::general_purpose::STANDARD};
 use regex::Regex;
 use serde_json::Value;
 use std::cmp::max;
-use std::collections::BTreeMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
 use std::{fs, io, iter};
+use typst::syntax::package::PackageSpec;
 use zip::ZipArchive;
 
 // Hashes the string as md5 hex digest
@@ -13,6 +14,81 @@ pub fn hash_string(input: &str) -> String {
     format!("{:x}", digest)
 }
 
+// Hashes text the same way whatever the platform it was saved on: without a byte order
+// mark, and with `\n` line endings, so that a checkout with CRLF line endings doesn't make
+// every card look changed
+pub fn hash_text(input: &str) -> String {
+    let input = input.strip_prefix('\u{FEFF}').unwrap_or(input);
+    if input.contains('\r') {
+        hash_string(&input.replace("\r\n", "\n"))
+    } else {
+        hash_string(input)
+    }
+}
+
+// Reads a text file: UTF-8, with or without the byte order mark some Windows editors add, or
+// UTF-16 with its byte order mark. Other encodings are refused, with where the first invalid
+// bytes are, rather than read with the wrong characters.
+pub fn read_text_file(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    decode_text(&bytes)
+}
+
+fn decode_text(bytes: &[u8]) -> Result<String, String> {
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        decode_utf8(rest, 3)
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        decode_utf16(rest, u16::from_le_bytes)
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        decode_utf16(rest, u16::from_be_bytes)
+    } else {
+        decode_utf8(bytes, 0)
+    }
+}
+
+// `offset` is where `bytes` start in the file, after the byte order mark
+fn decode_utf8(bytes: &[u8], offset: usize) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| {
+        let at = e.utf8_error().valid_up_to();
+        let line = bytes[..at].iter().filter(|&&b| b == b'\n').count() + 1;
+        format!(
+            "invalid UTF-8 at byte {} (line {}), the file must be saved as UTF-8",
+            offset + at,
+            line
+        )
+    })
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Result<String, String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err("truncated UTF-16, the file has an odd number of bytes".to_string());
+    }
+    let units = bytes.chunks_exact(2).map(|c| from_bytes([c[0], c[1]]));
+    let mut text = String::with_capacity(bytes.len() / 2);
+    // In 16-bit units, after the byte order mark
+    let mut position = 0;
+    for c in char::decode_utf16(units) {
+        match c {
+            Ok(c) => {
+                text.push(c);
+                position += c.len_utf16();
+            }
+            Err(_) => {
+                return Err(format!(
+                    "invalid UTF-16 at byte {}, the file must be saved as UTF-8",
+                    2 + position * 2
+                ));
+            }
+        }
+    }
+    Ok(text)
+}
+
+// Anki tags are space separated, so whitespace inside a tag is replaced
+pub fn sanitize_tag(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
 pub fn json_sorted_keys(v: &Value) -> Value {
     match v {
         Value::Object(map) => {
@@ -27,52 +103,70 @@ pub fn json_sorted_keys(v: &Value) -> Value {
     }
 }
 
-pub fn get_all_typst_imports(typst_content: &str) -> Vec<String> {
-    let pattern = Regex::new(r#"(?m)^#import\s*"([^"]+)"\s*"#).unwrap();
-    let mut r: Vec<String> = Vec::new();
-
-    let mut imports: Vec<String> = pattern
-        .captures_iter(typst_content)
-        .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
-        .collect();
+// What a Typst file depends on through its imports, followed transitively
+#[derive(Debug, Default)]
+pub struct TypstImports {
+    // The local files imported, sorted
+    pub files: Vec<PathBuf>,
+    // The packages imported, sorted
+    pub packages: Vec<PackageSpec>,
+}
 
-    let mut idx = 0;
-    while idx < imports.len() {
-        let mut import_path = imports[idx].clone();
+// The files and packages imported by `file`, and the ones they import. Paths are resolved
+// like Typst does: relative to the importing file, or to `root` when they start with `/`.
+pub fn get_all_typst_imports(root: &Path, file: &Path, typst_content: &str) -> TypstImports {
+    let pattern = Regex::new(r#"(?m)^\s*#import\s*"([^"]+)""#).unwrap();
+    let mut r = TypstImports::default();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    visited.insert(file.to_path_buf());
 
-        if Path::new(&import_path).is_absolute() {
-            if let Ok(rel) = Path::new(&import_path).strip_prefix("/") {
-                import_path = rel.to_string_lossy().into_owned();
-            } else if import_path.starts_with('/') {
-                import_path = import_path.trim_start_matches('/').to_string();
+    let mut pending: Vec<(PathBuf, String)> = vec![(file.to_path_buf(), typst_content.into())];
+    while let Some((importer, content)) = pending.pop() {
+        let importer_dir = importer.parent().unwrap_or(root);
+        for cap in pattern.captures_iter(&content) {
+            let import = &cap[1];
+            if import.starts_with('@') {
+                if let Ok(spec) = import.parse::<PackageSpec>() {
+                    r.packages.push(spec);
+                }
+                continue;
+            }
+            let path = match import.strip_prefix('/') {
+                Some(from_root) => root.join(from_root),
+                None => importer_dir.join(import),
+            };
+            let path = normalize_path(&path);
+            if !path.is_file() || !visited.insert(path.clone()) {
+                continue;
+            }
+            if let Ok(imported) = read_text_file(&path) {
+                pending.push((path.clone(), imported));
             }
+            r.files.push(path);
         }
+    }
 
-        let joined_path = {
-            let base = crate::config::get().path.clone();
-            Path::new(&base).join(&import_path)
-        };
-
-        if joined_path.exists() {
-            let joined_str = joined_path.to_string_lossy().into_owned();
-            if !r.contains(&joined_str) {
-                r.push(joined_str.clone());
-            }
+    r.files.sort();
+    r.packages.sort_by_key(|p| p.to_string());
+    r.packages.dedup();
+    r
+}
 
-            if let Ok(content) = fs::read_to_string(&joined_path) {
-                for cap in pattern.captures_iter(&content) {
-                    if let Some(m) = cap.get(1) {
-                        imports.push(m.as_str().to_string());
-                    }
-                }
+// Removes the `.` and `..` components of a path without touching the file system, so that
+// a file imported through different relative paths is only followed once
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut r = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(r.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                r.pop();
             }
+            c => r.push(c),
         }
-
-        idx += 1;
     }
-
-    r.sort();
-    r.dedup();
     r
 }
 
@@ -101,6 +195,27 @@ pub fn b64_decode(input: &str) -> Result<Vec<u8>, DecodeError> {
     STANDARD.decode(input)
 }
 
+// Size of the data encoded in a base64 string, without decoding it
+pub fn b64_decoded_len(input: &str) -> usize {
+    let padding = input.bytes().rev().take_while(|&b| b == b'=').count();
+    (input.len() / 4 * 3).saturating_sub(padding)
+}
+
+// "532 B", "12.3 KB", "4.0 MB"...
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 pub fn unzip_file_to_dir(zip_path: &Path, dest_path: &Path) -> io::Result<()> {
     let file = fs::File::open(zip_path)?;
     let mut archive = ZipArchive::new(file)?;
@@ -109,7 +224,14 @@ pub fn unzip_file_to_dir(zip_path: &Path, dest_path: &Path) -> io::Result<()> {
 
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
-        let outpath = dest_path.join(file.name());
+        // Entries with `..` or an absolute path would be written outside dest_path
+        let Some(name) = file.enclosed_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsafe path in the zip file: {}", file.name()),
+            ));
+        };
+        let outpath = dest_path.join(name);
 
         if file.is_dir() {
             fs::create_dir_all(&outpath)?;
@@ -126,6 +248,17 @@ pub fn unzip_file_to_dir(zip_path: &Path, dest_path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+// Downloaded packages are kept in the user's cache folder, which isn't emptied on reboot
+// like the temporary folder. Setting CACHE_DIRECTORY still keeps everything in one place.
+pub fn default_package_cache() -> PathBuf {
+    if std::env::var_os("CACHE_DIRECTORY").is_some() {
+        return get_typ2anki_tmp();
+    }
+    dirs::cache_dir()
+        .map(|d| d.join("typ2anki").join("packages"))
+        .unwrap_or_else(get_typ2anki_tmp)
+}
+
 pub fn get_typ2anki_tmp() -> PathBuf {
     let cache_directory: PathBuf = std::env::var_os("CACHE_DIRECTORY")
         .map(|os_path| os_path.into())
@@ -134,3 +267,104 @@ pub fn get_typ2anki_tmp() -> PathBuf {
     std::fs::create_dir_all(&cache_directory).unwrap_or(());
     cache_directory
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, content) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn unzips_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("cards.zip");
+        write_zip(&zip_path, &[("a.typ", "a"), ("sub/b.typ", "b")]);
+        let dest = dir.path().join("out");
+        unzip_file_to_dir(&zip_path, &dest).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("a.typ")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dest.join("sub/b.typ")).unwrap(), "b");
+    }
+
+    #[test]
+    fn refuses_paths_outside_of_the_destination() {
+        for name in ["../evil.txt", "sub/../../evil.txt", "/evil.txt"] {
+            let dir = tempfile::tempdir().unwrap();
+            let zip_path = dir.path().join("cards.zip");
+            write_zip(&zip_path, &[("a.typ", "a"), (name, "evil")]);
+            let dest = dir.path().join("out");
+            let error = unzip_file_to_dir(&zip_path, &dest).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", name);
+            assert!(!dir.path().join("evil.txt").exists(), "{}", name);
+            assert!(!Path::new("/evil.txt").exists(), "{}", name);
+        }
+    }
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn reads_utf8_with_and_without_bom() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cards.typ");
+        fs::write(&path, "#card(id: \"é\")").unwrap();
+        assert_eq!(read_text_file(&path).unwrap(), "#card(id: \"é\")");
+        fs::write(&path, "\u{FEFF}#card(id: \"é\")").unwrap();
+        assert_eq!(read_text_file(&path).unwrap(), "#card(id: \"é\")");
+        assert!(read_text_file(&dir.path().join("missing.typ")).is_err());
+    }
+
+    #[test]
+    fn reads_utf16_with_bom() {
+        let text = "#card(id: \"é 😀\")\r\n";
+        let le = [b"\xFF\xFE".to_vec(), utf16(text, u16::to_le_bytes)].concat();
+        assert_eq!(decode_text(&le).unwrap(), text);
+        let be = [b"\xFE\xFF".to_vec(), utf16(text, u16::to_be_bytes)].concat();
+        assert_eq!(decode_text(&be).unwrap(), text);
+    }
+
+    #[test]
+    fn refuses_invalid_bytes() {
+        // Latin-1 "é" on the second line
+        assert_eq!(
+            decode_text(b"ok\ncaf\xE9").unwrap_err(),
+            "invalid UTF-8 at byte 6 (line 2), the file must be saved as UTF-8"
+        );
+        assert_eq!(
+            decode_text(b"\xEF\xBB\xBFcaf\xE9").unwrap_err(),
+            "invalid UTF-8 at byte 6 (line 1), the file must be saved as UTF-8"
+        );
+        assert_eq!(
+            decode_text(b"\xFF\xFEa\x00b").unwrap_err(),
+            "truncated UTF-16, the file has an odd number of bytes"
+        );
+        // An unpaired surrogate after "a"
+        assert_eq!(
+            decode_text(b"\xFF\xFEa\x00\x00\xD8").unwrap_err(),
+            "invalid UTF-16 at byte 4, the file must be saved as UTF-8"
+        );
+    }
+
+    #[test]
+    fn line_endings_and_bom_dont_change_the_hash() {
+        let lf = "#card(\n  id: \"001\",\n)\n";
+        let crlf = "#card(\r\n  id: \"001\",\r\n)\r\n";
+        assert_eq!(hash_text(crlf), hash_text(lf));
+        assert_eq!(hash_text(&format!("\u{FEFF}{}", crlf)), hash_text(lf));
+        assert_eq!(hash_text(&format!("\u{FEFF}{}", lf)), hash_text(lf));
+        assert_eq!(hash_text(lf), hash_string(lf));
+        // A lone carriage return is kept
+        assert_ne!(hash_text("a\rb"), hash_text("a\nb"));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        Arc, Mutex, RwLock,
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    anki_api,
+    card_wrapper::{CardInfo, CardModificationStatus, TFiles, TFilesExt, sort_in_source_order},
+    cards_cache::CardsCacheManager,
+    compile,
+    config::Config,
+    ignore_file,
+    output::{OutputManager, OutputMessage},
+    parse_file,
+    typst_as_library::TypstWrapperWorld,
+};
+
+// Editors often write a file several times when saving, so events are grouped until
+// there has been no change for this long
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+type WatchEvents = mpsc::Receiver<notify::Result<notify::Event>>;
+
+/// Keeps running after the first upload, recompiling the cards of the files that change
+pub fn watch(
+    cfg: &Config,
+    output: Arc<impl OutputManager + 'static>,
+    cards_cache_manager: CardsCacheManager,
+) -> ExitCode {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(w) => w,
+        Err(e) => {
+            return output.fail_with_reason(format!("Failed to start the file watcher: {}", e));
+        }
+    };
+    for root in &cfg.paths {
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            return output.fail_with_reason(format!("Failed to watch {}: {}", root.display(), e));
+        }
+    }
+
+    let cards_cache_manager = Arc::new(Mutex::new(cards_cache_manager));
+    cards_cache_manager.lock().unwrap().commit_new_cache();
+    let mut world = compile::new_world(cfg, output.clone());
+    let mut created_decks: HashSet<String> = HashSet::new();
+
+    while !compile::is_cancelled() {
+        output.send(OutputMessage::WatchingForChanges);
+        let Some(changed) = wait_for_changes(&rx) else {
+            break;
+        };
+        recompile_changed(
+            cfg,
+            changed,
+            output.clone(),
+            cards_cache_manager.clone(),
+            &mut world,
+            &mut created_decks,
+        );
+        // Typst memoizes every compilation, and in a long session the results would pile up
+        // without bound. Results unused for this many cycles are dropped, as `typst watch` does.
+        comemo::evict(10);
+    }
+    ExitCode::SUCCESS
+}
+
+// Blocks until something changes, then returns every path changed until things settle.
+// Returns None if the watcher stopped or Ctrl-C was pressed.
+fn wait_for_changes(rx: &WatchEvents) -> Option<HashSet<PathBuf>> {
+    let mut changed: HashSet<PathBuf> = HashSet::new();
+    let mut event = recv_unless_cancelled(rx)?;
+    loop {
+        if let Ok(event) = event
+            && !matches!(event.kind, EventKind::Access(_))
+        {
+            changed.extend(event.paths);
+        }
+        event = match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if !changed.is_empty() => return Some(changed),
+            Err(RecvTimeoutError::Timeout) => recv_unless_cancelled(rx)?,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        };
+    }
+}
+
+// Waits for the next event, checking for Ctrl-C every DEBOUNCE
+fn recv_unless_cancelled(rx: &WatchEvents) -> Option<notify::Result<notify::Event>> {
+    loop {
+        if compile::is_cancelled() {
+            return None;
+        }
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => return Some(event),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+fn recompile_changed(
+    cfg: &Config,
+    changed: HashSet<PathBuf>,
+    output: Arc<impl OutputManager + 'static>,
+    cards_cache_manager: Arc<Mutex<CardsCacheManager>>,
+    world: &mut TypstWrapperWorld,
+    created_decks: &mut HashSet<String>,
+) {
+    // A change to ankiconf.typ affects every card
+    let ankiconf_changed = changed
+        .iter()
+        .any(|p| p.file_name().is_some_and(|n| n == "ankiconf.typ"));
+    // A change to an ignore file may bring back files that were ignored
+    let ignore_file_changed = changed.iter().any(|p| {
+        p.file_name()
+            .is_some_and(|n| n == ignore_file::IGNORE_FILENAME)
+    });
+    if ignore_file_changed {
+        ignore_file::clear_cache();
+    }
+    let mut typ_files: Vec<PathBuf> = if ankiconf_changed || ignore_file_changed {
+        if ankiconf_changed {
+            cards_cache_manager
+                .lock()
+                .unwrap()
+                .update_ankiconf_hash(cfg, parse_file::get_ankiconf_hash(cfg));
+        }
+        parse_file::find_card_files(cfg)
+    } else {
+        changed
+            .into_iter()
+            .filter(|p| {
+                parse_file::is_card_file(p)
+                    && p.is_file()
+                    && !ignore_file::is_ignored(cfg.root_of(p), p, false)
+            })
+            .collect()
+    };
+    if typ_files.is_empty() {
+        return;
+    }
+    typ_files.sort();
+    output.send(OutputMessage::WatchFilesChanged(
+        typ_files
+            .iter()
+            .map(|p| cfg.path_relative_to_root(p))
+            .collect(),
+    ));
+
+    // Imported files and images may have changed too
+    world.clear_project_files();
+
+    let mut cards: Vec<CardInfo> = Vec::new();
+    let mut deck_names: BTreeSet<String> = BTreeSet::new();
+    let files: TFiles = Arc::new(RwLock::new(HashMap::new()));
+    let mut files_lock = files.write().unwrap();
+    let parse_time = {
+        let mut cards_cache_manager = cards_cache_manager.lock().unwrap();
+        let parse_start = Instant::now();
+        files_lock.extend(parse_file::parse_typ_files(
+            cfg,
+            &typ_files,
+            &mut cards_cache_manager,
+            output.clone(),
+            &mut deck_names,
+            &mut cards,
+        ));
+        let parse_time = parse_start.elapsed();
+        if cards.is_empty() {
+            return;
+        }
+        sort_in_source_order(&mut cards);
+        cards_cache_manager.detect_configuration_change(cfg, output.as_ref());
+        for card in &mut cards {
+            card.set_status(cfg, &cards_cache_manager);
+            card.anki_deck_name = Some(anki_api::get_anki_deck_name(cfg, &card.deck_name));
+        }
+        parse_time
+    };
+
+    for deck_name in deck_names {
+        if created_decks.contains(&deck_name) {
+            continue;
+        }
+        if cfg.dry_run {
+            output.send(OutputMessage::DbgCreateDeck(deck_name.to_string()));
+        } else {
+            let _ = anki_api::create_deck(&anki_api::get_anki_deck_name(cfg, &deck_name));
+        }
+        created_decks.insert(deck_name);
+    }
+
+    for card in &cards {
+        if let Some(file_stats) = files_lock.get_mut(&card.source_file) {
+            file_stats.count_card_status(&card.modification_status);
+        }
+    }
+    drop(files_lock);
+
+    output.send(OutputMessage::ListTypstFiles(files.clone()));
+
+    let now = Instant::now();
+    compile::reset_fail_fast();
+    compile::compile_cards_in_world(
+        cfg,
+        world,
+        &cards,
+        output.clone(),
+        cards_cache_manager.clone(),
+        files.clone(),
+        Vec::new(),
+    );
+    let elapsed = now.elapsed();
+
+    output.send(OutputMessage::DbgCompilationDone {
+        files: files.clone(),
+    });
+    output.send(OutputMessage::Summary {
+        compiled_cards: cards
+            .iter()
+            .filter(|c| c.modification_status != CardModificationStatus::Unchanged)
+            .count(),
+        elapsed,
+        phases: compile::take_phase_times(parse_time),
+        cards: files.summary(),
+    });
+
+    let mut cards_cache_manager = cards_cache_manager.lock().unwrap();
+    cards_cache_manager.save_cache(cfg, output.as_ref());
+    if !cfg.dry_run {
+        crate::save_lockfile(cfg, output.as_ref());
+    }
+    cards_cache_manager.commit_new_cache();
+}